@@ -0,0 +1,100 @@
+// Compares peeling drags that rebuild and refactor the Laplacian system every frame
+// with drags that keep one system and only refactor when the area of effect changes.
+// Run with: cargo bench --bench laplacian_peeling
+
+extern crate glm;
+
+#[path = "../src/laplacian.rs"]
+#[allow(dead_code)]
+mod laplacian;
+#[path = "../src/solver.rs"]
+#[allow(dead_code)]
+mod solver;
+
+use std::time::{Duration, Instant};
+
+static FRAMES: usize = 120;
+// The area of effect grows by one control point every this many frames
+static FRAMES_PER_STEP: usize = 4;
+
+fn make_curve(n: usize) -> Vec<glm::Vec3> {
+    (0..n).map(|i| {
+	let t = i as f32 / n as f32 * 6.0;
+	glm::vec3(t - 3.0, 0.5 * t.sin(), 0.0)
+    }).collect()
+}
+
+fn get_constraints(n: usize, dragged: usize, area_of_effect: usize,
+		   settings: &laplacian::ConstraintSettings) -> Vec<(usize, f32)> {
+    (0..n).map(|i| {
+	let distance = if i > dragged { i - dragged } else { dragged - i };
+	let weight = if i == dragged {
+	    settings.pin_weight
+	} else {
+	    settings.falloff_weight(distance.saturating_sub(area_of_effect))
+	};
+	(i, weight)
+    }).collect()
+}
+
+// Moves the dragged point a little further every frame
+fn drag(points: &mut Vec<glm::Vec3>, dragged: usize, frame: usize) {
+    points[dragged].y = 0.01 * frame as f32;
+}
+
+fn run_rebuilding(original: &Vec<glm::Vec3>) -> Duration {
+    let n = original.len();
+    let dragged = n / 2;
+    let settings = laplacian::ConstraintSettings::new();
+    let mut points = original.clone();
+
+    let start = Instant::now();
+    for frame in 0..FRAMES {
+	drag(&mut points, dragged, frame);
+	
+	let mut system = laplacian::setup_graph_points(original, laplacian::chain_adjacency(original.len(), false),
+						       laplacian::LaplacianMode::Planar,
+						       laplacian::DeformationMethod::Linearized,
+						       solver::SolverBackend::CholeskyF32);
+	system.setup_fixed_points(get_constraints(n, dragged, frame / FRAMES_PER_STEP, &settings)).unwrap();
+	system.solve(&mut points).unwrap();
+    }
+    start.elapsed()
+}
+
+fn run_cached(original: &Vec<glm::Vec3>) -> Duration {
+    let n = original.len();
+    let dragged = n / 2;
+    let settings = laplacian::ConstraintSettings::new();
+    let mut points = original.clone();
+
+    let start = Instant::now();
+    let mut system = laplacian::setup_graph_points(original, laplacian::chain_adjacency(original.len(), false),
+						   laplacian::LaplacianMode::Planar,
+						   laplacian::DeformationMethod::Linearized,
+						   solver::SolverBackend::CholeskyF32);
+    for frame in 0..FRAMES {
+	drag(&mut points, dragged, frame);
+	
+	system.setup_fixed_points(get_constraints(n, dragged, frame / FRAMES_PER_STEP, &settings)).unwrap();
+	system.solve(&mut points).unwrap();
+    }
+    start.elapsed()
+}
+
+fn main() {
+    println!("{} frames per drag, area of effect changing every {} frames", FRAMES, FRAMES_PER_STEP);
+    println!("{:>8} {:>16} {:>16} {:>10}", "points", "rebuilding (ms)", "cached (ms)", "speedup");
+
+    for n in &[100, 250, 500, 1000, 2000] {
+	let curve = make_curve(*n);
+	
+	let rebuilding = run_rebuilding(&curve);
+	let cached = run_cached(&curve);
+
+	let per_frame = |d: Duration| d.as_secs_f64() * 1000.0 / FRAMES as f64;
+	println!("{:>8} {:>16.3} {:>16.3} {:>9.1}x",
+		 n, per_frame(rebuilding), per_frame(cached),
+		 rebuilding.as_secs_f64() / cached.as_secs_f64());
+    }
+}
@@ -6,6 +6,8 @@ use crate::edit;
 use crate::cylinder;
 use crate::shaders;
 use crate::utils;
+use crate::laplacian;
+use crate::noise;
 
 use std::ffi::CString;
 use std::collections::HashMap;
@@ -35,9 +37,50 @@ pub trait Annotation {
 	0
     }
 
+    fn set_render_index(&mut self, _index: usize) { }
+
     
     fn apply_size(&self, _sizes: &mut Vec<f32>) { }
 
+    // How much the point resists bending in the Laplacian solver
+    fn alters_stiffness(&self) -> bool {
+	false
+    }
+
+    fn get_stiffness(&self) -> f32 {
+	1.0
+    }
+
+    fn set_stiffness(&mut self, _stiffness: f32) { }
+
+    // How the point is held in place when others are dragged
+    fn get_constraint(&self) -> Option<laplacian::ConstraintKind> {
+	None
+    }
+
+    fn set_constraint(&mut self, _kind: laplacian::ConstraintKind) { }
+
+    // Cap of the cylinder, only used on its first and last point
+    fn get_cap(&self) -> Option<cylinder::Cap> {
+	None
+    }
+
+    fn set_cap(&mut self, _cap: cylinder::Cap) { }
+
+    // Displacement of the whole surface along its normals
+    fn get_noise(&self) -> Option<noise::NoiseSettings> {
+	None
+    }
+
+    fn set_noise(&mut self, _noise: noise::NoiseSettings) { }
+
+    // Surface color, interpolated along the spline between annotated points
+    fn get_paint(&self) -> Option<cylinder::Paint> {
+	None
+    }
+
+    fn set_paint(&mut self, _paint: cylinder::Paint) { }
+
 }
 
 
@@ -77,6 +120,10 @@ impl Annotation for SizeAnnotation {
 	self.index
     }
 
+    fn set_render_index(&mut self, index: usize) {
+	self.index = index;
+    }
+
     fn set_world_position(&mut self, vv: glm::Vec3) {
 	self.position = vv
     }
@@ -86,6 +133,286 @@ impl Annotation for SizeAnnotation {
     }
 }
 
+pub struct StiffnessAnnotation {
+    pub stiffness: f32,
+    pub index: usize,
+    pub position: glm::Vec3,
+}
+
+impl Annotation for StiffnessAnnotation {
+    fn get_str(&self) -> std::string::String {
+	format!("Sets stiffness to {}", self.stiffness.to_string())
+    }
+
+    fn get_color(&self) -> glm::Vec4 {
+	glm::vec4(0.0, 0.6, 0.0, 1.0)
+    }
+
+    fn set_size(&mut self, _size: f32) { }
+
+    fn alters_stiffness(&self) -> bool {
+	true
+    }
+
+    fn get_stiffness(&self) -> f32 {
+	self.stiffness
+    }
+
+    fn set_stiffness(&mut self, stiffness: f32) {
+	self.stiffness = stiffness;
+    }
+
+    fn get_render_index(&self) -> usize {
+	self.index
+    }
+
+    fn set_render_index(&mut self, index: usize) {
+	self.index = index;
+    }
+
+    fn set_world_position(&mut self, vv: glm::Vec3) {
+	self.position = vv
+    }
+
+    fn get_world_position(&self) -> glm::Vec3 {
+	self.position
+    }
+}
+
+pub struct ConstraintAnnotation {
+    pub kind: laplacian::ConstraintKind,
+    pub index: usize,
+    pub position: glm::Vec3,
+}
+
+impl Annotation for ConstraintAnnotation {
+    fn get_str(&self) -> std::string::String {
+	format!("Constrains the point as {:?}", self.kind)
+    }
+
+    fn get_color(&self) -> glm::Vec4 {
+	glm::vec4(1.0, 0.5, 0.0, 1.0)
+    }
+
+    fn set_size(&mut self, _size: f32) { }
+
+    fn get_constraint(&self) -> Option<laplacian::ConstraintKind> {
+	Some(self.kind)
+    }
+
+    fn set_constraint(&mut self, kind: laplacian::ConstraintKind) {
+	self.kind = kind;
+    }
+
+    fn get_render_index(&self) -> usize {
+	self.index
+    }
+
+    fn set_render_index(&mut self, index: usize) {
+	self.index = index;
+    }
+
+    fn set_world_position(&mut self, vv: glm::Vec3) {
+	self.position = vv
+    }
+
+    fn get_world_position(&self) -> glm::Vec3 {
+	self.position
+    }
+}
+
+pub struct CapAnnotation {
+    pub cap: cylinder::Cap,
+    pub index: usize,
+    pub position: glm::Vec3,
+}
+
+impl Annotation for CapAnnotation {
+    fn get_str(&self) -> std::string::String {
+	format!("Sets the cap to {:?}", self.cap.style)
+    }
+
+    fn get_color(&self) -> glm::Vec4 {
+	glm::vec4(0.6, 0.0, 0.6, 1.0)
+    }
+
+    fn set_size(&mut self, _size: f32) { }
+
+    fn get_cap(&self) -> Option<cylinder::Cap> {
+	Some(self.cap)
+    }
+
+    fn set_cap(&mut self, cap: cylinder::Cap) {
+	self.cap = cap;
+    }
+
+    fn get_render_index(&self) -> usize {
+	self.index
+    }
+
+    fn set_render_index(&mut self, index: usize) {
+	self.index = index;
+    }
+
+    fn set_world_position(&mut self, vv: glm::Vec3) {
+	self.position = vv
+    }
+
+    fn get_world_position(&self) -> glm::Vec3 {
+	self.position
+    }
+}
+
+pub struct NoiseAnnotation {
+    pub noise: noise::NoiseSettings,
+    pub index: usize,
+    pub position: glm::Vec3,
+}
+
+impl Annotation for NoiseAnnotation {
+    fn get_str(&self) -> std::string::String {
+	format!("Displaces the surface with {:?} noise", self.noise.kind)
+    }
+
+    fn get_color(&self) -> glm::Vec4 {
+	glm::vec4(0.8, 0.5, 0.0, 1.0)
+    }
+
+    fn set_size(&mut self, _size: f32) { }
+
+    fn get_noise(&self) -> Option<noise::NoiseSettings> {
+	Some(self.noise)
+    }
+
+    fn set_noise(&mut self, noise: noise::NoiseSettings) {
+	self.noise = noise;
+    }
+
+    fn get_render_index(&self) -> usize {
+	self.index
+    }
+
+    fn set_render_index(&mut self, index: usize) {
+	self.index = index;
+    }
+
+    fn set_world_position(&mut self, vv: glm::Vec3) {
+	self.position = vv
+    }
+
+    fn get_world_position(&self) -> glm::Vec3 {
+	self.position
+    }
+}
+
+pub struct ColorAnnotation {
+    pub paint: cylinder::Paint,
+    pub index: usize,
+    pub position: glm::Vec3,
+}
+
+impl Annotation for ColorAnnotation {
+    fn get_str(&self) -> std::string::String {
+	format!("Sets the color to {:?}", self.paint.color)
+    }
+
+    fn get_color(&self) -> glm::Vec4 {
+	glm::vec4(self.paint.color[0], self.paint.color[1], self.paint.color[2], 1.0)
+    }
+
+    fn set_size(&mut self, _size: f32) { }
+
+    fn get_paint(&self) -> Option<cylinder::Paint> {
+	Some(self.paint)
+    }
+
+    fn set_paint(&mut self, paint: cylinder::Paint) {
+	self.paint = paint;
+    }
+
+    fn get_render_index(&self) -> usize {
+	self.index
+    }
+
+    fn set_render_index(&mut self, index: usize) {
+	self.index = index;
+    }
+
+    fn set_world_position(&mut self, vv: glm::Vec3) {
+	self.position = vv
+    }
+
+    fn get_world_position(&self) -> glm::Vec3 {
+	self.position
+    }
+}
+
+// Noise layers of the cylinder, in the order they were annotated
+pub fn get_noises(annotations: &Vec<Box<dyn Annotation>>) -> Vec<noise::NoiseSettings> {
+    annotations.iter().filter_map(|ann| ann.get_noise()).collect()
+}
+
+// Per-point stiffness for the Laplacian solver, 1 where nothing is annotated
+pub fn get_stiffness(annotations: &Vec<Box<dyn Annotation>>,
+		     num_points: usize) -> Vec<f32> {
+    let mut stiffness = vec![1.0; num_points];
+
+    for ann in annotations {
+	if ann.alters_stiffness() && ann.get_render_index() < num_points {
+	    stiffness[ann.get_render_index()] = ann.get_stiffness();
+	}
+    }
+
+    stiffness
+}
+
+// Per-point constraint kinds, None where nothing is annotated
+pub fn get_constraints(annotations: &Vec<Box<dyn Annotation>>,
+		       num_points: usize) -> Vec<Option<laplacian::ConstraintKind>> {
+    let mut constraints = vec![None; num_points];
+
+    for ann in annotations {
+	if ann.get_render_index() < num_points {
+	    if let Some(kind) = ann.get_constraint() {
+		constraints[ann.get_render_index()] = Some(kind);
+	    }
+	}
+    }
+
+    constraints
+}
+
+// What points can be selected by in Edit mode
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum AnnotationKind {
+    Size,
+    Stiffness,
+    Pin,
+    Anchor,
+    Free,
+}
+
+// Indices of the points that carry an annotation of the given kind
+pub fn get_annotated_points(annotations: &Vec<Box<dyn Annotation>>,
+			    num_points: usize,
+			    kind: AnnotationKind) -> Vec<usize> {
+    let mut points : Vec<usize> = annotations.iter()
+	.filter(|ann| match kind {
+	    AnnotationKind::Size => ann.alters_size(),
+	    AnnotationKind::Stiffness => ann.alters_stiffness(),
+	    AnnotationKind::Pin => ann.get_constraint() == Some(laplacian::ConstraintKind::Pin),
+	    AnnotationKind::Anchor => ann.get_constraint() == Some(laplacian::ConstraintKind::Anchor),
+	    AnnotationKind::Free => ann.get_constraint() == Some(laplacian::ConstraintKind::Free),
+	})
+	.map(|ann| ann.get_render_index())
+	.filter(|i| *i < num_points)
+	.collect();
+
+    points.sort();
+    points.dedup();
+    points
+}
+
 pub struct AnnotationState {
     pub curr_cylinder_index: i32,
     pub curr_render_index: i32,
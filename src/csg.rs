@@ -0,0 +1,921 @@
+extern crate glm;
+
+use crate::program;
+use crate::cylinder;
+use crate::objects;
+use crate::lineobjects;
+use crate::Object;
+
+use std::collections::{HashMap, HashSet};
+
+// Boolean operations on closed triangle meshes, with binary space partitioning trees as in
+// csg.js. Polygons are split by the planes of the other mesh and kept or dropped by which
+// side they end up on. Faces that lie in the same plane are told apart by their normals,
+// so coplanar overlaps are kept once. The result is welded, and triangles with vertices
+// on their edges are split, so that closed inputs give a closed manifold mesh.
+//
+// The splitting is done in double precision, and the point where a plane cuts an edge is
+// always found from the same end of the edge, so that the two polygons on either side of
+// an edge are cut at exactly the same point. Where the surfaces are nearly tangent the
+// classification can still disagree over a sliver, so the result is closed up at the end,
+// see close_seams.
+
+// Distance within which a point lies on a plane
+static PLANE_EPSILON: f64 = 1e-7;
+
+// Distance within which vertices are merged, and points lie on edges. Kept above the
+// precision of the single precision meshes that come out
+static WELD_EPSILON: f64 = 1e-7;
+
+type Point = [f64; 3];
+
+fn add(a: Point, b: Point) -> Point {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
+fn sub(a: Point, b: Point) -> Point {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn scale(a: Point, s: f64) -> Point {
+    [a[0] * s, a[1] * s, a[2] * s]
+}
+
+fn dot(a: Point, b: Point) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: Point, b: Point) -> Point {
+    [a[1] * b[2] - a[2] * b[1],
+     a[2] * b[0] - a[0] * b[2],
+     a[0] * b[1] - a[1] * b[0]]
+}
+
+fn length(a: Point) -> f64 {
+    dot(a, a).sqrt()
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum BooleanOperation {
+    Union,
+    Difference,
+    Intersection,
+}
+
+// Triangles with counter-clockwise corners seen from outside
+pub struct TriangleMesh {
+    pub vertices: Vec<f32>,
+    pub colors: Vec<f32>,
+    pub indices: Vec<u32>,
+}
+
+#[derive(Copy, Clone)]
+struct Vertex {
+    position: Point,
+    color: glm::Vec3,
+}
+
+impl Vertex {
+    fn interpolate(&self, other: &Vertex, t: f64) -> Vertex {
+	Vertex { position: add(self.position, scale(sub(other.position, self.position), t)),
+		 color: self.color + (other.color - self.color) * t as f32 }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Plane {
+    normal: Point,
+    w: f64,
+}
+
+impl Plane {
+    fn from_points(a: Point, b: Point, c: Point) -> Option<Plane> {
+	let n = cross(sub(b, a), sub(c, a));
+	let l = length(n);
+	if l < 1e-18 {
+	    return None;
+	}
+
+	let normal = scale(n, 1.0 / l);
+	Some(Plane { normal, w: dot(normal, a) })
+    }
+
+    fn flip(&mut self) {
+	self.normal = scale(self.normal, -1.0);
+	self.w = -self.w;
+    }
+
+    fn distance(&self, p: Point) -> f64 {
+	dot(self.normal, p) - self.w
+    }
+
+    // Where the edge crosses the plane, found from the lower of its ends either way round
+    fn intersect(&self, a: &Vertex, b: &Vertex) -> Vertex {
+	let (a, b) = if a.position < b.position { (a, b) } else { (b, a) };
+	let t = (self.w - dot(self.normal, a.position)) / dot(self.normal, sub(b.position, a.position));
+	a.interpolate(b, t.max(0.0).min(1.0))
+    }
+}
+
+// Convex polygons in the plane they came from
+#[derive(Clone)]
+struct Polygon {
+    vertices: Vec<Vertex>,
+    plane: Plane,
+}
+
+impl Polygon {
+    fn flip(&mut self) {
+	self.vertices.reverse();
+	self.plane.flip();
+    }
+}
+
+const COPLANAR: u8 = 0;
+const FRONT: u8 = 1;
+const BACK: u8 = 2;
+const SPANNING: u8 = 3;
+
+// Sorts the polygon, or its pieces, into the lists by their side of the plane
+fn split_polygon(plane: &Plane,
+		 polygon: Polygon,
+		 coplanar_front: &mut Vec<Polygon>,
+		 coplanar_back: &mut Vec<Polygon>,
+		 front: &mut Vec<Polygon>,
+		 back: &mut Vec<Polygon>) {
+    let types : Vec<u8> = polygon.vertices.iter()
+	.map(|v| {
+	    let t = plane.distance(v.position);
+	    if t < -PLANE_EPSILON { BACK } else if t > PLANE_EPSILON { FRONT } else { COPLANAR }
+	})
+	.collect();
+    let polygon_type = types.iter().fold(COPLANAR, |m, t| m | t);
+
+    match polygon_type {
+	COPLANAR => {
+	    if dot(plane.normal, polygon.plane.normal) > 0.0 {
+		coplanar_front.push(polygon);
+	    } else {
+		coplanar_back.push(polygon);
+	    }
+	},
+	FRONT => front.push(polygon),
+	BACK => back.push(polygon),
+	_ => {
+	    let n = polygon.vertices.len();
+	    let mut f : Vec<Vertex> = Vec::new();
+	    let mut b : Vec<Vertex> = Vec::new();
+
+	    for i in 0..n {
+		let j = (i + 1) % n;
+		let (ti, tj) = (types[i], types[j]);
+		let (vi, vj) = (polygon.vertices[i], polygon.vertices[j]);
+
+		if ti != BACK {
+		    f.push(vi);
+		}
+		if ti != FRONT {
+		    b.push(vi);
+		}
+		if (ti | tj) == SPANNING {
+		    let v = plane.intersect(&vi, &vj);
+		    f.push(v);
+		    b.push(v);
+		}
+	    }
+
+	    if f.len() >= 3 {
+		front.push(Polygon { vertices: f, plane: polygon.plane });
+	    }
+	    if b.len() >= 3 {
+		back.push(Polygon { vertices: b, plane: polygon.plane });
+	    }
+	}
+    }
+}
+
+struct Node {
+    plane: Option<Plane>,
+    front: Option<usize>,
+    back: Option<usize>,
+    polygons: Vec<Polygon>,
+}
+
+impl Node {
+    fn empty() -> Node {
+	Node { plane: None, front: None, back: None, polygons: Vec::new() }
+    }
+}
+
+// The nodes are kept in a list and walked without recursion, since the trees of
+// tubes are deep
+struct Tree {
+    nodes: Vec<Node>,
+}
+
+impl Tree {
+    fn new(polygons: Vec<Polygon>) -> Tree {
+	let mut tree = Tree { nodes: vec![Node::empty()] };
+	tree.build(polygons);
+	tree
+    }
+
+    fn build(&mut self, polygons: Vec<Polygon>) {
+	let mut stack = vec![(0, polygons)];
+
+	while let Some((n, polygons)) = stack.pop() {
+	    if polygons.len() == 0 {
+		continue;
+	    }
+
+	    let plane = match self.nodes[n].plane {
+		Some(plane) => plane,
+		None => {
+		    self.nodes[n].plane = Some(polygons[0].plane);
+		    polygons[0].plane
+		}
+	    };
+
+	    let mut coplanar : Vec<Polygon> = Vec::new();
+	    let mut coplanar_back : Vec<Polygon> = Vec::new();
+	    let mut front : Vec<Polygon> = Vec::new();
+	    let mut back : Vec<Polygon> = Vec::new();
+	    for polygon in polygons {
+		split_polygon(&plane, polygon, &mut coplanar, &mut coplanar_back, &mut front, &mut back);
+	    }
+	    coplanar.extend(coplanar_back);
+	    self.nodes[n].polygons.extend(coplanar);
+
+	    if front.len() > 0 {
+		let child = self.get_child(n, true);
+		stack.push((child, front));
+	    }
+	    if back.len() > 0 {
+		let child = self.get_child(n, false);
+		stack.push((child, back));
+	    }
+	}
+    }
+
+    fn get_child(&mut self, n: usize, front: bool) -> usize {
+	let existing = if front { self.nodes[n].front } else { self.nodes[n].back };
+	match existing {
+	    Some(child) => child,
+	    None => {
+		self.nodes.push(Node::empty());
+		let child = self.nodes.len() - 1;
+		if front {
+		    self.nodes[n].front = Some(child);
+		} else {
+		    self.nodes[n].back = Some(child);
+		}
+		child
+	    }
+	}
+    }
+
+    // Swaps inside and outside
+    fn invert(&mut self) {
+	for node in self.nodes.iter_mut() {
+	    for polygon in node.polygons.iter_mut() {
+		polygon.flip();
+	    }
+	    if let Some(plane) = node.plane.as_mut() {
+		plane.flip();
+	    }
+	    std::mem::swap(&mut node.front, &mut node.back);
+	}
+    }
+
+    // Removes the parts of the polygons that are inside this tree
+    fn clip_polygons(&self, polygons: Vec<Polygon>) -> Vec<Polygon> {
+	let mut result : Vec<Polygon> = Vec::new();
+	let mut stack = vec![(0, polygons)];
+
+	while let Some((n, polygons)) = stack.pop() {
+	    let node = &self.nodes[n];
+	    let plane = match node.plane {
+		Some(plane) => plane,
+		None => {
+		    result.extend(polygons);
+		    continue;
+		}
+	    };
+
+	    let mut front : Vec<Polygon> = Vec::new();
+	    let mut back : Vec<Polygon> = Vec::new();
+	    for polygon in polygons {
+		let (mut coplanar_front, mut coplanar_back) = (Vec::new(), Vec::new());
+		split_polygon(&plane, polygon, &mut coplanar_front, &mut coplanar_back, &mut front, &mut back);
+		front.extend(coplanar_front);
+		back.extend(coplanar_back);
+	    }
+
+	    match node.front {
+		Some(child) => stack.push((child, front)),
+		None => result.extend(front),
+	    }
+	    // Behind a leaf is inside
+	    if let Some(child) = node.back {
+		stack.push((child, back));
+	    }
+	}
+
+	result
+    }
+
+    fn clip_to(&mut self, other: &Tree) {
+	for node in self.nodes.iter_mut() {
+	    let polygons = std::mem::replace(&mut node.polygons, Vec::new());
+	    node.polygons = other.clip_polygons(polygons);
+	}
+    }
+
+    fn all_polygons(&self) -> Vec<Polygon> {
+	self.nodes.iter().flat_map(|n| n.polygons.iter().cloned()).collect()
+    }
+}
+
+fn get_polygons(mesh: &TriangleMesh) -> Vec<Polygon> {
+    let vertex = |i: u32| {
+	let i = i as usize;
+	let color = if mesh.colors.len() == mesh.vertices.len() {
+	    glm::vec3(mesh.colors[3 * i], mesh.colors[3 * i + 1], mesh.colors[3 * i + 2])
+	} else {
+	    glm::vec3(1.0, 1.0, 1.0)
+	};
+	Vertex { position: [mesh.vertices[3 * i] as f64, mesh.vertices[3 * i + 1] as f64, mesh.vertices[3 * i + 2] as f64],
+		 color }
+    };
+
+    mesh.indices.chunks(3)
+	.filter(|t| t.len() == 3)
+	.filter_map(|t| {
+	    let vertices = vec![vertex(t[0]), vertex(t[1]), vertex(t[2])];
+	    Plane::from_points(vertices[0].position, vertices[1].position, vertices[2].position)
+		.map(|plane| Polygon { vertices, plane })
+	})
+	.collect()
+}
+
+fn combine(a: &TriangleMesh, b: &TriangleMesh, operation: BooleanOperation) -> Vec<Polygon> {
+    let mut a = Tree::new(get_polygons(a));
+    let mut b = Tree::new(get_polygons(b));
+
+    match operation {
+	BooleanOperation::Union => {
+	    a.clip_to(&b);
+	    b.clip_to(&a);
+	    b.invert();
+	    b.clip_to(&a);
+	    b.invert();
+	    a.build(b.all_polygons());
+	},
+	BooleanOperation::Difference => {
+	    a.invert();
+	    a.clip_to(&b);
+	    b.clip_to(&a);
+	    b.invert();
+	    b.clip_to(&a);
+	    b.invert();
+	    a.build(b.all_polygons());
+	    a.invert();
+	},
+	BooleanOperation::Intersection => {
+	    a.invert();
+	    b.clip_to(&a);
+	    b.invert();
+	    a.clip_to(&b);
+	    b.clip_to(&a);
+	    a.build(b.all_polygons());
+	    a.invert();
+	},
+    }
+
+    a.all_polygons()
+}
+
+// Merges vertices closer than the weld distance, looking in the neighboring cells of a
+// hash grid so that close vertices on both sides of a cell border are found
+struct Welder {
+    cells: HashMap<(i64, i64, i64), Vec<u32>>,
+    positions: Vec<Point>,
+    colors: Vec<glm::Vec3>,
+}
+
+impl Welder {
+    fn new() -> Welder {
+	Welder { cells: HashMap::new(), positions: Vec::new(), colors: Vec::new() }
+    }
+
+    fn cell(p: Point) -> (i64, i64, i64) {
+	((p[0] / WELD_EPSILON).floor() as i64,
+	 (p[1] / WELD_EPSILON).floor() as i64,
+	 (p[2] / WELD_EPSILON).floor() as i64)
+    }
+
+    fn add(&mut self, vertex: &Vertex) -> u32 {
+	let (x, y, z) = Welder::cell(vertex.position);
+	for dx in -1..=1 {
+	    for dy in -1..=1 {
+		for dz in -1..=1 {
+		    if let Some(indices) = self.cells.get(&(x + dx, y + dy, z + dz)) {
+			for i in indices {
+			    if length(sub(self.positions[*i as usize], vertex.position)) <= WELD_EPSILON {
+				return *i;
+			    }
+			}
+		    }
+		}
+	    }
+	}
+
+	let i = self.positions.len() as u32;
+	self.positions.push(vertex.position);
+	self.colors.push(vertex.color);
+	self.cells.entry((x, y, z)).or_insert_with(Vec::new).push(i);
+	i
+    }
+}
+
+fn get_area(positions: &Vec<Point>, a: u32, b: u32, c: u32) -> f64 {
+    let (pa, pb, pc) = (positions[a as usize], positions[b as usize], positions[c as usize]);
+    length(cross(sub(pb, pa), sub(pc, pa))) / 2.0
+}
+
+// Vertices that lie inside the edge from a to b, ordered from a
+fn get_edge_points(positions: &Vec<Point>,
+		   grid: &HashMap<(i64, i64, i64), Vec<u32>>,
+		   cell: f64,
+		   a: u32,
+		   b: u32) -> Vec<u32> {
+    let (pa, pb) = (positions[a as usize], positions[b as usize]);
+    let ab = sub(pb, pa);
+    let l2 = dot(ab, ab);
+    if l2 < 1e-30 {
+	return Vec::new();
+    }
+
+    let range = |k: usize| ((pa[k].min(pb[k]) - WELD_EPSILON) / cell).floor() as i64..=
+	((pa[k].max(pb[k]) + WELD_EPSILON) / cell).floor() as i64;
+    let mut points : Vec<(f64, u32)> = Vec::new();
+
+    for x in range(0) {
+	for y in range(1) {
+	    for z in range(2) {
+		for v in grid.get(&(x, y, z)).into_iter().flat_map(|v| v.iter()) {
+		    if *v == a || *v == b {
+			continue;
+		    }
+		    let p = positions[*v as usize];
+		    let t = dot(sub(p, pa), ab) / l2;
+		    let margin = WELD_EPSILON / l2.sqrt();
+		    if t > margin && t < 1.0 - margin && length(sub(p, add(pa, scale(ab, t)))) < WELD_EPSILON {
+			points.push((t, *v));
+		    }
+		}
+	    }
+	}
+    }
+
+    points.sort_by(|p, q| p.0.partial_cmp(&q.0).unwrap());
+    points.dedup_by_key(|p| p.1);
+    points.iter().map(|p| p.1).collect()
+}
+
+// Triangles with vertices of their neighbors lying on their edges are split, with a new
+// vertex in their middle, so that every edge is shared by whole triangles
+fn split_t_junctions(positions: &mut Vec<Point>,
+		     colors: &mut Vec<glm::Vec3>,
+		     triangles: Vec<[u32; 3]>) -> Vec<[u32; 3]> {
+    if triangles.len() == 0 {
+	return triangles;
+    }
+
+    // Cells about the size of an edge keep the lookups short
+    let total : f64 = triangles.iter()
+	.map(|t| length(sub(positions[t[1] as usize], positions[t[0] as usize])))
+	.sum();
+    let cell = (total / triangles.len() as f64).max(WELD_EPSILON * 10.0);
+
+    let mut grid : HashMap<(i64, i64, i64), Vec<u32>> = HashMap::new();
+    for (i, p) in positions.iter().enumerate() {
+	let key = ((p[0] / cell).floor() as i64, (p[1] / cell).floor() as i64, (p[2] / cell).floor() as i64);
+	grid.entry(key).or_insert_with(Vec::new).push(i as u32);
+    }
+
+    let mut result : Vec<[u32; 3]> = Vec::with_capacity(triangles.len());
+
+    for t in triangles {
+	let mut outline : Vec<u32> = Vec::new();
+	for k in 0..3 {
+	    outline.push(t[k]);
+	    outline.extend(get_edge_points(positions, &grid, cell, t[k], t[(k + 1) % 3]));
+	}
+
+	if outline.len() == 3 {
+	    result.push(t);
+	    continue;
+	}
+
+	let corners = [positions[t[0] as usize], positions[t[1] as usize], positions[t[2] as usize]];
+	let center_color = (colors[t[0] as usize] + colors[t[1] as usize] + colors[t[2] as usize]) / 3.0;
+	positions.push(scale(add(add(corners[0], corners[1]), corners[2]), 1.0 / 3.0));
+	colors.push(center_color);
+	let center = (positions.len() - 1) as u32;
+
+	for k in 0..outline.len() {
+	    result.push([center, outline[k], outline[(k + 1) % outline.len()]]);
+	}
+    }
+
+    result
+}
+
+// Pieces of faces that touch where the meshes meet can come out as the same triangle
+// twice, facing both ways. Such pairs enclose nothing and are dropped, as are copies
+fn remove_fins(triangles: Vec<[u32; 3]>) -> Vec<[u32; 3]> {
+    let mut faces : HashMap<[u32; 3], Vec<usize>> = HashMap::new();
+    for (i, t) in triangles.iter().enumerate() {
+	let mut key = *t;
+	key.sort();
+	faces.entry(key).or_insert_with(Vec::new).push(i);
+    }
+
+    let mut keep = vec![true; triangles.len()];
+    for uses in faces.values().filter(|u| u.len() > 1) {
+	// Rotated so that the lowest index comes first, the two windings differ in order
+	let winding = |t: &[u32; 3]| {
+	    let k = (0..3).min_by_key(|k| t[*k]).unwrap();
+	    t[(k + 1) % 3] < t[(k + 2) % 3]
+	};
+	let (up, down) : (Vec<usize>, Vec<usize>) = uses.iter().partition(|i| winding(&triangles[**i]));
+	let pairs = up.len().min(down.len());
+	for i in up.iter().skip(pairs).skip(1).chain(down.iter().skip(pairs).skip(1)) {
+	    keep[*i] = false;
+	}
+	for i in up.iter().take(pairs).chain(down.iter().take(pairs)) {
+	    keep[*i] = false;
+	}
+    }
+
+    triangles.iter().zip(keep.iter()).filter(|(_, k)| **k).map(|(t, _)| *t).collect()
+}
+
+// Triangulates a hole, given the corners along its rim in the direction the triangles
+// around it go, cutting off the corner with the shortest diagonal each time. Fails if a
+// diagonal would be an edge that the mesh has already
+fn fill_hole(positions: &Vec<Point>,
+	     edges: &HashMap<(u32, u32), usize>,
+	     mut rim: Vec<u32>) -> Option<Vec<[u32; 3]>> {
+    let mut fill : Vec<[u32; 3]> = Vec::new();
+    let mut diagonals : Vec<(u32, u32)> = Vec::new();
+    let used = |a: u32, b: u32, diagonals: &Vec<(u32, u32)>| {
+	edges.contains_key(&(a, b)) || edges.contains_key(&(b, a)) || diagonals.contains(&(a.min(b), a.max(b)))
+    };
+
+    while rim.len() > 3 {
+	let n = rim.len();
+	let ends = |k: usize| (rim[(k + n - 1) % n], rim[(k + 1) % n]);
+	let diagonal = |k: usize| {
+	    let (a, b) = ends(k);
+	    length(sub(positions[a as usize], positions[b as usize]))
+	};
+	let k = (0..n)
+	    .filter(|k| !used(ends(*k).0, ends(*k).1, &diagonals))
+	    .min_by(|i, j| diagonal(*i).partial_cmp(&diagonal(*j)).unwrap())?;
+	let (a, b) = ends(k);
+	diagonals.push((a.min(b), a.max(b)));
+	fill.push([b, rim[k], a]);
+	rim.remove(k);
+    }
+    if rim.len() == 3 {
+	fill.push([rim[2], rim[1], rim[0]]);
+    }
+
+    Some(fill)
+}
+
+// Where the surfaces of the meshes are nearly tangent, a sliver can be classified
+// differently from the two sides, leaving a small hole or a face covered twice. Faces
+// that share an edge in the same direction are thinned out to the largest, and the
+// holes left are filled. Holes that can't be filled without doubling an edge are widened
+// by the triangles along their rim first
+fn close_seams(positions: &Vec<Point>, mut triangles: Vec<[u32; 3]>) -> Vec<[u32; 3]> {
+    for _ in 0..8 {
+	let mut edges : HashMap<(u32, u32), Vec<usize>> = HashMap::new();
+	for (i, t) in triangles.iter().enumerate() {
+	    for k in 0..3 {
+		edges.entry((t[k], t[(k + 1) % 3])).or_insert_with(Vec::new).push(i);
+	    }
+	}
+
+	let mut keep = vec![true; triangles.len()];
+	let area = |i: usize| get_area(positions, triangles[i][0], triangles[i][1], triangles[i][2]);
+	for uses in edges.values().filter(|u| u.len() > 1) {
+	    let largest = *uses.iter().max_by(|i, j| area(**i).partial_cmp(&area(**j)).unwrap()).unwrap();
+	    for i in uses.iter().filter(|i| **i != largest) {
+		keep[*i] = false;
+	    }
+	}
+	triangles = remove_fins(triangles.iter().zip(keep.iter()).filter(|(_, k)| **k).map(|(t, _)| *t).collect());
+
+	let mut edges : HashMap<(u32, u32), usize> = HashMap::new();
+	for t in &triangles {
+	    for k in 0..3 {
+		*edges.entry((t[k], t[(k + 1) % 3])).or_insert(0) += 1;
+	    }
+	}
+	if edges.iter().all(|((a, b), uses)| *uses == 1 && edges.contains_key(&(*b, *a))) {
+	    break;
+	}
+
+	let mut open : HashMap<u32, Vec<u32>> = HashMap::new();
+	for (a, b) in edges.keys().filter(|(a, b)| !edges.contains_key(&(*b, *a))) {
+	    open.entry(*a).or_insert_with(Vec::new).push(*b);
+	}
+
+	// Walks along the open edges, and fills every loop that closes on the way
+	let mut widen : HashSet<(u32, u32)> = HashSet::new();
+	while let Some(&start) = open.keys().next() {
+	    let mut path = vec![start];
+	    loop {
+		let v = path[path.len() - 1];
+		let next = match open.get_mut(&v).and_then(|n| n.pop()) {
+		    Some(next) => next,
+		    None => break,
+		};
+		if open[&v].is_empty() {
+		    open.remove(&v);
+		}
+		match path.iter().position(|p| *p == next) {
+		    Some(k) => {
+			let rim = path.split_off(k);
+			match fill_hole(positions, &edges, rim.clone()) {
+			    Some(fill) => triangles.extend(fill),
+			    None => widen.extend((0..rim.len()).map(|i| (rim[i], rim[(i + 1) % rim.len()]))),
+			}
+			path.push(next);
+		    },
+		    None => path.push(next),
+		}
+	    }
+	}
+
+	triangles.retain(|t| !(0..3).any(|k| widen.contains(&(t[k], t[(k + 1) % 3]))));
+    }
+
+    triangles
+}
+
+fn to_mesh(polygons: Vec<Polygon>) -> TriangleMesh {
+    let mut welder = Welder::new();
+    let mut triangles : Vec<[u32; 3]> = Vec::new();
+
+    for polygon in &polygons {
+	let mut indices : Vec<u32> = polygon.vertices.iter().map(|v| welder.add(v)).collect();
+	indices.dedup();
+	while indices.len() > 1 && indices[0] == indices[indices.len() - 1] {
+	    indices.pop();
+	}
+
+	// The polygons are convex, so fans cover them
+	for k in 1..indices.len().max(2) - 1 {
+	    let triangle = [indices[0], indices[k], indices[k + 1]];
+	    triangles.push(triangle);
+	}
+    }
+
+    let mut positions = welder.positions;
+    let mut colors = welder.colors;
+    let triangles = remove_fins(split_t_junctions(&mut positions, &mut colors, triangles));
+    let triangles = close_seams(&positions, triangles);
+
+    TriangleMesh { vertices: positions.iter().flat_map(|p| vec![p[0] as f32, p[1] as f32, p[2] as f32]).collect(),
+		   colors: colors.iter().flat_map(|c| vec![c.x, c.y, c.z]).collect(),
+		   indices: triangles.iter().flat_map(|t| t.to_vec()).collect() }
+}
+
+pub fn apply(a: &TriangleMesh, b: &TriangleMesh, operation: BooleanOperation) -> TriangleMesh {
+    to_mesh(combine(a, b, operation))
+}
+
+// The union of the additive cylinders of the session, as they are meshed, with the
+// subtractive ones cut out of it. Cylinders with open ends enclose nothing and are left out
+pub fn get_session_union(session: &program::Session) -> TriangleMesh {
+    let mut union = TriangleMesh { vertices: Vec::new(), colors: Vec::new(), indices: Vec::new() };
+
+    for mode in &[cylinder::CombineMode::Add, cylinder::CombineMode::Subtract] {
+	for cylinder in session.cylinders.iter().filter(|c| c.get_mode() == *mode && c.is_closed()) {
+	    let mesh = TriangleMesh { vertices: cylinder.object.vertices.clone(),
+				      colors: cylinder.object.colors.clone(),
+				      indices: cylinder.object.indices.clone() };
+	    union = if union.indices.len() == 0 {
+		// Nothing to carve
+		if *mode == cylinder::CombineMode::Subtract {
+		    break;
+		}
+		to_mesh(get_polygons(&mesh))
+	    } else if *mode == cylinder::CombineMode::Add {
+		apply(&union, &mesh, BooleanOperation::Union)
+	    } else {
+		apply(&union, &mesh, BooleanOperation::Difference)
+	    };
+	}
+    }
+
+    union
+}
+
+// The carved cylinders, shown in place of the closed additive ones while any cylinder is
+// subtractive
+pub struct CarvedSurface {
+    pub object: Object,
+    pub line_object: lineobjects::LineObject,
+}
+
+// Remakes the carved surface when a cylinder's mesh or mode has changed since it was
+// last made
+pub fn update_carved_surface(session: &mut program::Session) {
+    if session.cylinders.iter().all(|c| c.get_mode() == cylinder::CombineMode::Add) {
+	session.carved = None;
+	session.carved_fingerprint = None;
+	return;
+    }
+
+    let fingerprint = program::get_fingerprint(session);
+    if session.carved_fingerprint == Some(fingerprint) {
+	return;
+    }
+
+    let mesh = get_session_union(session);
+    session.carved = if mesh.indices.len() > 0 {
+	let normals = objects::compute_normals(&mesh.vertices, &mesh.indices);
+	Some(CarvedSurface {
+	    line_object: lineobjects::create_line_object(&mesh.vertices, &mesh.indices),
+	    object: objects::create_object_with_colors(mesh.vertices, normals, mesh.colors, mesh.indices) })
+    } else {
+	None
+    };
+    session.carved_fingerprint = Some(fingerprint);
+}
+
+// Closed meshes have every edge shared by exactly two triangles, in opposite directions
+#[derive(Copy, Clone, Debug)]
+pub struct ValidationReport {
+    pub num_vertices: usize,
+    pub num_triangles: usize,
+    pub degenerate_triangles: usize,
+    pub boundary_edges: usize,     // Used by one triangle
+    pub non_manifold_edges: usize, // Used by more than two triangles
+    pub flipped_edges: usize,      // Used twice in the same direction
+}
+
+impl ValidationReport {
+    pub fn is_watertight(&self) -> bool {
+	self.boundary_edges == 0 && self.non_manifold_edges == 0
+    }
+
+    pub fn is_manifold(&self) -> bool {
+	self.is_watertight() && self.flipped_edges == 0
+    }
+
+    pub fn get_summary(&self) -> String {
+	format!("{} vertices, {} triangles\n{}\n{} boundary, {} non-manifold, {} flipped edges, {} degenerate triangles",
+		self.num_vertices, self.num_triangles,
+		if self.is_manifold() { "Closed manifold" } else if self.is_watertight() { "Watertight, badly oriented" } else { "Not watertight" },
+		self.boundary_edges, self.non_manifold_edges, self.flipped_edges, self.degenerate_triangles)
+    }
+}
+
+pub fn validate(mesh: &TriangleMesh) -> ValidationReport {
+    let positions : Vec<Point> = mesh.vertices.chunks(3).map(|p| [p[0] as f64, p[1] as f64, p[2] as f64]).collect();
+
+    // Uses of each edge, and the uses from the lower to the higher index minus the others
+    let mut edges : HashMap<(u32, u32), (usize, i64)> = HashMap::new();
+    let mut degenerate_triangles = 0;
+
+    for t in mesh.indices.chunks(3) {
+	if t[0] == t[1] || t[1] == t[2] || t[2] == t[0] ||
+	    get_area(&positions, t[0], t[1], t[2]) <= WELD_EPSILON * WELD_EPSILON {
+		degenerate_triangles += 1;
+	    }
+
+	for k in 0..3 {
+	    let (a, b) = (t[k], t[(k + 1) % 3]);
+	    let entry = edges.entry((a.min(b), a.max(b))).or_insert((0, 0));
+	    entry.0 += 1;
+	    entry.1 += if a < b { 1 } else { -1 };
+	}
+    }
+
+    ValidationReport { num_vertices: positions.len(),
+		       num_triangles: mesh.indices.len() / 3,
+		       degenerate_triangles,
+		       boundary_edges: edges.values().filter(|e| e.0 == 1).count(),
+		       non_manifold_edges: edges.values().filter(|e| e.0 > 2).count(),
+		       flipped_edges: edges.values().filter(|e| e.0 == 2 && e.1 != 0).count() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A tube swept along a curve, with flat caps and its triangles facing outwards
+    fn sweep(curve: &dyn Fn(f64) -> Point, rings: usize, sides: usize, radius: f64) -> TriangleMesh {
+	let mut vertices : Vec<Point> = Vec::new();
+	for i in 0..=rings {
+	    let s = i as f64 / rings as f64;
+	    let tangent = sub(curve(s + 1e-4), curve(s - 1e-4));
+	    let tangent = scale(tangent, 1.0 / length(tangent));
+	    let normal = cross(tangent, [0.0, 0.0, 1.0]);
+	    let normal = scale(normal, 1.0 / length(normal));
+	    let binormal = cross(tangent, normal);
+	    for j in 0..sides {
+		let angle = j as f64 / sides as f64 * 2.0 * std::f64::consts::PI;
+		vertices.push(add(curve(s), add(scale(normal, radius * angle.cos()), scale(binormal, radius * angle.sin()))));
+	    }
+	}
+	vertices.push(curve(0.0));
+	vertices.push(curve(1.0));
+
+	let index = |i: usize, j: usize| (i * sides + j % sides) as u32;
+	let (start, end) = ((vertices.len() - 2) as u32, (vertices.len() - 1) as u32);
+	let mut indices : Vec<u32> = Vec::new();
+	for j in 0..sides {
+	    for i in 0..rings {
+		indices.extend(&[index(i, j), index(i, j + 1), index(i + 1, j + 1)]);
+		indices.extend(&[index(i, j), index(i + 1, j + 1), index(i + 1, j)]);
+	    }
+	    indices.extend(&[start, index(0, j + 1), index(0, j)]);
+	    indices.extend(&[end, index(rings, j), index(rings, j + 1)]);
+	}
+
+	TriangleMesh { vertices: vertices.iter().flat_map(|p| vec![p[0] as f32, p[1] as f32, p[2] as f32]).collect(),
+		       colors: vec![1.0; vertices.len() * 3],
+		       indices }
+    }
+
+    // Signed volume, positive for closed meshes facing outwards
+    fn volume(mesh: &TriangleMesh) -> f64 {
+	let point = |i: u32| -> Point {
+	    let i = i as usize * 3;
+	    [mesh.vertices[i] as f64, mesh.vertices[i + 1] as f64, mesh.vertices[i + 2] as f64]
+	};
+	mesh.indices.chunks(3)
+	    .map(|t| dot(point(t[0]), cross(point(t[1]), point(t[2]))) / 6.0)
+	    .sum()
+    }
+
+    #[test]
+    fn swept_cylinders_cross_into_closed_meshes() {
+	for k in 0..6 {
+	    let offset = k as f64 * 0.013;
+	    let a = sweep(&|s| [s - 0.5, 0.1 * (1.0 - 4.0 * (s - 0.5) * (s - 0.5)) + offset, 0.05 * (3.0 * s).sin()],
+			  16, 12, 0.1);
+	    let b = sweep(&|s| [0.05 * (2.0 * s).cos() + offset, s - 0.5, 0.06 * (s - 0.5) - 0.02],
+			  16, 12, 0.1);
+	    assert!(validate(&a).is_manifold() && validate(&b).is_manifold());
+
+	    for operation in &[BooleanOperation::Union, BooleanOperation::Difference, BooleanOperation::Intersection] {
+		let report = validate(&apply(&a, &b, *operation));
+		assert!(report.num_triangles > 0, "{:?} at offset {}", operation, offset);
+		assert!(report.is_manifold(), "{:?} at offset {}: {}", operation, offset, report.get_summary());
+	    }
+	}
+    }
+
+    // Identical tubes, tubes meeting cap to cap and a thinner tube ending in the cap of a
+    // wider one all have faces in the same plane
+    #[test]
+    fn coplanar_faces_stay_closed() {
+	let straight = |x0: f64, x1: f64| move |s: f64| [x0 + (x1 - x0) * s, 0.0, 0.0];
+	let whole = sweep(&straight(-0.5, 0.5), 4, 12, 0.1);
+	let left = sweep(&straight(-0.5, 0.0), 2, 12, 0.1);
+	let right = sweep(&straight(0.0, 0.5), 2, 12, 0.1);
+	let thin = sweep(&straight(0.0, 0.5), 2, 12, 0.05);
+	let (v_whole, v_left, v_right, v_thin) = (volume(&whole), volume(&left), volume(&right), volume(&thin));
+
+	// Expected volumes of the union, the difference and the intersection
+	let cases = [(&whole, &whole, [v_whole, 0.0, v_whole]),
+		     (&left, &right, [v_left + v_right, v_left, 0.0]),
+		     (&whole, &thin, [v_whole, v_whole - v_thin, v_thin])];
+	let operations = [BooleanOperation::Union, BooleanOperation::Difference, BooleanOperation::Intersection];
+
+	for (n, (a, b, volumes)) in cases.iter().enumerate() {
+	    for (operation, expected) in operations.iter().zip(volumes.iter()) {
+		let result = apply(a, b, *operation);
+		let report = validate(&result);
+		assert!(report.is_manifold(), "{:?} in case {}: {}", operation, n, report.get_summary());
+
+		if *expected == 0.0 {
+		    assert_eq!(report.num_triangles, 0, "{:?} in case {}", operation, n);
+		} else {
+		    assert!(report.num_triangles > 0, "{:?} in case {}", operation, n);
+		    assert!((volume(&result) - expected).abs() < 1e-4 * v_whole,
+			    "{:?} in case {}: volume {} instead of {}", operation, n, volume(&result), expected);
+		}
+	    }
+	}
+    }
+}
@@ -4,15 +4,152 @@ use crate::objects;
 use crate::lineobjects;
 use crate::shaders;
 use crate::annotation;
+use crate::tessellation;
+use crate::noise;
 
 use std::ffi::{CString};
 use crate::Object;
 
 use std::f32;
 
+pub static DEFAULT_RADIUS: f32 = 0.1;
+pub static DEFAULT_CIRC_RESOLUTION: usize = 5;
+
+// The hemispherical caps need at least two rings
+pub static MIN_CIRC_RESOLUTION: usize = 2;
+pub static MAX_CIRC_RESOLUTION: usize = 32;
+
+// Which end of the cylinder a cap is at
+pub static START_CAP: usize = 0;
+pub static END_CAP: usize = 1;
+
+// How the ends of open cylinders are closed. Closed loops never have caps
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CapStyle {
+    Hemisphere,
+    Flat,
+    Cone,
+    Ellipsoid,
+    Open,
+}
+
+// What to do where the spline bends tighter than the tube is wide, so that
+// neighboring rings overlap on the inner side of the bend
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum BendHandling {
+    Warn,
+    ClampRadius,
+    FixInnerSide,
+}
+
+// Subtractive cylinders carve into the additive ones instead of adding material. Only
+// closed cylinders carve. There is no separate sphere primitive: a short stroke with
+// rounded caps carves a round pocket through the same path
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CombineMode {
+    Add,
+    Subtract,
+}
+
+// How close rings may get to the fold when the radius is clamped or the inner side fixed
+static FOLD_MARGIN: f32 = 0.9;
+
+// The depth is relative to the radius at the end, and sets the length
+// of cones and ellipsoids
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Cap {
+    pub style: CapStyle,
+    pub depth: f32,
+}
+
+impl Cap {
+    pub fn new(style: CapStyle) -> Cap {
+	Cap { style, depth: 1.0 }
+    }
+
+    // Offset along the end direction and radius of the cap's ring at height t,
+    // both relative to the radius at the end. t = 1 is the tip
+    fn get_profile(&self, t: f32) -> (f32, f32) {
+	let phi = t * f32::consts::PI / 2.0;
+	match self.style {
+	    CapStyle::Hemisphere => (phi.sin(), phi.cos()),
+	    CapStyle::Ellipsoid => (phi.sin() * self.depth, phi.cos()),
+	    CapStyle::Cone => (t * self.depth, 1.0 - t),
+	    CapStyle::Flat | CapStyle::Open => (0.0, 1.0 - t),
+	}
+    }
+
+    // Length of the cap's profile up to height t, relative to the radius at the end
+    fn get_arc_length(&self, t: f32) -> f32 {
+	let num_steps = 32;
+	let mut length = 0.0;
+	let mut last = self.get_profile(0.0);
+
+	for i in 1..(num_steps + 1) {
+	    let curr = self.get_profile(t * i as f32 / num_steps as f32);
+	    length += ((curr.0 - last.0).powi(2) + (curr.1 - last.1).powi(2)).sqrt();
+	    last = curr;
+	}
+
+	length
+    }
+
+    // Outward normal of the cap at height t, in terms of the end direction and
+    // the direction away from the spline
+    fn get_normal(&self, t: f32) -> (f32, f32) {
+	let h = 1e-3;
+	let (a0, r0) = self.get_profile((t - h).max(0.0));
+	let (a1, r1) = self.get_profile((t + h).min(1.0));
+	let (da, dr) = (a1 - a0, r1 - r0);
+
+	let l = (da * da + dr * dr).sqrt();
+	if l < 1e-9 {
+	    (1.0, 0.0)
+	} else {
+	    (-dr / l, da / l)
+	}
+    }
+}
+
+// Surface color, varied by noise. The amplitude of the noise is in color units
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Paint {
+    pub color: [f32; 3],
+    pub variation: noise::NoiseSettings,
+}
+
+impl Paint {
+    pub fn new(color: [f32; 3]) -> Paint {
+	let mut variation = noise::NoiseSettings::new(noise::NoiseKind::Perlin);
+	variation.amplitude = 0.0;
+
+	Paint { color, variation }
+    }
+}
+
+// The swept surface, with positions and normals as flat lists of coordinates
+pub struct CylinderMesh {
+    pub vertices: Vec<f32>,
+    pub normals: Vec<f32>,
+    pub colors: Vec<f32>, // Red, green and blue in [0, 1]
+    pub uvs: Vec<f32>, // u along the caps and the spline, v around it, both in [0, 1]
+    pub length: f32, // Arc length that u runs over
+    pub indices: Vec<u32>,
+    pub folded_rings: usize, // Rings that overlapped their neighbors at tight bends
+    pub rings: Vec<tessellation::Ring>, // Centers and radius scales the surface was swept along
+}
+
 pub struct GeneralizedCylinder {
+    name : String,
     radius : f32,
     circ_resolution : usize,
+    caps : [Cap; 2],
+    tessellation : tessellation::TessellationSettings,
+    bend_handling : BendHandling,
+    mode : CombineMode,
+    folded_rings : usize, // Rings that overlapped their neighbors when the mesh was last made
+    uvs : Vec<f32>, // Texture coordinates of the object's vertices
+    rings : Vec<tessellation::Ring>,
     pub object : Object,
     pub line_object : lineobjects::LineObject,
     pub spline : splinedraw::SplineState,
@@ -21,21 +158,124 @@ pub struct GeneralizedCylinder {
 impl GeneralizedCylinder {
     pub fn update_mesh(self : &mut GeneralizedCylinder,
 		       annotations: &Vec<Box<dyn annotation::Annotation>>) {
-        let (vertices, indices) = get_cylinder_values(self.radius, self.circ_resolution, &self.spline,
-						      Some(annotations));
+        let mesh = get_cylinder_values(self.radius, self.circ_resolution, self.caps,
+				       &self.tessellation, self.bend_handling,
+				       &self.spline, Some(annotations));
+
+	self.folded_rings = mesh.folded_rings;
+
+	// Noise is displaced after the sweep, and the normals follow the displaced surface
+	let noises = annotation::get_noises(annotations);
+	let (vertices, normals) = if noises.len() > 0 {
+	    let vertices = displace(&mesh, &noises, self.radius, self.spline.closed);
+	    let normals = objects::compute_normals(&vertices, &mesh.indices);
+	    (vertices, normals)
+	} else {
+	    (mesh.vertices, mesh.normals)
+	};
+
+	self.uvs = mesh.uvs;
+	self.rings = mesh.rings;
 
         self.object.vertices = vertices;
-        self.object.indices = indices;
+        self.object.normals = normals;
+        self.object.colors = mesh.colors;
+        self.object.indices = mesh.indices;
         
         self.object.update_gpu_state();
         self.line_object.update(self.object.vertices.clone(), &self.object.indices);
     }
+
+    pub fn get_name(&self) -> &str {
+	&self.name
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+	self.name = name.to_string();
+    }
+
+    pub fn get_radius(&self) -> f32 {
+	self.radius
+    }
+
+    // The mesh must be regenerated with update_mesh for the new value to show
+    pub fn set_radius(&mut self, radius: f32) {
+	self.radius = radius.max(1e-3);
+    }
+
+    pub fn get_circ_resolution(&self) -> usize {
+	self.circ_resolution
+    }
+
+    pub fn set_circ_resolution(&mut self, circ_resolution: usize) {
+	self.circ_resolution = circ_resolution.max(MIN_CIRC_RESOLUTION).min(MAX_CIRC_RESOLUTION);
+    }
+
+    // end is START_CAP or END_CAP. Cap annotations on the end points take precedence
+    pub fn get_cap(&self, end: usize) -> Cap {
+	self.caps[end]
+    }
+
+    pub fn set_cap(&mut self, end: usize, cap: Cap) {
+	self.caps[end] = Cap { style: cap.style, depth: cap.depth.max(0.0) };
+    }
+
+    pub fn get_bend_handling(&self) -> BendHandling {
+	self.bend_handling
+    }
+
+    pub fn set_bend_handling(&mut self, bend_handling: BendHandling) {
+	self.bend_handling = bend_handling;
+    }
+
+    pub fn get_mode(&self) -> CombineMode {
+	self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: CombineMode) {
+	self.mode = mode;
+    }
+
+    // Whether the mesh encloses a solid, as the mesh booleans need
+    pub fn is_closed(&self) -> bool {
+	self.spline.closed || self.caps.iter().all(|c| c.style != CapStyle::Open)
+    }
+
+    pub fn get_folded_rings(&self) -> usize {
+	self.folded_rings
+    }
+
+    pub fn get_uvs(&self) -> &Vec<f32> {
+	&self.uvs
+    }
+
+    // The skeleton of the surface, with scales of the base radius
+    pub fn get_rings(&self) -> &Vec<tessellation::Ring> {
+	&self.rings
+    }
+
+    // With adaptive tessellation, the circumferential resolution is chosen automatically
+    pub fn set_tessellation(&mut self, tessellation: tessellation::TessellationSettings) {
+	self.tessellation = tessellation;
+    }
 }
 
-pub fn draw_cylinder(generalized_cylinder : &GeneralizedCylinder,
-		     body_program : &shaders::ShaderProgram,
-		     line_program : &shaders::ShaderProgram,
-		     transform : &glm::Mat4) {
+// Lit shading needs the normals of the mesh. The toon look draws a flat body with outlines
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ShadingMode {
+    Toon,
+    Lambert,
+    BlinnPhong,
+}
+
+// Towards the light, in world coordinates. The y-axis points down on the screen
+static LIGHT_DIRECTION: [f32; 3] = [-0.4, -0.6, 1.0];
+
+// Draws a mesh in the look of the cylinders. The line object holds its outlines
+pub fn draw_toon_body(object : &Object,
+		      line_object : &lineobjects::LineObject,
+		      body_program : &shaders::ShaderProgram,
+		      transform : &glm::Mat4) {
     let black_color = glm::vec4(0.0, 0.0, 0.0, 1.0);
     let white_color = glm::vec4(1.0, 1.0, 1.0, 1.0);
 
@@ -58,6 +298,11 @@ pub fn draw_cylinder(generalized_cylinder : &GeneralizedCylinder,
 	gl::GetUniformLocation(body_program.id,
 			       CString::new("uni_color").unwrap().as_ptr())
     };
+
+    let vertex_colors_location = unsafe {
+	gl::GetUniformLocation(body_program.id,
+			       CString::new("vertex_colors").unwrap().as_ptr())
+    };
     
     unsafe {
 	gl::UniformMatrix4fv(transform_location,
@@ -68,12 +313,12 @@ pub fn draw_cylinder(generalized_cylinder : &GeneralizedCylinder,
 		       1, &black_color[0]);
 	
 	gl::LineWidth(1.0);
-	gl::BindVertexArray(generalized_cylinder.line_object.all_vao);
+	gl::BindVertexArray(line_object.all_vao);
 
 	gl::DisableVertexAttribArray(1);
 	gl::DrawElements(
 	    gl::LINES,
-	    generalized_cylinder.line_object.all_indices.len() as gl::types::GLsizei,
+	    line_object.all_indices.len() as gl::types::GLsizei,
 	    gl::UNSIGNED_INT,
 	    std::ptr::null());
 
@@ -82,30 +327,93 @@ pub fn draw_cylinder(generalized_cylinder : &GeneralizedCylinder,
 		       1, &small_translation[0]);
 	gl::Uniform4fv(color_location,
 		       1, &white_color[0]);
+	gl::Uniform1i(vertex_colors_location, 1);
 	
-	gl::BindVertexArray(generalized_cylinder.object.vao);
+	gl::BindVertexArray(object.vao);
 	gl::DrawElements(
 	    gl::TRIANGLES,
-	    generalized_cylinder.object.indices.len() as gl::types::GLsizei,
+	    object.indices.len() as gl::types::GLsizei,
 	    gl::UNSIGNED_INT,
 	    std::ptr::null());
 
+	gl::Uniform1i(vertex_colors_location, 0);
+
 	gl::Uniform4fv(color_location,
 		       1, &black_color[0]);
 	gl::LineWidth(2.0);
 
-	gl::BindVertexArray(generalized_cylinder.line_object.vao);
-	// println!("Number of lines to draw: {}", generalized_cylinder.line_object.indices.len());
+	gl::BindVertexArray(line_object.vao);
+	// println!("Number of lines to draw: {}", line_object.indices.len());
 	gl::DrawElements(
 	    gl::LINES,
-	    generalized_cylinder.line_object.indices.len() as gl::types::GLsizei,
+	    line_object.indices.len() as gl::types::GLsizei,
 	    gl::UNSIGNED_INT,
 	    std::ptr::null()); 
 
 
-	gl::Disable(gl::DEPTH_TEST);
 	gl::EnableVertexAttribArray(1);
     }
+}
+
+pub fn draw_lit_body(object : &Object,
+		     lit_program : &shaders::ShaderProgram,
+		     transform : &glm::Mat4,
+		     shading : ShadingMode,
+		     view_dir : glm::Vec3) {
+    let body_color = glm::vec4(0.85, 0.85, 0.85, 1.0);
+    let light_dir = glm::vec3(LIGHT_DIRECTION[0], LIGHT_DIRECTION[1], LIGHT_DIRECTION[2]);
+
+    lit_program.activate();
+
+    let get_location = |name: &str| unsafe {
+	gl::GetUniformLocation(lit_program.id, CString::new(name).unwrap().as_ptr())
+    };
+
+    unsafe {
+	gl::UniformMatrix4fv(get_location("trans"),
+			     1, gl::FALSE, &transform[0][0]);
+	gl::Uniform4fv(get_location("uni_color"),
+		       1, &body_color[0]);
+	gl::Uniform3fv(get_location("light_dir"),
+		       1, &light_dir[0]);
+	gl::Uniform3fv(get_location("view_dir"),
+		       1, &view_dir[0]);
+	gl::Uniform1i(get_location("blinn"),
+		      if shading == ShadingMode::BlinnPhong { 1 } else { 0 });
+
+	gl::BindVertexArray(object.vao);
+	gl::DrawElements(
+	    gl::TRIANGLES,
+	    object.indices.len() as gl::types::GLsizei,
+	    gl::UNSIGNED_INT,
+	    std::ptr::null());
+    }
+}
+
+pub fn draw_cylinder(generalized_cylinder : &GeneralizedCylinder,
+		     body_program : &shaders::ShaderProgram,
+		     lit_program : &shaders::ShaderProgram,
+		     line_program : &shaders::ShaderProgram,
+		     transform : &glm::Mat4,
+		     shading : ShadingMode,
+		     view_dir : glm::Vec3) {
+    if shading == ShadingMode::Toon {
+	draw_toon_body(&generalized_cylinder.object, &generalized_cylinder.line_object,
+		       body_program, transform);
+    } else {
+	draw_lit_body(&generalized_cylinder.object, lit_program, transform, shading, view_dir);
+    }
+
+    draw_spline_overlay(generalized_cylinder, line_program, transform);
+}
+
+// Spline and control points are drawn on top
+pub fn draw_spline_overlay(generalized_cylinder : &GeneralizedCylinder,
+			   line_program : &shaders::ShaderProgram,
+			   transform : &glm::Mat4) {
+    unsafe {
+	gl::Disable(gl::DEPTH_TEST);
+    }
 
     line_program.activate();
 
@@ -131,67 +439,154 @@ pub fn draw_cylinder(generalized_cylinder : &GeneralizedCylinder,
 
 pub fn get_cylinder_values(radius : f32,
                            circ_resolution: usize,
+			   caps: [Cap; 2],
+			   tessellation: &tessellation::TessellationSettings,
+			   bend_handling: BendHandling,
                            spline_state : &splinedraw::SplineState,
 			   annotations: Option<&Vec<Box<dyn annotation::Annotation>>>)
-                           -> (Vec<f32>, Vec<u32>) {
+                           -> CylinderMesh {
 
     let mut avv : Vec<(usize, f32)> = Vec::new();
+    let mut paints : Vec<(usize, Paint)> = Vec::new();
+    let mut caps = caps;
     
     match &annotations {
 	None => {
 	    avv.push((0, 1.0));
-	    avv.push((spline_state.spline_points.len() + 1, 1.0));
+	    avv.push((spline_state.spline_points.len() - 1, 1.0));
 	},
 	Some(vec) => {
 	    for i in *vec {
+		if let Some(paint) = i.as_ref().get_paint() {
+		    paints.push((spline_state.control_point_to_spline_index(i.as_ref().get_render_index()),
+				 paint));
+		}
+
 		if i.as_ref().alters_size() {
-		    avv.push((i.as_ref().get_render_index() * splinedraw::SPLINE_RESOLUTION,
+		    avv.push((spline_state.control_point_to_spline_index(i.as_ref().get_render_index()),
 			      i.as_ref().get_size()));
 		}
+
+		if let Some(cap) = i.as_ref().get_cap() {
+		    let index = i.as_ref().get_render_index();
+		    if index == 0 {
+			caps[START_CAP] = cap;
+		    } else if index + 1 == spline_state.control_points.len() {
+			caps[END_CAP] = cap;
+		    }
+		}
 	    }
 
 	    avv.sort_by(|a, b| a.partial_cmp(b).unwrap()); // sort();
+	    paints.sort_by_key(|p| p.0);
 	}
     };
 
-    let len_resolution = spline_state.spline_points.len() - 1;
+    let closed = spline_state.closed;
+
+    let sample_scales = get_ring_scales(&avv, spline_state.spline_points.len(), closed);
+
+    // Size of the finest displacement detail, which the adaptive mesh samples twice
+    let feature_size = annotations
+	.map(|anns| annotation::get_noises(anns))
+	.unwrap_or(Vec::new())
+	.iter()
+	.fold(f32::INFINITY, |m, n| m.min(n.get_feature_size()));
+
+    // Adaptive tessellation places its own rings along the spline. All rings have
+    // the same number of vertices, chosen for the widest one
+    let (mut rings, mut circ_resolution) = if tessellation.adaptive {
+	let tolerance = tessellation.get_tolerance();
+	let rings = tessellation::get_rings(&spline_state.spline_points, &sample_scales,
+					    radius, closed, tolerance);
+	let max_scale = rings.iter().fold(0.0f32, |m, r| m.max(r.scale));
+	(rings, tessellation::get_circ_resolution(radius * max_scale, tolerance,
+						  MIN_CIRC_RESOLUTION, MAX_CIRC_RESOLUTION))
+    } else {
+	(spline_state.spline_points.iter().zip(sample_scales.iter())
+	 .map(|(p, s)| tessellation::Ring { center: *p, scale: *s })
+	 .collect::<Vec<tessellation::Ring>>(),
+	 circ_resolution)
+    };
+
+    // Noise needs rings and vertices closer than its finest detail, however the
+    // rings were placed
+    if feature_size.is_finite() {
+	let spacing = feature_size / 2.0;
+	rings = tessellation::subdivide_rings(&rings, spacing, closed);
+
+	// 2 * circ_resolution vertices around the widest ring
+	let max_scale = rings.iter().fold(0.0f32, |m, r| m.max(r.scale));
+	let detail_resolution = (f32::consts::PI * radius * max_scale / spacing).ceil() as usize;
+	circ_resolution = circ_resolution.max(detail_resolution.min(tessellation::MAX_DETAIL_CIRC_RESOLUTION));
+    }
+
+    let centers : Vec<glm::Vec3> = rings.iter().map(|r| r.center).collect();
+    let mut scales : Vec<f32> = rings.iter().map(|r| r.scale).collect();
+    
+    // Closed splines wrap around, so their last ring connects back to the first one
+    // and there are no end caps
+    let num_rings = centers.len();
+    let num_panes = if closed { num_rings } else { num_rings - 1 };
+    let capped : Vec<usize> = if closed {
+	Vec::new()
+    } else {
+	(0..2).filter(|k| caps[*k].style != CapStyle::Open).collect()
+    };
+    let num_caps = capped.len();
+    
+    let len_resolution = num_rings - 1;
     let icirc_resolution = circ_resolution as u32;
 
+    let tangents = get_ring_tangents(&centers, closed);
+
+    // Rings wider than the radius of curvature fold over their neighbors
+    let fold_radii = get_fold_radii(&centers, &tangents, closed);
+    let folded_rings = (0..num_rings).filter(|i| radius * scales[*i] > fold_radii[*i]).count();
+
+    if bend_handling == BendHandling::ClampRadius {
+	for i in 0..num_rings {
+	    scales[i] = scales[i].min(FOLD_MARGIN * fold_radii[i] / radius);
+	}
+    }
+
+
     
-    // The hemisphere at each end: 2 * resolution panes around its circumference,
+    // Every cap style shares the layout of the hemisphere at each end: 2 * resolution panes around its circumference,
     // resolution - 1 of those in height, and then 2 * resolution triangles to close it on top.
     let num_end_triangles =
 	(2 * circ_resolution * (circ_resolution - 1)) * 2 + 2 * circ_resolution;
 
     
-    // len_resolution panes in length, 2 * circ_resolution panes in circumference. Two triangles per pane
-    let num_base_triangles = len_resolution * 2 * circ_resolution * 2;
+    // num_panes panes in length, 2 * circ_resolution panes in circumference. Two triangles per pane
+    let num_base_triangles = num_panes * 2 * circ_resolution * 2;
     
-    let num_total_triangles = 2 * num_end_triangles + num_base_triangles;
+    let num_total_triangles = num_caps * num_end_triangles + num_base_triangles;
 
     let mut indices = vec![0; 3 * num_total_triangles];
 
     
     // Create base
-    for i in 0..len_resolution {
+    for i in 0..num_panes {
 	let ii = i as u32;
+	let ni = ((i + 1) % num_rings) as u32;
 	for j in 0..(circ_resolution * 2) {
 	    let ij = j as u32;
 	    indices[6  * (i * 2 * circ_resolution + j) + 0] = ii       * 2 * icirc_resolution + ij;
-	    indices[6  * (i * 2 * circ_resolution + j) + 1] = (ii + 1) * 2 * icirc_resolution + ij;
-	    indices[6  * (i * 2 * circ_resolution + j) + 2] = (ii + 1) * 2 * icirc_resolution + ((ij + 1) % (2 * icirc_resolution));
+	    indices[6  * (i * 2 * circ_resolution + j) + 1] = ni       * 2 * icirc_resolution + ij;
+	    indices[6  * (i * 2 * circ_resolution + j) + 2] = ni       * 2 * icirc_resolution + ((ij + 1) % (2 * icirc_resolution));
 
 	    indices[6  * (i * 2 * circ_resolution + j) + 3] = ii       * 2 * icirc_resolution + ij;
-	    indices[6  * (i * 2 * circ_resolution + j) + 4] = (ii + 1) * 2 * icirc_resolution + ((ij + 1) % (2 * icirc_resolution));
+	    indices[6  * (i * 2 * circ_resolution + j) + 4] = ni       * 2 * icirc_resolution + ((ij + 1) % (2 * icirc_resolution));
 	    indices[6  * (i * 2 * circ_resolution + j) + 5] = ii       * 2 * icirc_resolution + ((ij + 1) % (2 * icirc_resolution));
 	}
     }
 
-    // Create hemispheres
-    for k in 0..2 {
+    // Create caps. k is the end, slot the position of the cap in the buffers
+    for (slot, &k) in capped.iter().enumerate() {
 	
-	let ind_base = 3 * num_base_triangles + k * 3 * num_end_triangles;
-	let vert_base = (2 * circ_resolution * (len_resolution + 1) + k * (2 * circ_resolution * (circ_resolution - 1) + 1)) as u32;
+	let ind_base = 3 * num_base_triangles + slot * 3 * num_end_triangles;
+	let vert_base = (2 * circ_resolution * (len_resolution + 1) + slot * (2 * circ_resolution * (circ_resolution - 1) + 1)) as u32;
 	let dir = (if k == 0 {-1} else {1}) as i32;
 	let start_j = (if k == 0 {circ_resolution * 2} else {0}) as i32;
 	
@@ -242,84 +637,117 @@ pub fn get_cylinder_values(radius : f32,
     
     // Create vertices
 
-    let num_base_vertices = circ_resolution * 2 * (len_resolution + 1);
+    let num_base_vertices = circ_resolution * 2 * num_rings;
     let num_end_vertices = circ_resolution * 2 * (circ_resolution - 1) + 1;
 
-    let num_total_vertices = 2 * num_end_vertices + num_base_vertices;
+    let num_total_vertices = num_caps * num_end_vertices + num_base_vertices;
 
     let mut vertices : Vec<f32> = vec![0.0; num_total_vertices * 3];
+    let mut normals : Vec<f32> = vec![0.0; num_total_vertices * 3];
+
+    // u is the arc length over the caps and along the spline, normalized over the whole cylinder.
+    // v is the angle around the spline over 2 pi, with the seam at the top (positive z)
+    let mut uvs : Vec<f32> = vec![0.0; num_total_vertices * 2];
+    let (ring_distances, spline_length) = get_ring_distances(&centers, closed);
+    let mut cap_lengths = [0.0, 0.0];
+    for &k in &capped {
+	let scale = if k == 0 { scales[0] } else { scales[num_rings - 1] };
+	cap_lengths[k] = caps[k].get_arc_length(1.0) * radius * scale;
+    }
+    let total_length = (cap_lengths[0] + spline_length + cap_lengths[1]).max(1e-9);
 
     // Create base
     let base_length = 1.0; // length - 2.0 * radius;
 
-    let mut curr_ann = 0;
-
-
-    println!("Beg");
-    for i in 0..(len_resolution + 1) {
-	while i > avv[curr_ann].0 {
-	    curr_ann += 1;
-	}
-
-	// Re-assign radius with scale
-	let radius = radius * if i == avv[curr_ann].0 {
-	    avv[curr_ann].1
+    for i in 0..num_rings {
+	// How quickly the radius changes along the spline tilts the normals
+	let (prev, next) = if closed {
+	    ((i + num_rings - 1) % num_rings, (i + 1) % num_rings)
+	} else {
+	    (i.max(1) - 1, (i + 1).min(num_rings - 1))
+	};
+	let distance = glm::builtin::length(glm::vec3(centers[next].x - centers[prev].x,
+						      centers[next].y - centers[prev].y, 0.0));
+	let slope = if distance > 1e-9 {
+	    radius * (scales[next] - scales[prev]) / distance
 	} else {
-	    (avv[curr_ann - 1].1 * (avv[curr_ann].0 - i) as f32 +
-	     avv[curr_ann].1 * (i - avv[curr_ann - 1].0) as f32) /
-	    (avv[curr_ann].0 - avv[curr_ann - 1].0) as f32 
-            // avv[curr_ann].1
-            // 1.5
+	    0.0
 	};
 
-        println!("Size: {}", radius);
-	
-	let ai = if i == len_resolution { i - 1 } else { i };
-	let z_dir = glm::builtin::normalize(glm::vec3(spline_state.spline_points[ai + 1].x,
-						      spline_state.spline_points[ai + 1].y,
-						      0.0) -
-					    glm::vec3(spline_state.spline_points[ai].x,
-						      spline_state.spline_points[ai].y,
-						      0.0));
+	// Re-assign radius with scale
+	let radius = radius * scales[i];
+
+	let z_dir = tangents[i];
 	let y_dir = glm::vec3(-z_dir.y, z_dir.x, 0.0);
 	let x_dir = glm::vec3(0.0, 0.0, 1.0);
 	
 	for j in 0..(circ_resolution * 2) {
 	    let ij = j as u32;
 	    let theta = ij as f32 * f32::consts::PI * 2.0 / (circ_resolution * 2) as f32;
-	    
-	    let vertex = glm::vec3(spline_state.spline_points[i].x,
-				   spline_state.spline_points[i].y,
-				   0.0) * base_length +
-		y_dir * theta.sin() * radius +
-		x_dir * theta.cos() * radius;
+
+	    let center = glm::vec3(centers[i].x, centers[i].y, 0.0) * base_length;
+	    let offset = y_dir * theta.sin() + x_dir * theta.cos();
+
+	    // Pull vertices on the inner side of tight bends back in front of the neighboring rings
+	    let radius = if bend_handling == BendHandling::FixInnerSide {
+		let mut limited = radius;
+		for &n in &[i as i64 - 1, i as i64 + 1] {
+		    if closed || (n >= 0 && n < num_rings as i64) {
+			let n = n.rem_euclid(num_rings as i64) as usize;
+			let neighbor = glm::vec3(centers[n].x, centers[n].y, 0.0) * base_length;
+			limited = limited.min(get_plane_distance(center, offset, neighbor, tangents[n]) * FOLD_MARGIN);
+		    }
+		}
+		limited
+	    } else {
+		radius
+	    };
+
+	    let vertex = center + offset * radius;
+	    let normal = glm::builtin::normalize(offset - z_dir * slope);
 
 	    vertices[3 * (i * circ_resolution * 2 + j) + 0] = vertex.x;
 	    vertices[3 * (i * circ_resolution * 2 + j) + 1] = vertex.y;
 	    vertices[3 * (i * circ_resolution * 2 + j) + 2] = vertex.z;
+
+	    normals[3 * (i * circ_resolution * 2 + j) + 0] = normal.x;
+	    normals[3 * (i * circ_resolution * 2 + j) + 1] = normal.y;
+	    normals[3 * (i * circ_resolution * 2 + j) + 2] = normal.z;
+
+	    uvs[2 * (i * circ_resolution * 2 + j) + 0] = (cap_lengths[0] + ring_distances[i]) / total_length;
+	    uvs[2 * (i * circ_resolution * 2 + j) + 1] = j as f32 / (circ_resolution * 2) as f32;
 	}
     }
 
 
-    // Create hemispheres
-    for k in 0..2 {
+    // Create caps
+    for (slot, &k) in capped.iter().enumerate() {
+	let cap = caps[k];
 	let factor = if k == 0 {-1.0} else {1.0};
 
-	let scale = if k == 0 { avv[0].1 } else { avv[avv.len() - 1].1 };
+	let scale = if k == 0 { scales[0] } else { scales[num_rings - 1] };
 	
 	// Redefine radius here
 	let radius = radius * scale;
 
-	let vert_base = 3 * (num_base_vertices + k * num_end_vertices);
+	let vert_base = 3 * (num_base_vertices + slot * num_end_vertices);
+	let uv_base = 2 * (num_base_vertices + slot * num_end_vertices);
+
+	// The caps continue u outwards from the ends of the spline
+	let get_u = |arc_length: f32| if k == 0 {
+	    (cap_lengths[0] - arc_length * radius) / total_length
+	} else {
+	    (cap_lengths[0] + spline_length + arc_length * radius) / total_length
+	};
 
-	let centerxy = if k == 0 { spline_state.spline_points[0] }
-	else { spline_state.spline_points[spline_state.spline_points.len() - 1] } ;
+	let centerxy = if k == 0 { centers[0] }
+	else { centers[num_rings - 1] } ;
 	let center = glm::vec3(centerxy.x, centerxy.y, 0.0) * base_length;
 
-	let z_dirxy = if k == 0 { spline_state.spline_points[0] -
-				  spline_state.spline_points[1] }
-	else {spline_state.spline_points[len_resolution] -
-	      spline_state.spline_points[len_resolution - 1] };
+	let z_dirxy = if k == 0 { centers[0] -
+				  centers[1] }
+	else {centers[len_resolution] -
+	      centers[len_resolution - 1] };
 
 
 	let z_dir = glm::builtin::normalize(glm::vec3(z_dirxy.x, z_dirxy.y, 0.0));
@@ -327,31 +755,283 @@ pub fn get_cylinder_values(radius : f32,
 	let x_dir = glm::vec3(0.0, 0.0, 1.0);
 	
 	for i in 0..(circ_resolution - 1) {
-	    let phi = (i + 1) as f32 * f32::consts::PI / 2.0 / circ_resolution as f32;
-	    let cp = phi.cos();
-	    let sp = phi.sin();
+	    let t = (i + 1) as f32 / circ_resolution as f32;
+	    let (sp, cp) = cap.get_profile(t);
+	    let (nz, nr) = cap.get_normal(t);
 	    for j in 0..(circ_resolution * 2) {
 		let theta = j as f32 * f32::consts::PI * 2.0 / (circ_resolution * 2) as f32;
+		let offset = y_dir * theta.sin() + x_dir * theta.cos();
 
-		let vertex = center + z_dir * sp * radius +
-		    y_dir * radius * cp * theta.sin() +
-		    x_dir * radius * cp * theta.cos();
+		let vertex = center + z_dir * sp * radius + offset * radius * cp;
+		let normal = z_dir * nz + offset * nr;
 		
 		vertices[vert_base + 3 * (i * circ_resolution * 2 + j) + 0] = vertex.x;
 		vertices[vert_base + 3 * (i * circ_resolution * 2 + j) + 1] = vertex.y;
 		vertices[vert_base + 3 * (i * circ_resolution * 2 + j) + 2] = vertex.z;
-		
+
+		normals[vert_base + 3 * (i * circ_resolution * 2 + j) + 0] = normal.x;
+		normals[vert_base + 3 * (i * circ_resolution * 2 + j) + 1] = normal.y;
+		normals[vert_base + 3 * (i * circ_resolution * 2 + j) + 2] = normal.z;
+
+		uvs[uv_base + 2 * (i * circ_resolution * 2 + j) + 0] = get_u(cap.get_arc_length(t));
+		uvs[uv_base + 2 * (i * circ_resolution * 2 + j) + 1] = j as f32 / (circ_resolution * 2) as f32;
 	    }
 	}
 
-	let vertex = center + z_dir * radius;
+	let (tip, _) = cap.get_profile(1.0);
+	let vertex = center + z_dir * tip * radius;
 	
 	vertices[vert_base + 3 * (num_end_vertices - 1) + 0] = vertex.x; 
 	vertices[vert_base + 3 * (num_end_vertices - 1) + 1] = vertex.y;
 	vertices[vert_base + 3 * (num_end_vertices - 1) + 2] = vertex.z;
+
+	normals[vert_base + 3 * (num_end_vertices - 1) + 0] = z_dir.x;
+	normals[vert_base + 3 * (num_end_vertices - 1) + 1] = z_dir.y;
+	normals[vert_base + 3 * (num_end_vertices - 1) + 2] = z_dir.z;
+
+	uvs[uv_base + 2 * (num_end_vertices - 1) + 0] = get_u(cap.get_arc_length(1.0));
+	uvs[uv_base + 2 * (num_end_vertices - 1) + 1] = 0.5;
+    }
+
+    // Colors follow the spline, with the caps in the colors of its ends
+    let point_distances = get_point_distances(&spline_state.spline_points, closed);
+    let paints : Vec<(f32, Paint)> = paints.iter()
+	.map(|(index, paint)| (point_distances[*index] * spline_length, *paint))
+	.collect();
+
+    let mut colors : Vec<f32> = vec![1.0; num_total_vertices * 3];
+    for i in 0..num_total_vertices {
+	let (u, v) = (uvs[2 * i], uvs[2 * i + 1]);
+	let distance = (u * total_length - cap_lengths[0]).max(0.0).min(spline_length);
+	let p = get_noise_position(u * total_length, v, total_length, radius, closed);
+
+	let color = get_color(&paints, distance, spline_length, closed, p);
+	colors[3 * i..3 * i + 3].copy_from_slice(&color);
+    }
+
+    let rings = (0..num_rings)
+	.map(|i| tessellation::Ring { center: glm::vec3(centers[i].x, centers[i].y, 0.0), scale: scales[i] })
+	.collect();
+
+    CylinderMesh { vertices, normals, colors, uvs, length: total_length, indices, folded_rings, rings }
+}
+
+// Where noise is sampled for a point at a distance along the tube and at v around it. The
+// tube is unrolled along the x-axis, or bent into a ring for closed splines so that the
+// noise meets itself at the seam
+fn get_noise_position(distance: f32, v: f32, length: f32, radius: f32, closed: bool) -> glm::Vec3 {
+    let theta = v * 2.0 * f32::consts::PI;
+
+    if closed {
+	let ring_radius = length / (2.0 * f32::consts::PI);
+	let phi = distance / ring_radius.max(1e-9);
+	let r = ring_radius + radius * theta.cos();
+	glm::vec3(r * phi.cos(), r * phi.sin(), radius * theta.sin())
+    } else {
+	glm::vec3(distance, radius * theta.cos(), radius * theta.sin())
+    }
+}
+
+// Moves the vertices along their normals by the sum of the noise layers. The noise is
+// sampled on an unrolled, straight tube so that it stays on the surface when the spline
+// is edited. Vertices are placed by their uvs, so extra rings and vertices added for
+// fine noise sample it at their own positions
+fn displace(mesh: &CylinderMesh,
+	    noises: &Vec<noise::NoiseSettings>,
+	    radius: f32,
+	    closed: bool) -> Vec<f32> {
+    let mut vertices = mesh.vertices.clone();
+
+    for i in 0..(vertices.len() / 3) {
+	let (u, v) = (mesh.uvs[2 * i], mesh.uvs[2 * i + 1]);
+	let p = get_noise_position(u * mesh.length, v, mesh.length, radius, closed);
+
+	let offset : f32 = noises.iter().map(|n| n.get_displacement(p)).sum();
+
+	for k in 0..3 {
+	    vertices[3 * i + k] += mesh.normals[3 * i + k] * offset;
+	}
+    }
+
+    vertices
+}
+
+// Each spline point's share of the spline length, from 0 at the first point to 1 at the
+// last one, or at the way back to the first one for closed splines
+fn get_point_distances(points: &Vec<glm::Vec3>, closed: bool) -> Vec<f32> {
+    let (distances, length) = get_ring_distances(points, closed);
+    distances.iter().map(|d| d / length.max(1e-9)).collect()
+}
+
+// Color at a distance along the spline, interpolated between the paints around it. Paints
+// are given with their distances along the spline, in order. White without paints
+fn get_color(paints: &Vec<(f32, Paint)>,
+	     distance: f32,
+	     spline_length: f32,
+	     closed: bool,
+	     p: glm::Vec3) -> [f32; 3] {
+    if paints.len() == 0 {
+	return [1.0, 1.0, 1.0];
+    }
+
+    let next = paints.iter().position(|(d, _)| *d > distance);
+
+    // Before the first and after the last paint, the colors wrap around on closed
+    // splines and are held on open ones
+    let (a, b, t) = match next {
+	Some(0) | None if closed => {
+	    let (da, a) = paints[paints.len() - 1];
+	    let (db, b) = paints[0];
+	    let span = (db - da).rem_euclid(spline_length);
+	    let offset = (distance - da).rem_euclid(spline_length);
+	    (a, b, if span > 1e-9 { offset / span } else { 0.0 })
+	},
+	Some(0) => (paints[0].1, paints[0].1, 0.0),
+	None => (paints[paints.len() - 1].1, paints[paints.len() - 1].1, 0.0),
+	Some(k) => {
+	    let (da, a) = paints[k - 1];
+	    let (db, b) = paints[k];
+	    (a, b, if db - da > 1e-9 { (distance - da) / (db - da) } else { 0.0 })
+	},
+    };
+
+    let t = t.max(0.0).min(1.0);
+    let variation = a.variation.get_displacement(p) * (1.0 - t) + b.variation.get_displacement(p) * t;
+
+    let mut color = [0.0; 3];
+    for k in 0..3 {
+	color[k] = (a.color[k] * (1.0 - t) + b.color[k] * t + variation).max(0.0).min(1.0);
+    }
+
+    color
+}
+
+// Distance along the spline from the first ring to each ring, and the length of the
+// whole spline. Closed splines include the way back to the first ring
+fn get_ring_distances(centers: &Vec<glm::Vec3>, closed: bool) -> (Vec<f32>, f32) {
+    let n = centers.len();
+    let distance = |a: glm::Vec3, b: glm::Vec3|
+	glm::builtin::length(glm::vec3(b.x - a.x, b.y - a.y, 0.0));
+
+    let mut distances = vec![0.0; n];
+    for i in 1..n {
+	distances[i] = distances[i - 1] + distance(centers[i - 1], centers[i]);
+    }
+
+    let length = if closed && n > 0 {
+	distances[n - 1] + distance(centers[n - 1], centers[0])
+    } else if n > 0 {
+	distances[n - 1]
+    } else {
+	0.0
+    };
+
+    (distances, length)
+}
+
+// Directions of the rings' normals in the xy-plane. Closed splines use central differences,
+// which also handle the seam
+fn get_ring_tangents(centers: &Vec<glm::Vec3>, closed: bool) -> Vec<glm::Vec3> {
+    let n = centers.len();
+
+    (0..n).map(|i| {
+	let (prev_point, next_point) = if closed {
+	    (centers[(i + n - 1) % n], centers[(i + 1) % n])
+	} else {
+	    let ai = if i == n - 1 { i - 1 } else { i };
+	    (centers[ai], centers[ai + 1])
+	};
+
+	glm::builtin::normalize(glm::vec3(next_point.x, next_point.y, 0.0) -
+				glm::vec3(prev_point.x, prev_point.y, 0.0))
+    }).collect()
+}
+
+// The largest radius each ring can have before it crosses one of its neighbors. Two rings
+// at distance l whose normals differ by theta meet at l / (2 tan(theta / 2)) from the spline
+fn get_fold_radii(centers: &Vec<glm::Vec3>,
+		  tangents: &Vec<glm::Vec3>,
+		  closed: bool) -> Vec<f32> {
+    let n = centers.len();
+    let mut radii = vec![f32::INFINITY; n];
+    let num_panes = if closed { n } else { n - 1 };
+
+    for i in 0..num_panes {
+	let j = (i + 1) % n;
+	let theta = glm::builtin::dot(tangents[i], tangents[j]).max(-1.0).min(1.0).acos();
+	if theta < 1e-6 {
+	    continue;
+	}
+
+	let l = glm::builtin::length(glm::vec3(centers[j].x - centers[i].x,
+					       centers[j].y - centers[i].y, 0.0));
+	let fold_radius = l / (2.0 * (theta / 2.0).tan());
+
+	radii[i] = radii[i].min(fold_radius);
+	radii[j] = radii[j].min(fold_radius);
+    }
+
+    radii
+}
+
+// How far from center along offset the plane through point with the given normal is,
+// or infinity if offset does not lead towards it
+fn get_plane_distance(center: glm::Vec3,
+		      offset: glm::Vec3,
+		      point: glm::Vec3,
+		      normal: glm::Vec3) -> f32 {
+    let side = glm::builtin::dot(center - point, normal);
+    let speed = glm::builtin::dot(offset, normal);
+
+    if side * speed < 0.0 {
+	-side / speed
+    } else {
+	f32::INFINITY
+    }
+}
+
+// Interpolates the annotated sizes linearly over all rings. avv holds
+// (ring index, size) pairs, sorted by ring index
+fn get_ring_scales(avv: &Vec<(usize, f32)>,
+		   num_rings: usize,
+		   closed: bool) -> Vec<f32> {
+    let mut scales = vec![1.0; num_rings];
+
+    if avv.len() == 0 {
+	return scales;
+    }
+
+    let first = avv[0];
+    let last = avv[avv.len() - 1];
+    
+    let mut curr_ann = 0;
+    for i in 0..num_rings {
+	while curr_ann < avv.len() && i > avv[curr_ann].0 {
+	    curr_ann += 1;
+	}
+
+	scales[i] = if curr_ann < avv.len() && i == avv[curr_ann].0 {
+	    avv[curr_ann].1
+	} else if curr_ann == 0 || curr_ann == avv.len() {
+	    // Outside the annotated range. Closed splines interpolate across the seam,
+	    // open splines keep the size of the closest annotation
+	    if closed {
+		let span = (first.0 + num_rings - last.0) as f32;
+		let t = ((i + num_rings - last.0) % num_rings) as f32 / span;
+		last.1 * (1.0 - t) + first.1 * t
+	    } else if curr_ann == 0 {
+		first.1
+	    } else {
+		last.1
+	    }
+	} else {
+	    let (i0, s0) = avv[curr_ann - 1];
+	    let (i1, s1) = avv[curr_ann];
+	    (s0 * (i1 - i) as f32 + s1 * (i - i0) as f32) / (i1 - i0) as f32
+	};
     }
 
-    (vertices, indices)
+    scales
 }
 
 pub fn create_cylinder(radius : f32,
@@ -360,15 +1040,29 @@ pub fn create_cylinder(radius : f32,
     
     splinedraw::spline_screen_to_world_transform(&mut spline_state);
     
-    let (vertices, indices) = get_cylinder_values(radius, circ_resolution, &spline_state,
-						  None);
+    let caps = [Cap::new(CapStyle::Hemisphere); 2];
+    let tessellation = tessellation::TessellationSettings::new();
+    let mesh = get_cylinder_values(radius, circ_resolution, caps,
+				   &tessellation, BendHandling::Warn,
+				   &spline_state, None);
+    let folded_rings = mesh.folded_rings;
+    let uvs = mesh.uvs;
+    let rings = mesh.rings;
 
     GeneralizedCylinder {
-	line_object: lineobjects::create_line_object(&vertices, &indices),
-	object: objects::create_object(vertices, indices),
+	line_object: lineobjects::create_line_object(&mesh.vertices, &mesh.indices),
+	object: objects::create_object_with_colors(mesh.vertices, mesh.normals, mesh.colors, mesh.indices),
 	spline: spline_state,
+	name: String::new(),
         radius,
-        circ_resolution}
+        circ_resolution,
+	caps,
+	tessellation,
+	bend_handling: BendHandling::Warn,
+	mode: CombineMode::Add,
+	folded_rings,
+	uvs,
+	rings}
 	
 }
 
@@ -7,6 +7,9 @@ use crate::laplacian;
 use crate::splinedraw;
 use crate::annotation;
 use crate::utils;
+use crate::oversketch;
+use crate::gizmo;
+use crate::skeleton;
 
 pub static SELECTION_SENSITIVITY : f32 = 0.03;
 
@@ -15,12 +18,43 @@ pub enum EditEnum {
     Dragging
 }
 
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SelectionTool {
+    Points, // Click or paint over points
+    Box,
+    Lasso,
+}
+
+// How a new selection is combined with the current one
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SelectionOp {
+    Replace,
+    Add,      // Shift
+    Subtract, // Ctrl
+}
+
+pub fn get_selection_op(key_state: &program::KeyState) -> SelectionOp {
+    if key_state.ctrl {
+	SelectionOp::Subtract
+    } else if key_state.shift {
+	SelectionOp::Add
+    } else {
+	SelectionOp::Replace
+    }
+}
+
 pub struct EditState {
     pub selected_indices: Vec<usize>,
     pub ref_point: glm::Vec2,
     pub laplacian_system: laplacian::LaplacianEditingSystem,
     pub state : EditEnum,
     pub curr_cylinder: usize,
+    pub oversketch_stroke: splinedraw::SplineState,
+    pub solver_warning: Option<String>,
+    pub skeleton: skeleton::SkeletonGraph, // Cylinders edited together with the current one
+    pub selection_path: Vec<glm::Vec2>, // Box corners or lasso points, in normalized window coordinates
+    pub selection_outline: splinedraw::SplineState,
+    pub gizmo: gizmo::Gizmo,
 }
 
 
@@ -30,7 +64,13 @@ impl EditState {
 		    ref_point : glm::vec2(0.0, 0.0),
                     state : EditEnum::Selecting,
 		    laplacian_system : laplacian::LaplacianEditingSystem::empty(),
-		    curr_cylinder: usize::max_value() }
+		    curr_cylinder: usize::max_value(),
+		    oversketch_stroke: splinedraw::SplineState::new(),
+		    solver_warning: None,
+		    skeleton: skeleton::SkeletonGraph::empty(),
+		    selection_path: Vec::new(),
+		    selection_outline: new_selection_outline(),
+		    gizmo: gizmo::Gizmo::new() }
     }
 
     pub fn from_annotation_state(annotation_state : annotation::AnnotationState) -> EditState {
@@ -60,15 +100,177 @@ impl EditState {
 	self.selected_indices.clear();
 	
     }
+
+    pub fn remove_selected_point(&mut self,
+				 session: &mut program::Session,
+				 ind: usize) {
+	let cylinder = &mut session.cylinders[self.curr_cylinder];
+	cylinder.spline.point_colors[ind] = glm::vec4(0.0, 0.0, 0.0, 1.0);
+	self.selected_indices.retain(|i| *i != ind);
+    }
+
+    // Combines the given points with the current selection
+    pub fn apply_selection(&mut self,
+			   session: &mut program::Session,
+			   indices: Vec<usize>,
+			   op: SelectionOp) {
+	if op == SelectionOp::Replace {
+	    self.clear_selected(&mut session.cylinders);
+	}
+
+	for i in indices {
+	    let selected = self.selected_indices.contains(&i);
+	    if op == SelectionOp::Subtract {
+		if selected {
+		    self.remove_selected_point(session, i);
+		}
+	    } else if !selected {
+		self.add_selected_point(session, i);
+	    }
+	}
+
+	// The selection can be dragged right away
+	self.state = if self.selected_indices.len() > 0 {
+	    EditEnum::Dragging
+	} else {
+	    EditEnum::Selecting
+	};
+    }
+
+    // Selects the points along the spline between the two selected points.
+    // On closed splines, the shorter way around is taken
+    pub fn select_between(&mut self,
+			  session: &mut program::Session) {
+	if self.selected_indices.len() != 2 {
+	    return;
+	}
+
+	let spline = &session.cylinders[self.curr_cylinder].spline;
+	let n = spline.control_points.len();
+	let a = self.selected_indices[0].min(self.selected_indices[1]);
+	let b = self.selected_indices[0].max(self.selected_indices[1]);
+
+	let between : Vec<usize> = if spline.closed && n - (b - a) < b - a {
+	    (b..n).chain(0..(a + 1)).collect()
+	} else {
+	    (a..(b + 1)).collect()
+	};
+
+	self.apply_selection(session, between, SelectionOp::Add);
+    }
+
+    // Selects the points of the current cylinder that carry an annotation of the given kind
+    pub fn select_annotated(&mut self,
+			    session: &mut program::Session,
+			    kind: annotation::AnnotationKind,
+			    op: SelectionOp) {
+	let num_points = session.cylinders[self.curr_cylinder].spline.control_points.len();
+	let annotated = annotation::get_annotated_points(&session.annotations[self.curr_cylinder],
+							 num_points, kind);
+	self.apply_selection(session, annotated, op);
+    }
+
+    // Follows the mouse while a box or lasso is drawn
+    fn update_selection_path(&mut self, tool: SelectionTool, point: glm::Vec2) {
+	match tool {
+	    SelectionTool::Box => {
+		self.selection_path.truncate(1);
+		self.selection_path.push(point);
+	    },
+	    _ => {
+		if self.selection_path.last() != Some(&point) {
+		    self.selection_path.push(point);
+		}
+	    }
+	}
+
+	let polygon = self.get_selection_polygon(tool);
+	self.selection_outline.control_points = polygon.iter().map(|p| glm::vec3(p.x, p.y, 0.0)).collect();
+	self.selection_outline.point_colors = vec![glm::vec4(0.0, 0.0, 0.0, 1.0); polygon.len()];
+    }
+
+    fn get_selection_polygon(&self, tool: SelectionTool) -> Vec<glm::Vec2> {
+	match tool {
+	    SelectionTool::Box if self.selection_path.len() >= 2 => {
+		let a = self.selection_path[0];
+		let b = self.selection_path[1];
+		vec![a, glm::vec2(b.x, a.y), b, glm::vec2(a.x, b.y)]
+	    },
+	    _ => self.selection_path.clone(),
+	}
+    }
+
+    // Points of the current cylinder inside the box or lasso
+    fn get_points_in_selection(&self,
+			       tool: SelectionTool,
+			       points: &Vec<glm::Vec3>,
+			       proj: &glm::Mat4) -> Vec<usize> {
+	let polygon = self.get_selection_polygon(tool);
+	(0..points.len())
+	    .filter(|i| utils::point_in_polygon(utils::project_point(points[*i], proj), &polygon))
+	    .collect()
+    }
+}
+
+// The outline is drawn as a closed polygon, i.e. a closed spline of degree 1
+fn new_selection_outline() -> splinedraw::SplineState {
+    let mut outline = splinedraw::SplineState::new();
+    outline.set_degree(1);
+    outline.closed = true;
+    outline
+}
+
+// Solves the Laplacian system for the skeleton points and copies them to the cylinders.
+// If that fails, the points are restored to their last valid positions and the error
+// is kept for the GUI to show
+fn solve_skeleton(edit_state: &mut EditState,
+		  cylinders: &mut Vec<cylinder::GeneralizedCylinder>,
+		  last_valid: Vec<glm::Vec3>) {
+    match edit_state.laplacian_system.solve(&mut edit_state.skeleton.points) {
+	Ok(()) => edit_state.solver_warning = None,
+	Err(error) => {
+	    edit_state.skeleton.points = last_valid;
+	    edit_state.solver_warning = Some(error.to_string());
+	}
+    }
+
+    edit_state.skeleton.write_back(cylinders);
+}
+
+// Weighted constraints for a drag. distances holds how many skeleton edges each node is
+// away from the moving region, 0 inside it. Annotated constraints take precedence,
+// except for the dragged node which is always pinned
+fn get_drag_constraints(annotated: &Vec<Option<laplacian::ConstraintKind>>,
+			dragged: usize,
+			distances: &Vec<usize>,
+			settings: &laplacian::ConstraintSettings) -> Vec<(usize, f32)> {
+    let mut constraints : Vec<(usize, f32)> = Vec::with_capacity(distances.len());
+    for i in 0..distances.len() {
+	let weight = if i == dragged {
+	    settings.pin_weight
+	} else {
+	    match annotated[i] {
+		Some(kind) => settings.weight(kind),
+		None => settings.falloff_weight(distances[i]),
+	    }
+	};
+
+	constraints.push((i, weight));
+    }
+
+    constraints
 }
 
 pub fn handle_edit_no_peeling(proj : &glm::Mat4, input_state: &program::InputState,
 			      edit_state : &mut EditState,
 			      session : &mut program::Session) {
     let cylinder = &mut session.cylinders[edit_state.curr_cylinder];
+    let tool = input_state.gui_state.selection_tool;
+    let op = get_selection_op(&input_state.key_state);
+    
     match edit_state.state {
         EditEnum::Selecting => {
-            if input_state.mouse_state.button1_pressed {
+            if input_state.mouse_state.button1_pressed && tool == SelectionTool::Points {
                 let selected_point_ind = utils::select_point(input_state.mouse_state.pos,
 							     &cylinder.spline.control_points,
 							     proj, SELECTION_SENSITIVITY);
@@ -84,10 +286,21 @@ pub fn handle_edit_no_peeling(proj : &glm::Mat4, input_state: &program::InputSta
                 }
 
                 
-                if !already_chosen && selected_point_ind >= 0 {
+                if already_chosen && op == SelectionOp::Subtract {
+		    edit_state.remove_selected_point(session, selected_point_ind as usize);
+		} else if !already_chosen && selected_point_ind >= 0 && op != SelectionOp::Subtract {
 		    edit_state.add_selected_point(session, selected_point_ind as usize);
                 }
-            }
+            } else if input_state.mouse_state.button1_pressed {
+		edit_state.update_selection_path(tool, utils::normalize_point(input_state.mouse_state.pos));
+	    } else if input_state.mouse_state.button1_was_pressed && edit_state.selection_path.len() > 0 {
+		let inside = edit_state.get_points_in_selection(tool, &cylinder.spline.control_points, proj);
+		edit_state.apply_selection(session, inside, op);
+		
+		edit_state.selection_path.clear();
+		edit_state.selection_outline.control_points.clear();
+		edit_state.selection_outline.point_colors.clear();
+	    }
             
             if !input_state.mouse_state.button1_pressed &&
                 input_state.mouse_state.button1_was_pressed &&
@@ -113,41 +326,61 @@ pub fn handle_edit_no_peeling(proj : &glm::Mat4, input_state: &program::InputSta
 	                }
                     }
                     
-                    if selected_point_ind < 0 || !already_chosen {
+                    if op != SelectionOp::Replace {
+			// Holding shift or ctrl changes the selection instead of dragging it
+                        edit_state.state = EditEnum::Selecting;
+		    } else if selected_point_ind < 0 || !already_chosen {
 			edit_state.clear_selected(&mut session.cylinders);
                         edit_state.state = EditEnum::Selecting;
                     } else {
                         edit_state.ref_point = utils::normalize_point(input_state.mouse_state.pos);
 
-			
 
-			let mut fixed_vec : Vec<usize> = Vec::new();
-			
-			let mut arr : Vec<i32> = (0..cylinder.spline.control_points.len() as i32).collect();
-			for i in &edit_state.selected_indices {
-			    arr[*i] = -1;
-			}
-			for i in arr {
-			    if i != -1 {
-				fixed_vec.push(i as usize);
-			    }
-			}
+			// Cylinders attached to this one are edited along with it
+			edit_state.skeleton = skeleton::build_graph(&session.cylinders, &session.junctions,
+								    edit_state.curr_cylinder);
+			let graph = &edit_state.skeleton;
+			let curr = edit_state.curr_cylinder;
+
+			// The selected points move freely, the rest are held by their distance to them
+			let selected_nodes : Vec<usize> = edit_state.selected_indices.iter()
+			    .filter_map(|i| graph.node(curr, *i))
+			    .collect();
+			let distances = graph.distances(&selected_nodes);
+			let dragged = graph.node(curr, selected_point_ind as usize).unwrap();
 
-			// Fix the position of the currently moving node
-			fixed_vec.push(selected_point_ind as usize);
+			let fixed_vec = get_drag_constraints(&graph.get_constraints(&session.annotations), dragged,
+							     &distances, &input_state.gui_state.constraint_settings);
+			let stiffness = graph.get_stiffness(&session.annotations);
 			
-			edit_state.laplacian_system = laplacian::setup_system(&cylinder.spline.control_points,
-									      fixed_vec);
+			edit_state.laplacian_system = match laplacian::setup_system(&graph.points,
+										    graph.adjacency.clone(),
+										    fixed_vec,
+										    stiffness,
+										    input_state.gui_state.laplacian_mode,
+										    input_state.gui_state.deformation_method,
+										    input_state.gui_state.solver_backend) {
+			    Ok(system) => system,
+			    Err(error) => {
+				// Solving fails until the next successful setup, so the curve stays put
+				edit_state.solver_warning = Some(error.to_string());
+				laplacian::LaplacianEditingSystem::empty()
+			    }
+			};
                     }
                 } else {
                     let new_mpoint = utils::normalize_point(input_state.mouse_state.pos);
+		    let last_valid = edit_state.skeleton.points.clone();
 	            for i in &edit_state.selected_indices {
-		        cylinder.spline.control_points[*i] =
-			    glm::vec3(new_mpoint.x, -new_mpoint.y, 0.0);
+			if let Some(node) = edit_state.skeleton.node(edit_state.curr_cylinder, *i) {
+			    // The view looks down the z-axis, so dragging keeps the depth
+		            edit_state.skeleton.points[node].x = new_mpoint.x;
+		            edit_state.skeleton.points[node].y = -new_mpoint.y;
+			}
 	            }
 
 		    
-		    edit_state.laplacian_system.solve(&mut cylinder.spline.control_points);
+		    solve_skeleton(edit_state, &mut session.cylinders, last_valid);
                 }
                 
             }
@@ -170,7 +403,15 @@ pub fn handle_edit_with_peeling(proj : &glm::Mat4, input_state: &program::InputS
 							     proj, SELECTION_SENSITIVITY);
 		if selected_point_ind >= 0 {
 		    
-		    edit_state.laplacian_system = laplacian::setup_original_points(&cylinder.spline.control_points);
+		    // Cylinders attached to this one are edited along with it
+		    edit_state.skeleton = skeleton::build_graph(&session.cylinders, &session.junctions,
+								edit_state.curr_cylinder);
+		    edit_state.laplacian_system = laplacian::setup_graph_points(&edit_state.skeleton.points,
+										edit_state.skeleton.adjacency.clone(),
+										input_state.gui_state.laplacian_mode,
+										input_state.gui_state.deformation_method,
+										input_state.gui_state.solver_backend);
+		    edit_state.laplacian_system.set_stiffness(edit_state.skeleton.get_stiffness(&session.annotations));
 			
 		    edit_state.ref_point = utils::normalize_point(input_state.mouse_state.pos);
 
@@ -189,36 +430,46 @@ pub fn handle_edit_with_peeling(proj : &glm::Mat4, input_state: &program::InputS
 
 		let area_of_effect = (glm::length(new_point - edit_state.ref_point) / splinedraw::LINE_LIMIT) as i32;
 
-		let mut fixed_points : Vec<usize> = Vec::new();
-
+		let curr = edit_state.curr_cylinder;
 		let len = cylinder.spline.control_points.len();
 		
 		let s1 = edit_state.selected_indices[0];
+		let dragged = edit_state.skeleton.node(curr, s1).unwrap();
+		let last_valid = edit_state.skeleton.points.clone();
 		
-		cylinder.spline.control_points[s1 as usize] =
-		    glm::vec3(new_point.x, -new_point.y, 0.0);
+		edit_state.skeleton.points[dragged].x = new_point.x;
+		edit_state.skeleton.points[dragged].y = -new_point.y;
 
 		edit_state.clear_selected(&mut session.cylinders);
 		
 		// Preserve dragged point as first in selected-point-list
 		edit_state.add_selected_point(session, s1);
-		
+
+		// The area of effect is measured along the skeleton, so it wraps around the seam of
+		// closed splines and reaches into attached cylinders
+		let distances = edit_state.skeleton.distances(&vec![dragged]);
 		for i in 0..len {
-		    if (i as i32 - s1 as i32).abs() > area_of_effect {
-			fixed_points.push(i as usize);
-		    } else if i != s1 {
+		    let node = edit_state.skeleton.node(curr, i).unwrap();
+		    
+		    if distances[node] <= area_of_effect as usize && i != s1 {
 			edit_state.add_selected_point(session, i as usize);
 		    }
 		}
 
-		fixed_points.push(s1 as usize);
-
-		// Must redeclare to release mutable borrow for above section
-		let cylinder = &mut session.cylinders[edit_state.curr_cylinder];
+		let distances : Vec<usize> = distances.iter()
+		    .map(|d| d.saturating_sub(area_of_effect as usize))
+		    .collect();
+		let fixed_points = get_drag_constraints(&edit_state.skeleton.get_constraints(&session.annotations),
+							dragged, &distances,
+							&input_state.gui_state.constraint_settings);
 		
-		edit_state.laplacian_system.setup_fixed_points(fixed_points);
-
-		edit_state.laplacian_system.solve(&mut cylinder.spline.control_points);
+		match edit_state.laplacian_system.setup_fixed_points(fixed_points) {
+		    Ok(()) => solve_skeleton(edit_state, &mut session.cylinders, last_valid),
+		    Err(error) => {
+			edit_state.skeleton.points = last_valid;
+			edit_state.solver_warning = Some(error.to_string());
+		    }
+		}
 	    }
 	}
     }
@@ -228,7 +479,15 @@ pub fn handle_edit_operation(proj : &glm::Mat4, input_state: &program::InputStat
 			     mut edit_state : &mut EditState,
 			     session: &mut program::Session) {
 
-    if input_state.gui_state.using_peeling {
+    if input_state.gui_state.using_oversketch {
+	oversketch::handle_edit_oversketch(&input_state,
+					   &mut edit_state,
+					   session);
+    } else if input_state.gui_state.using_gizmo {
+	gizmo::handle_edit_gizmo(&proj, &input_state,
+				 &mut edit_state,
+				 session);
+    } else if input_state.gui_state.using_peeling {
 	handle_edit_with_peeling(&proj, &input_state,
 				 &mut edit_state,
 				 session);
@@ -239,9 +498,18 @@ pub fn handle_edit_operation(proj : &glm::Mat4, input_state: &program::InputStat
     }
 
     
-    let cylinder = &mut session.cylinders[edit_state.curr_cylinder];
-    cylinder.update_mesh(&session.annotations[edit_state.curr_cylinder]);
+    // Dragging may have moved attached cylinders as well
+    let mut changed = edit_state.skeleton.cylinders.clone();
+    if !changed.contains(&edit_state.curr_cylinder) {
+	changed.push(edit_state.curr_cylinder);
+    }
 
-    cylinder.spline.update_gpu_state();
+    for c in changed {
+	let cylinder = &mut session.cylinders[c];
+
+	// Spline points must be regenerated before the mesh is swept along them
+	cylinder.spline.update_gpu_state();
+	cylinder.update_mesh(&session.annotations[c]);
+    }
 
 }
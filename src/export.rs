@@ -0,0 +1,439 @@
+use crate::program;
+use crate::cylinder;
+use crate::implicit;
+use crate::csg;
+use crate::objects;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write, BufWriter};
+use std::path::Path;
+
+// Writes the cylinders of a session to files other programs can read. The editor's y-axis
+// points down the screen, so the models are turned half a turn around the x-axis to be y-up
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ExportFormat {
+    Obj,
+    Ply,
+    Gltf,
+}
+
+impl ExportFormat {
+    pub fn get_extension(&self) -> &'static str {
+	match self {
+	    ExportFormat::Obj => "obj",
+	    ExportFormat::Ply => "ply",
+	    ExportFormat::Gltf => "gltf",
+	}
+    }
+}
+
+// A triangle mesh as written to file, with flat lists of coordinates
+pub struct ExportMesh {
+    pub name: String,
+    pub positions: Vec<f32>,
+    pub normals: Vec<f32>,
+    pub colors: Vec<f32>,
+    pub uvs: Vec<f32>, // Empty for meshes without texture coordinates
+    pub indices: Vec<u32>,
+}
+
+impl ExportMesh {
+    fn num_vertices(&self) -> usize {
+	self.positions.len() / 3
+    }
+
+    fn get_average_color(&self) -> [f32; 3] {
+	let mut color = [0.0; 3];
+	for c in self.colors.chunks(3) {
+	    for k in 0..3 {
+		color[k] += c[k];
+	    }
+	}
+
+	let n = self.num_vertices().max(1) as f32;
+	[color[0] / n, color[1] / n, color[2] / n]
+    }
+
+    // Copies a vertex, shifting its texture coordinates by whole turns
+    fn push_shifted(&mut self, i: usize, du: f32, dv: f32) -> u32 {
+	for k in 0..3 {
+	    self.positions.push(self.positions[3 * i + k]);
+	    self.normals.push(self.normals[3 * i + k]);
+	    self.colors.push(self.colors[3 * i + k]);
+	}
+	self.uvs.push(self.uvs[2 * i + 0] + du);
+	self.uvs.push(self.uvs[2 * i + 1] + dv);
+
+	(self.num_vertices() - 1) as u32
+    }
+}
+
+// Texture coordinates wrap around the seam of the cylinder, and along closed splines.
+// Triangles that cross a seam get copies of their vertices on the far side of it
+fn split_uv_seams(mesh: &mut ExportMesh, wrap_u: bool) {
+    let mut copies : HashMap<(u32, bool, bool), u32> = HashMap::new();
+
+    for t in 0..(mesh.indices.len() / 3) {
+	let corners = [mesh.indices[3 * t], mesh.indices[3 * t + 1], mesh.indices[3 * t + 2]];
+	let mut shift = [[false; 2]; 3];
+
+	for axis in 0..2 {
+	    if axis == 0 && !wrap_u {
+		continue;
+	    }
+
+	    let coords : Vec<f32> = corners.iter().map(|i| mesh.uvs[2 * *i as usize + axis]).collect();
+	    let min = coords.iter().cloned().fold(f32::INFINITY, f32::min);
+	    let max = coords.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+
+	    if max - min > 0.5 {
+		for c in 0..3 {
+		    shift[c][axis] = coords[c] < 0.5;
+		}
+	    }
+	}
+
+	for c in 0..3 {
+	    let (su, sv) = (shift[c][0], shift[c][1]);
+	    if su || sv {
+		let i = corners[c];
+		let copy = match copies.get(&(i, su, sv)) {
+		    Some(copy) => *copy,
+		    None => {
+			let copy = mesh.push_shifted(i as usize,
+						     if su { 1.0 } else { 0.0 },
+						     if sv { 1.0 } else { 0.0 });
+			copies.insert((i, su, sv), copy);
+			copy
+		    }
+		};
+		mesh.indices[3 * t + c] = copy;
+	    }
+	}
+    }
+}
+
+// Half a turn around the x-axis
+fn to_y_up(coordinates: &Vec<f32>) -> Vec<f32> {
+    coordinates.chunks(3).flat_map(|p| vec![p[0], -p[1], -p[2]]).collect()
+}
+
+// The blended surface has no parametrization, so it is written without texture coordinates
+fn get_blended_export_mesh(blended: &implicit::BlendedSurface) -> ExportMesh {
+    ExportMesh { name: String::from("Blended surface"),
+		 positions: to_y_up(&blended.object.vertices),
+		 normals: to_y_up(&blended.object.normals),
+		 colors: blended.object.colors.clone(),
+		 uvs: Vec::new(),
+		 indices: blended.object.indices.clone() }
+}
+
+// The union has no parametrization either, and smooth normals across its seams
+fn get_union_export_mesh(union: &csg::TriangleMesh) -> ExportMesh {
+    ExportMesh { name: String::from("Union"),
+		 positions: to_y_up(&union.vertices),
+		 normals: to_y_up(&objects::compute_normals(&union.vertices, &union.indices)),
+		 colors: union.colors.clone(),
+		 uvs: Vec::new(),
+		 indices: union.indices.clone() }
+}
+
+pub fn get_export_mesh(cylinder: &cylinder::GeneralizedCylinder, index: usize) -> ExportMesh {
+    let name = if cylinder.get_name().len() > 0 {
+	cylinder.get_name().to_string()
+    } else {
+	format!("Cylinder {}", index)
+    };
+
+    let mut mesh = ExportMesh { name,
+				positions: to_y_up(&cylinder.object.vertices),
+				normals: to_y_up(&cylinder.object.normals),
+				colors: cylinder.object.colors.clone(),
+				uvs: cylinder.get_uvs().clone(),
+				indices: cylinder.object.indices.clone() };
+
+    split_uv_seams(&mut mesh, cylinder.spline.closed);
+
+    mesh
+}
+
+fn get_obj_name(name: &str) -> String {
+    name.split_whitespace().collect::<Vec<&str>>().join("_")
+}
+
+// Each object gets a material in its average color, next to the OBJ file
+fn write_mtl(path: &str, meshes: &Vec<ExportMesh>) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "# Exported from ParaGem")?;
+
+    for mesh in meshes {
+	let color = mesh.get_average_color();
+	writeln!(file, "newmtl {}", get_obj_name(&mesh.name))?;
+	writeln!(file, "Ka 0 0 0")?;
+	writeln!(file, "Kd {} {} {}", color[0], color[1], color[2])?;
+	writeln!(file, "Ks 0 0 0")?;
+	writeln!(file, "d 1")?;
+	writeln!(file, "illum 1")?;
+    }
+
+    file.flush()
+}
+
+// Vertex colors follow the positions, which most programs that read OBJ files understand
+fn write_obj(path: &str, meshes: &Vec<ExportMesh>) -> io::Result<()> {
+    let mtl_path = Path::new(path).with_extension("mtl");
+    write_mtl(&mtl_path.to_string_lossy(), meshes)?;
+
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "# Exported from ParaGem")?;
+    writeln!(file, "mtllib {}", mtl_path.file_name().unwrap_or_default().to_string_lossy())?;
+
+    // Indices in OBJ files start at 1 and count over all objects
+    let mut offset = 1;
+
+    for mesh in meshes {
+	writeln!(file, "o {}", get_obj_name(&mesh.name))?;
+	writeln!(file, "usemtl {}", get_obj_name(&mesh.name))?;
+
+	for (p, c) in mesh.positions.chunks(3).zip(mesh.colors.chunks(3)) {
+	    writeln!(file, "v {} {} {} {} {} {}", p[0], p[1], p[2], c[0], c[1], c[2])?;
+	}
+	for t in mesh.uvs.chunks(2) {
+	    writeln!(file, "vt {} {}", t[0], t[1])?;
+	}
+	for n in mesh.normals.chunks(3) {
+	    writeln!(file, "vn {} {} {}", n[0], n[1], n[2])?;
+	}
+
+	for t in mesh.indices.chunks(3) {
+	    let (a, b, c) = (t[0] as usize + offset, t[1] as usize + offset, t[2] as usize + offset);
+	    if mesh.uvs.len() > 0 {
+		writeln!(file, "f {}/{}/{} {}/{}/{} {}/{}/{}", a, a, a, b, b, b, c, c, c)?;
+	    } else {
+		writeln!(file, "f {}//{} {}//{} {}//{}", a, a, b, b, c, c)?;
+	    }
+	}
+
+	offset += mesh.num_vertices();
+    }
+
+    file.flush()
+}
+
+// ASCII PLY with all cylinders in one mesh, and colors in bytes
+fn write_ply(path: &str, meshes: &Vec<ExportMesh>) -> io::Result<()> {
+    let num_vertices : usize = meshes.iter().map(|m| m.num_vertices()).sum();
+    let num_faces : usize = meshes.iter().map(|m| m.indices.len() / 3).sum();
+
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "ply")?;
+    writeln!(file, "format ascii 1.0")?;
+    writeln!(file, "comment Exported from ParaGem")?;
+    writeln!(file, "element vertex {}", num_vertices)?;
+    for property in &["x", "y", "z", "nx", "ny", "nz", "s", "t"] {
+	writeln!(file, "property float {}", property)?;
+    }
+    for property in &["red", "green", "blue"] {
+	writeln!(file, "property uchar {}", property)?;
+    }
+    writeln!(file, "element face {}", num_faces)?;
+    writeln!(file, "property list uchar uint vertex_indices")?;
+    writeln!(file, "end_header")?;
+
+    let to_byte = |c: f32| (c.max(0.0).min(1.0) * 255.0).round() as u8;
+
+    for mesh in meshes {
+	for i in 0..mesh.num_vertices() {
+	    let (p, n, c) = (&mesh.positions[3 * i..3 * i + 3],
+			     &mesh.normals[3 * i..3 * i + 3],
+			     &mesh.colors[3 * i..3 * i + 3]);
+	    let t = mesh.uvs.get(2 * i..2 * i + 2).unwrap_or(&[0.0, 0.0]);
+	    writeln!(file, "{} {} {} {} {} {} {} {} {} {} {}",
+		     p[0], p[1], p[2], n[0], n[1], n[2], t[0], t[1],
+		     to_byte(c[0]), to_byte(c[1]), to_byte(c[2]))?;
+	}
+    }
+
+    let mut offset = 0;
+    for mesh in meshes {
+	for t in mesh.indices.chunks(3) {
+	    writeln!(file, "3 {} {} {}", t[0] as usize + offset, t[1] as usize + offset, t[2] as usize + offset)?;
+	}
+	offset += mesh.num_vertices();
+    }
+
+    file.flush()
+}
+
+fn base64(bytes: &Vec<u8>) -> String {
+    let alphabet = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity((bytes.len() + 2) / 3 * 4);
+
+    for chunk in bytes.chunks(3) {
+	let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+	let n = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | b[2] as u32;
+
+	for k in 0..4 {
+	    if k <= chunk.len() {
+		encoded.push(alphabet[((n >> (18 - 6 * k)) & 63) as usize] as char);
+	    } else {
+		encoded.push('=');
+	    }
+	}
+    }
+
+    encoded
+}
+
+fn escape_json(s: &str) -> String {
+    s.chars().flat_map(|c| match c {
+	'"' => vec!['\\', '"'],
+	'\\' => vec!['\\', '\\'],
+	c if (c as u32) < 0x20 => vec![' '],
+	c => vec![c],
+    }).collect()
+}
+
+// Appends the values to the buffer and describes them with a buffer view and an accessor
+fn push_accessor(buffer: &mut Vec<u8>,
+		 views: &mut Vec<String>,
+		 accessors: &mut Vec<String>,
+		 bytes: Vec<u8>,
+		 target: u32,
+		 component_type: u32,
+		 count: usize,
+		 kind: &str,
+		 bounds: Option<(Vec<f32>, Vec<f32>)>) -> usize {
+    // Every view starts at a multiple of four bytes
+    while buffer.len() % 4 != 0 {
+	buffer.push(0);
+    }
+
+    views.push(format!("{{\"buffer\": 0, \"byteOffset\": {}, \"byteLength\": {}, \"target\": {}}}",
+		       buffer.len(), bytes.len(), target));
+    buffer.extend(bytes);
+
+    let bounds = match bounds {
+	Some((min, max)) => format!(", \"min\": {:?}, \"max\": {:?}", min, max),
+	None => String::new(),
+    };
+
+    accessors.push(format!("{{\"bufferView\": {}, \"componentType\": {}, \"count\": {}, \"type\": \"{}\"{}}}",
+			   views.len() - 1, component_type, count, kind, bounds));
+    accessors.len() - 1
+}
+
+fn get_bounds(positions: &Vec<f32>) -> (Vec<f32>, Vec<f32>) {
+    let mut min = vec![f32::INFINITY; 3];
+    let mut max = vec![f32::NEG_INFINITY; 3];
+
+    for p in positions.chunks(3) {
+	for k in 0..3 {
+	    min[k] = min[k].min(p[k]);
+	    max[k] = max[k].max(p[k]);
+	}
+    }
+
+    (min, max)
+}
+
+fn float_bytes(values: &Vec<f32>) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect()
+}
+
+// glTF 2.0 with the binary data embedded in the file
+fn write_gltf(path: &str, meshes: &Vec<ExportMesh>) -> io::Result<()> {
+    const FLOAT: u32 = 5126;
+    const UNSIGNED_INT: u32 = 5125;
+    const ARRAY_BUFFER: u32 = 34962;
+    const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+
+    let mut buffer : Vec<u8> = Vec::new();
+    let mut views : Vec<String> = Vec::new();
+    let mut accessors : Vec<String> = Vec::new();
+    let mut gltf_meshes : Vec<String> = Vec::new();
+    let mut nodes : Vec<String> = Vec::new();
+
+    for (i, mesh) in meshes.iter().enumerate() {
+	let count = mesh.num_vertices();
+
+	let position = push_accessor(&mut buffer, &mut views, &mut accessors,
+				     float_bytes(&mesh.positions), ARRAY_BUFFER, FLOAT,
+				     count, "VEC3", Some(get_bounds(&mesh.positions)));
+	let normal = push_accessor(&mut buffer, &mut views, &mut accessors,
+				   float_bytes(&mesh.normals), ARRAY_BUFFER, FLOAT,
+				   count, "VEC3", None);
+	let uv = if mesh.uvs.len() > 0 {
+	    let uv = push_accessor(&mut buffer, &mut views, &mut accessors,
+				   float_bytes(&mesh.uvs), ARRAY_BUFFER, FLOAT,
+				   count, "VEC2", None);
+	    format!(", \"TEXCOORD_0\": {}", uv)
+	} else {
+	    String::new()
+	};
+	let color = push_accessor(&mut buffer, &mut views, &mut accessors,
+				  float_bytes(&mesh.colors), ARRAY_BUFFER, FLOAT,
+				  count, "VEC3", None);
+	let indices = push_accessor(&mut buffer, &mut views, &mut accessors,
+				    mesh.indices.iter().flat_map(|i| i.to_le_bytes().to_vec()).collect(),
+				    ELEMENT_ARRAY_BUFFER, UNSIGNED_INT,
+				    mesh.indices.len(), "SCALAR", None);
+
+	let name = escape_json(&mesh.name);
+	gltf_meshes.push(format!("{{\"name\": \"{}\", \"primitives\": [{{\"attributes\": \
+				  {{\"POSITION\": {}, \"NORMAL\": {}{}, \"COLOR_0\": {}}}, \"indices\": {}}}]}}",
+				 name, position, normal, uv, color, indices));
+	nodes.push(format!("{{\"name\": \"{}\", \"mesh\": {}}}", name, i));
+    }
+
+    let node_indices : Vec<String> = (0..nodes.len()).map(|i| i.to_string()).collect();
+
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "{{")?;
+    writeln!(file, "  \"asset\": {{\"version\": \"2.0\", \"generator\": \"ParaGem\"}},")?;
+    writeln!(file, "  \"scene\": 0,")?;
+    writeln!(file, "  \"scenes\": [{{\"nodes\": [{}]}}],", node_indices.join(", "))?;
+    writeln!(file, "  \"nodes\": [{}],", nodes.join(", "))?;
+    writeln!(file, "  \"meshes\": [{}],", gltf_meshes.join(", "))?;
+    writeln!(file, "  \"accessors\": [{}],", accessors.join(", "))?;
+    writeln!(file, "  \"bufferViews\": [{}],", views.join(", "))?;
+    writeln!(file, "  \"buffers\": [{{\"byteLength\": {}, \"uri\": \"data:application/octet-stream;base64,{}\"}}]",
+	     buffer.len(), base64(&buffer))?;
+    writeln!(file, "}}")?;
+
+    file.flush()
+}
+
+// Writes all cylinders, or the blended surface when it is shown, to path, with the format's extension added.
+// With union, or when some cylinders are subtractive, the cylinders are merged into one
+// closed mesh first, and checked.
+// Returns the file name, and the check of the union
+pub fn export_session(session: &program::Session,
+		      path: &str,
+		      format: ExportFormat,
+		      union: bool) -> io::Result<(String, Option<csg::ValidationReport>)> {
+    let path = format!("{}.{}", path, format.get_extension());
+    let mut report = None;
+
+    let meshes : Vec<ExportMesh> = match &session.blended {
+	Some(blended) => vec![get_blended_export_mesh(blended)],
+	None if union || session.cylinders.iter().any(|c| c.get_mode() == cylinder::CombineMode::Subtract) => {
+	    let union = csg::get_session_union(session);
+	    report = Some(csg::validate(&union));
+	    vec![get_union_export_mesh(&union)]
+	},
+	None => session.cylinders.iter().enumerate()
+	    .map(|(i, c)| get_export_mesh(c, i))
+	    .collect(),
+    };
+
+    match format {
+	ExportFormat::Obj => write_obj(&path, &meshes)?,
+	ExportFormat::Ply => write_ply(&path, &meshes)?,
+	ExportFormat::Gltf => write_gltf(&path, &meshes)?,
+    }
+
+    Ok((path, report))
+}
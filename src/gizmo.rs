@@ -0,0 +1,378 @@
+extern crate gl;
+extern crate glm;
+
+use crate::program;
+use crate::edit;
+use crate::shaders;
+use crate::utils;
+use crate::skeleton;
+
+use glm::builtin::*;
+
+use std::ffi::CString;
+
+// A gizmo for moving, rotating and scaling control points rigidly, without the Laplacian solver.
+// It works on the selected points of the current cylinder, or on the whole cylinder if nothing
+// is selected. Only positions change, so annotations stay attached to their points by index.
+// The points are moved as nodes of the skeleton, so attached ends follow the points they are
+// attached to, and moving a whole cylinder moves the cylinders attached to it as well.
+// Everything stays in the drawing plane, since the cylinders are swept in it.
+
+// Length of the axis handles, in world coordinates
+static GIZMO_SIZE: f32 = 0.2;
+static RING_RADIUS_FACTOR: f32 = 1.3;
+static RING_SEGMENTS: usize = 48;
+// How close (in normalized window coordinates) the mouse must be to a handle to grab it
+static GRAB_DISTANCE: f32 = 0.03;
+
+// Moving with the center handle is restricted to this axis
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Axis {
+    Free,
+    X,
+    Y,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Handle {
+    Center,
+    AxisX,
+    AxisY,
+    Ring,
+    Scale,
+}
+
+pub struct Gizmo {
+    pub axis: Axis,
+    // Numeric entry in the GUI
+    pub translation: [f32; 2],
+    pub angle: f32, // Degrees, counterclockwise
+    pub scale: f32,
+
+    grabbed: Option<Handle>,
+    grab_start: glm::Vec2,          // Mouse position when grabbed, in normalized window coordinates
+    pivot: glm::Vec3,
+    original_points: Vec<glm::Vec3>, // Skeleton nodes when grabbed, so dragging does not accumulate errors
+
+    vao: gl::types::GLuint,
+    positions_vbo: gl::types::GLuint,
+    colors_vbo: gl::types::GLuint,
+    num_vertices: usize,
+}
+
+impl Drop for Gizmo {
+    fn drop(&mut self) {
+	unsafe {
+	    gl::DeleteBuffers(1, &self.positions_vbo);
+	    gl::DeleteBuffers(1, &self.colors_vbo);
+	    gl::DeleteVertexArrays(1, &self.vao);
+	}
+    }
+}
+
+impl Gizmo {
+    pub fn new() -> Gizmo {
+	let mut gizmo = Gizmo { axis: Axis::Free,
+				translation: [0.0, 0.0],
+				angle: 0.0,
+				scale: 1.0,
+				grabbed: None,
+				grab_start: glm::vec2(0.0, 0.0),
+				pivot: glm::vec3(0.0, 0.0, 0.0),
+				original_points: Vec::new(),
+				vao: 0, positions_vbo: 0, colors_vbo: 0,
+				num_vertices: 0 };
+
+	unsafe {
+	    gl::GenBuffers(1, &mut gizmo.positions_vbo);
+	    gl::GenBuffers(1, &mut gizmo.colors_vbo);
+	    gl::GenVertexArrays(1, &mut gizmo.vao);
+
+	    gl::BindVertexArray(gizmo.vao);
+
+	    gl::BindBuffer(gl::ARRAY_BUFFER, gizmo.positions_vbo);
+	    gl::VertexAttribPointer(
+		0, 3, gl::FLOAT,
+		gl::FALSE, std::mem::size_of::<glm::Vec3>() as gl::types::GLint,
+		std::ptr::null());
+
+	    gl::BindBuffer(gl::ARRAY_BUFFER, gizmo.colors_vbo);
+	    gl::VertexAttribPointer(
+		1, 4, gl::FLOAT,
+		gl::FALSE, std::mem::size_of::<glm::Vec4>() as gl::types::GLint,
+		std::ptr::null());
+
+	    gl::EnableVertexAttribArray(0);
+	    gl::EnableVertexAttribArray(1);
+	}
+
+	gizmo
+    }
+
+    fn get_color(&self, handle: Handle, color: glm::Vec4) -> glm::Vec4 {
+	if self.grabbed == Some(handle) {
+	    glm::vec4(1.0, 0.6, 0.0, 1.0)
+	} else {
+	    color
+	}
+    }
+
+    // Line segments of all handles around the pivot
+    pub fn update_gpu_state(&mut self, pivot: glm::Vec3) {
+	let mut positions : Vec<glm::Vec3> = Vec::new();
+	let mut colors : Vec<glm::Vec4> = Vec::new();
+
+	let mut add_line = |a: glm::Vec3, b: glm::Vec3, color: glm::Vec4| {
+	    positions.push(a);
+	    positions.push(b);
+	    colors.push(color);
+	    colors.push(color);
+	};
+
+	let x = glm::vec3(GIZMO_SIZE, 0.0, 0.0);
+	let y = glm::vec3(0.0, GIZMO_SIZE, 0.0);
+
+	// Axis handles with arrow heads
+	let red = self.get_color(Handle::AxisX, glm::vec4(0.9, 0.0, 0.0, 1.0));
+	add_line(pivot, pivot + x, red);
+	add_line(pivot + x, pivot + x * 0.85 + y * 0.08, red);
+	add_line(pivot + x, pivot + x * 0.85 - y * 0.08, red);
+
+	let green = self.get_color(Handle::AxisY, glm::vec4(0.0, 0.7, 0.0, 1.0));
+	add_line(pivot, pivot + y, green);
+	add_line(pivot + y, pivot + y * 0.85 + x * 0.08, green);
+	add_line(pivot + y, pivot + y * 0.85 - x * 0.08, green);
+
+	// Small squares for the center and the scale handle
+	let square = |center: glm::Vec3| {
+	    let (a, b) = (x * 0.08, y * 0.08);
+	    vec![center - a - b, center + a - b, center + a + b, center - a + b]
+	};
+
+	let center_color = self.get_color(Handle::Center, glm::vec4(0.2, 0.2, 0.2, 1.0));
+	let scale_color = self.get_color(Handle::Scale, glm::vec4(0.5, 0.0, 0.5, 1.0));
+	for (corners, color) in vec![(square(pivot), center_color),
+				     (square(pivot + (x + y) * 0.7), scale_color)] {
+	    for i in 0..4 {
+		add_line(corners[i], corners[(i + 1) % 4], color);
+	    }
+	}
+
+	let blue = self.get_color(Handle::Ring, glm::vec4(0.0, 0.3, 0.9, 1.0));
+	let radius = GIZMO_SIZE * RING_RADIUS_FACTOR;
+	for i in 0..RING_SEGMENTS {
+	    let a0 = i as f32 / RING_SEGMENTS as f32 * 2.0 * std::f32::consts::PI;
+	    let a1 = (i + 1) as f32 / RING_SEGMENTS as f32 * 2.0 * std::f32::consts::PI;
+	    add_line(pivot + glm::vec3(a0.cos(), a0.sin(), 0.0) * radius,
+		     pivot + glm::vec3(a1.cos(), a1.sin(), 0.0) * radius,
+		     blue);
+	}
+
+	self.num_vertices = positions.len();
+
+	unsafe {
+	    gl::BindBuffer(gl::ARRAY_BUFFER, self.positions_vbo);
+	    gl::BufferData(
+		gl::ARRAY_BUFFER,
+		(positions.len() * std::mem::size_of::<glm::Vec3>()) as gl::types::GLsizeiptr,
+		positions.as_ptr() as *const gl::types::GLvoid,
+		gl::STREAM_DRAW);
+
+	    gl::BindBuffer(gl::ARRAY_BUFFER, self.colors_vbo);
+	    gl::BufferData(
+		gl::ARRAY_BUFFER,
+		(colors.len() * std::mem::size_of::<glm::Vec4>()) as gl::types::GLsizeiptr,
+		colors.as_ptr() as *const gl::types::GLvoid,
+		gl::STREAM_DRAW);
+	}
+    }
+
+    // The handle under the mouse, if any. The closest one wins
+    fn pick_handle(&self, mouse: glm::Vec2, pivot: glm::Vec3, proj: &glm::Mat4) -> Option<Handle> {
+	let center = utils::project_point(pivot, proj);
+	let x = utils::project_point(pivot + glm::vec3(GIZMO_SIZE, 0.0, 0.0), proj);
+	let y = utils::project_point(pivot + glm::vec3(0.0, GIZMO_SIZE, 0.0), proj);
+	let corner = utils::project_point(pivot + glm::vec3(GIZMO_SIZE, GIZMO_SIZE, 0.0) * 0.7, proj);
+	let radius = length(x - center) * RING_RADIUS_FACTOR;
+
+	let candidates = vec![(Handle::Center, length(mouse - center)),
+			      (Handle::Scale, length(mouse - corner)),
+			      (Handle::AxisX, distance_to_segment(mouse, center, x)),
+			      (Handle::AxisY, distance_to_segment(mouse, center, y)),
+			      (Handle::Ring, (length(mouse - center) - radius).abs())];
+
+	candidates.into_iter()
+	    .filter(|(_, d)| *d < GRAB_DISTANCE)
+	    .fold(None, |best: Option<(Handle, f32)>, (h, d)| match best {
+		Some((_, bd)) if bd <= d => best,
+		_ => Some((h, d)),
+	    })
+	    .map(|(h, _)| h)
+    }
+
+    pub fn draw(&self,
+		line_program: &shaders::ShaderProgram,
+		transform: &glm::Mat4) {
+	line_program.activate();
+
+	let transform_location = unsafe {
+	    gl::GetUniformLocation(line_program.id,
+				   CString::new("trans").unwrap().as_ptr())
+	};
+
+	unsafe {
+	    gl::UniformMatrix4fv(transform_location,
+				 1, gl::FALSE, &transform[0][0]);
+
+	    // Always on top of the cylinders
+	    gl::Disable(gl::DEPTH_TEST);
+
+	    gl::BindVertexArray(self.vao);
+	    gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, 0);
+	    gl::EnableVertexAttribArray(0);
+	    gl::EnableVertexAttribArray(1);
+	    gl::LineWidth(2.0);
+
+	    gl::DrawArrays(gl::LINES, 0, self.num_vertices as i32);
+	    gl::Enable(gl::DEPTH_TEST);
+	}
+    }
+}
+
+fn distance_to_segment(p: glm::Vec2, a: glm::Vec2, b: glm::Vec2) -> f32 {
+    let ab = b - a;
+    let t = (dot(p - a, ab) / dot(ab, ab).max(1e-12)).max(0.0).min(1.0);
+    length(p - (a + ab * t))
+}
+
+// Rotates v counterclockwise around the z-axis
+fn rotate(v: glm::Vec3, angle: f32) -> glm::Vec3 {
+    let (sin, cos) = angle.sin_cos();
+    glm::vec3(v.x * cos - v.y * sin, v.x * sin + v.y * cos, v.z)
+}
+
+// Scales and rotates the point around the pivot, then moves it
+pub fn transform_point(point: glm::Vec3,
+		       pivot: glm::Vec3,
+		       translation: glm::Vec3,
+		       angle: f32,
+		       scale: f32) -> glm::Vec3 {
+    pivot + rotate(point - pivot, angle) * scale + translation
+}
+
+// The control points of the current cylinder the gizmo works on
+fn get_targets(edit_state: &edit::EditState, num_points: usize) -> Vec<usize> {
+    if edit_state.selected_indices.len() > 0 {
+	edit_state.selected_indices.clone()
+    } else {
+	(0..num_points).collect()
+    }
+}
+
+// The skeleton nodes the gizmo moves: those of the selected points, or all of them, which
+// takes the attached cylinders along
+fn get_target_nodes(edit_state: &edit::EditState) -> Vec<usize> {
+    if edit_state.selected_indices.len() > 0 {
+	let mut nodes : Vec<usize> = edit_state.selected_indices.iter()
+	    .filter_map(|i| edit_state.skeleton.node(edit_state.curr_cylinder, *i))
+	    .collect();
+	nodes.sort();
+	nodes.dedup();
+	nodes
+    } else {
+	(0..edit_state.skeleton.points.len()).collect()
+    }
+}
+
+pub fn get_pivot(edit_state: &edit::EditState, points: &Vec<glm::Vec3>) -> glm::Vec3 {
+    let targets = get_targets(edit_state, points.len());
+    let mut sum = glm::vec3(0.0, 0.0, 0.0);
+    for i in &targets {
+	sum = sum + points[*i];
+    }
+
+    sum / targets.len().max(1) as f32
+}
+
+// Applies the numeric transformation from the GUI
+pub fn apply_numeric_transform(edit_state: &mut edit::EditState,
+			       session: &mut program::Session) {
+    let pivot = get_pivot(edit_state, &session.cylinders[edit_state.curr_cylinder].spline.control_points);
+    edit_state.skeleton = skeleton::build_graph(&session.cylinders, &session.junctions, edit_state.curr_cylinder);
+
+    let gizmo = &edit_state.gizmo;
+    let t = gizmo.translation;
+    for node in get_target_nodes(edit_state) {
+	edit_state.skeleton.points[node] = transform_point(edit_state.skeleton.points[node], pivot,
+							   glm::vec3(t[0], t[1], 0.0),
+							   gizmo.angle.to_radians(), gizmo.scale);
+    }
+    edit_state.skeleton.write_back(&mut session.cylinders);
+}
+
+pub fn handle_edit_gizmo(proj: &glm::Mat4,
+			 input_state: &program::InputState,
+			 edit_state: &mut edit::EditState,
+			 session: &mut program::Session) {
+    let mouse = utils::normalize_point(input_state.mouse_state.pos);
+    let pivot = get_pivot(edit_state, &session.cylinders[edit_state.curr_cylinder].spline.control_points);
+
+    if !input_state.mouse_state.button1_pressed {
+	edit_state.gizmo.grabbed = None;
+    } else if !input_state.mouse_state.button1_was_pressed {
+	let gizmo = &mut edit_state.gizmo;
+	gizmo.grabbed = gizmo.pick_handle(mouse, pivot, proj);
+	gizmo.grab_start = mouse;
+	gizmo.pivot = pivot;
+
+	// Cylinders attached to this one are moved with it
+	edit_state.skeleton = skeleton::build_graph(&session.cylinders, &session.junctions, edit_state.curr_cylinder);
+	edit_state.gizmo.original_points = edit_state.skeleton.points.clone();
+    } else if let Some(handle) = edit_state.gizmo.grabbed {
+	let gizmo = &edit_state.gizmo;
+
+	// Normalized window coordinates map to world coordinates with y flipped
+	let start = gizmo.grab_start;
+	let delta = glm::vec2(mouse.x - start.x, -(mouse.y - start.y));
+	let center = utils::project_point(gizmo.pivot, proj);
+
+	let mut translation = glm::vec3(0.0, 0.0, 0.0);
+	let mut angle = 0.0;
+	let mut scale = 1.0;
+
+	match handle {
+	    Handle::Center => {
+		translation = match gizmo.axis {
+		    Axis::Free => glm::vec3(delta.x, delta.y, 0.0),
+		    Axis::X => glm::vec3(delta.x, 0.0, 0.0),
+		    Axis::Y => glm::vec3(0.0, delta.y, 0.0),
+		};
+	    },
+	    Handle::AxisX => translation = glm::vec3(delta.x, 0.0, 0.0),
+	    Handle::AxisY => translation = glm::vec3(0.0, delta.y, 0.0),
+	    Handle::Ring => {
+		let (a, b) = (start - center, mouse - center);
+		// Turning the ring counterclockwise on screen turns the points counterclockwise in the world
+		angle = -(a.x * b.y - a.y * b.x).atan2(dot(a, b));
+	    },
+	    Handle::Scale => {
+		let d0 = length(start - center);
+		if d0 > 1e-6 {
+		    scale = (length(mouse - center) / d0).max(1e-3);
+		}
+	    },
+	}
+
+	let transformed : Vec<(usize, glm::Vec3)> = get_target_nodes(edit_state).into_iter()
+	    .map(|node| (node, transform_point(gizmo.original_points[node], gizmo.pivot, translation, angle, scale)))
+	    .collect();
+	for (node, point) in transformed {
+	    edit_state.skeleton.points[node] = point;
+	}
+	edit_state.skeleton.write_back(&mut session.cylinders);
+    }
+
+    let gizmo = &mut edit_state.gizmo;
+    let pivot = if gizmo.grabbed.is_some() { gizmo.pivot } else { pivot };
+    gizmo.update_gpu_state(pivot);
+}
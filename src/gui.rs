@@ -2,27 +2,68 @@ use crate::GLFWState;
 use crate::program;
 use crate::annotation;
 use crate::edit;
+use crate::splinedraw;
+use crate::stroke;
+use crate::laplacian;
+use crate::solver;
+use crate::gizmo;
+use crate::cylinder;
+use crate::tessellation;
+use crate::export;
+use crate::noise;
+use crate::implicit;
 
 use std::mem;
 
-use imgui_glfw_rs::imgui::{Window,im_str,Condition};
+use imgui_glfw_rs::imgui::{Window,im_str,Condition,ImString};
 
 
 pub struct GUIState {
     pub using_peeling: bool,
+    pub using_oversketch: bool,
+    pub using_gizmo: bool,
+    pub laplacian_mode: laplacian::LaplacianMode,
+    pub deformation_method: laplacian::DeformationMethod,
+    pub solver_backend: solver::SolverBackend,
+    pub selection_tool: edit::SelectionTool,
+    pub annotation_selection: annotation::AnnotationKind,
+    pub constraint_settings: laplacian::ConstraintSettings,
     pub used_mouse: bool,
+    pub stroke_settings: stroke::StrokeSettings,
+    pub tessellation_settings: tessellation::TessellationSettings,
+    pub shading_mode: cylinder::ShadingMode,
+    pub implicit_settings: implicit::ImplicitSettings,
+    pub export_path: String,
+    pub export_union: bool,
+    pub export_status: String,
 }
 
 impl Clone for GUIState {
     fn clone(&self) -> GUIState {
 	GUIState { using_peeling: self.using_peeling,
-		   used_mouse:    self.used_mouse }
+		   using_oversketch: self.using_oversketch,
+		   using_gizmo: self.using_gizmo,
+		   laplacian_mode: self.laplacian_mode,
+		   deformation_method: self.deformation_method,
+		   solver_backend: self.solver_backend,
+		   selection_tool: self.selection_tool,
+		   annotation_selection: self.annotation_selection,
+		   constraint_settings: self.constraint_settings,
+		   used_mouse:    self.used_mouse,
+		   stroke_settings: self.stroke_settings,
+		   tessellation_settings: self.tessellation_settings,
+		   shading_mode: self.shading_mode,
+		   implicit_settings: self.implicit_settings,
+		   export_path: self.export_path.clone(),
+		   export_union: self.export_union,
+		   export_status: self.export_status.clone() }
     }
 }
 
 pub fn run_gui(session: &mut program::Session,
 	       mut program_state: &mut program::ProgramState,
 	       glfw_state: &mut GLFWState,
+	       key_state: &program::KeyState,
 	       gui_state: &mut GUIState) {
     let ui = glfw_state.imgui_glfw_context.frame(&mut glfw_state.window, &mut glfw_state.imgui_context);
     // ui.show_demo_window(&mut true);
@@ -39,13 +80,87 @@ pub fn run_gui(session: &mut program::Session,
 	    let old_prog_num = program_state.to_num();
 	    let mut prog_num = program_state.to_num();
 
-	    match program_state {
-		program::ProgramState::Edit(_) | program::ProgramState::Annotate(_) => {
-		    ui.radio_button(im_str!("Edit"), &mut prog_num, program::PS_EDIT_NUM);
-		    ui.radio_button(im_str!("Annotate"), &mut prog_num, program::PS_ANNOTATE_NUM);
-		    ui.separator();
-		},
-		_ => {}
+	    // Drawing more cylinders is possible at any time, they may be attached to the others
+	    if session.cylinders.len() > 0 {
+		ui.radio_button(im_str!("Draw"), &mut prog_num, program::PS_DRAW_NUM);
+		ui.radio_button(im_str!("Edit"), &mut prog_num, program::PS_EDIT_NUM);
+		ui.radio_button(im_str!("Annotate"), &mut prog_num, program::PS_ANNOTATE_NUM);
+		ui.separator();
+	    }
+
+	    // Mesh quality of all cylinders
+	    let tessellation = &mut gui_state.tessellation_settings;
+	    ui.checkbox(im_str!("Adaptive mesh"), &mut tessellation.adaptive);
+	    if tessellation.adaptive {
+		ui.slider_float(im_str!("Quality"), &mut tessellation.quality, 0.0, 1.0).build();
+		ui.text(format!("Error target: {:.2} pixels", tessellation.get_pixel_error()));
+	    }
+
+	    ui.text(im_str!("Shading"));
+	    ui.radio_button(im_str!("Toon"), &mut gui_state.shading_mode, cylinder::ShadingMode::Toon);
+	    ui.radio_button(im_str!("Lambert"), &mut gui_state.shading_mode, cylinder::ShadingMode::Lambert);
+	    ui.radio_button(im_str!("Blinn-Phong"), &mut gui_state.shading_mode, cylinder::ShadingMode::BlinnPhong);
+	    ui.separator();
+
+	    // One smooth surface around all cylinders
+	    if session.cylinders.len() > 0 {
+		let implicit_settings = &mut gui_state.implicit_settings;
+		ui.checkbox(im_str!("Blended surface"), &mut implicit_settings.enabled);
+		if implicit_settings.enabled {
+		    let mut resolution = implicit_settings.resolution as i32;
+		    ui.slider_int(im_str!("Grid resolution"), &mut resolution,
+				  implicit::MIN_RESOLUTION as i32, implicit::MAX_RESOLUTION as i32).build();
+		    implicit_settings.resolution = resolution as usize;
+		    ui.slider_float(im_str!("Blend"), &mut implicit_settings.blend, 1.1, 4.0).build();
+		}
+		ui.separator();
+	    }
+
+	    // Export of all cylinders, or of the blended surface, with their texture coordinates and colors
+	    if session.cylinders.len() > 0 {
+		let mut path = ImString::with_capacity(256);
+		path.push_str(&gui_state.export_path);
+		if ui.input_text(im_str!("File"), &mut path).build() {
+		    gui_state.export_path = path.to_str().to_string();
+		}
+
+		if session.blended.is_none() {
+		    ui.checkbox(im_str!("Union of cylinders"), &mut gui_state.export_union);
+		}
+
+		let mut format = None;
+		if ui.button(im_str!("Export OBJ"), [200.0, 30.0]) {
+		    format = Some(export::ExportFormat::Obj);
+		}
+		if ui.button(im_str!("Export PLY"), [200.0, 30.0]) {
+		    format = Some(export::ExportFormat::Ply);
+		}
+		if ui.button(im_str!("Export glTF"), [200.0, 30.0]) {
+		    format = Some(export::ExportFormat::Gltf);
+		}
+
+		if let Some(format) = format {
+		    // The blended surface may lag behind the last edits
+		    implicit::update_blended_surface(session, &gui_state.implicit_settings);
+		    gui_state.export_status = match export::export_session(session, &gui_state.export_path, format,
+									   gui_state.export_union) {
+			Ok((file_name, None)) => format!("Wrote {}", file_name),
+			Ok((file_name, Some(report))) => {
+			    let open = session.cylinders.iter().filter(|c| !c.is_closed()).count();
+			    if open > 0 {
+				format!("Wrote {}\n{}\n{} cylinders with open ends left out", file_name, report.get_summary(), open)
+			    } else {
+				format!("Wrote {}\n{}", file_name, report.get_summary())
+			    }
+			},
+			Err(error) => format!("Export failed: {}", error),
+		    };
+		}
+
+		if gui_state.export_status.len() > 0 {
+		    ui.text(&gui_state.export_status);
+		}
+		ui.separator();
 	    }
 
 
@@ -55,7 +170,11 @@ pub fn run_gui(session: &mut program::Session,
 		let mut ps2 = program::ProgramState::Draw;
 		mem::swap(&mut ps2, &mut program_state);
 		
-		if prog_num == program::PS_EDIT_NUM {
+		if prog_num == program::PS_DRAW_NUM {
+		    if let program::ProgramState::Edit(mut edit_state) = ps2 {
+			edit_state.clear_selected(&mut session.cylinders);
+		    }
+		} else if prog_num == program::PS_EDIT_NUM {
 		    match ps2 {
 			program::ProgramState::Annotate(annotation_state) => {
 			    
@@ -67,9 +186,10 @@ pub fn run_gui(session: &mut program::Session,
 			    ); */
 			},
 			_ => {
-			    *program_state = program::ProgramState::Edit(
-				edit::EditState::new()
-			    );
+			    // Continue with the most recently drawn cylinder
+			    let mut edit_state = edit::EditState::new();
+			    edit_state.curr_cylinder = session.cylinders.len() - 1;
+			    *program_state = program::ProgramState::Edit(edit_state);
 			}
 		    }
 		} else if prog_num == program::PS_ANNOTATE_NUM {
@@ -82,17 +202,206 @@ pub fn run_gui(session: &mut program::Session,
 			},
 			
 			_ => {
-			    std::panic!("Tried to convert to annotate state without having an edit state");
+			    *program_state = program::ProgramState::Annotate(
+				annotation::AnnotationState::new(edit::EditState::new(), session)
+			    );
 			}
 		    }
 		}
 	    }
 
 	    match program_state {
-		program::ProgramState::Edit(_) => {
+		program::ProgramState::Edit(ref mut edit_state) => {
+		    if session.cylinders.len() > 1 {
+			let mut curr = edit_state.curr_cylinder as i32;
+			if ui.slider_int(im_str!("Active cylinder"), &mut curr,
+					 0, session.cylinders.len() as i32 - 1).build() {
+			    edit_state.clear_selected(&mut session.cylinders);
+			    edit_state.state = edit::EditEnum::Selecting;
+			    edit_state.curr_cylinder = curr as usize;
+			}
+			ui.separator();
+		    }
 		    ui.text(im_str!("Use peeling"));
 		    ui.radio_button(im_str!("On"), &mut gui_state.using_peeling, true);
 		    ui.radio_button(im_str!("Off"), &mut gui_state.using_peeling, false);
+		    ui.text(im_str!("Deformation"));
+		    ui.radio_button(im_str!("Linearized"), &mut gui_state.deformation_method,
+				    laplacian::DeformationMethod::Linearized);
+		    ui.radio_button(im_str!("As-rigid-as-possible"), &mut gui_state.deformation_method,
+				    laplacian::DeformationMethod::AsRigidAsPossible);
+		    ui.checkbox(im_str!("Oversketch"), &mut gui_state.using_oversketch);
+		    ui.checkbox(im_str!("Transform gizmo"), &mut gui_state.using_gizmo);
+		    ui.text(im_str!("Laplacian editing"));
+		    ui.radio_button(im_str!("2D"), &mut gui_state.laplacian_mode, laplacian::LaplacianMode::Planar);
+		    ui.radio_button(im_str!("3D"), &mut gui_state.laplacian_mode, laplacian::LaplacianMode::Spatial);
+		    ui.text(im_str!("Solver"));
+		    ui.radio_button(im_str!("Cholesky (f32)"), &mut gui_state.solver_backend, solver::SolverBackend::CholeskyF32);
+		    ui.radio_button(im_str!("Cholesky (f64)"), &mut gui_state.solver_backend, solver::SolverBackend::CholeskyF64);
+		    ui.radio_button(im_str!("LSQR"), &mut gui_state.solver_backend, solver::SolverBackend::Lsqr);
+		    let constraints = &mut gui_state.constraint_settings;
+		    ui.drag_float(im_str!("Pin weight"), &mut constraints.pin_weight)
+			.min(0.1).max(100.0).speed(0.1)
+			.build();
+		    ui.drag_float(im_str!("Anchor weight"), &mut constraints.anchor_weight)
+			.min(0.01).max(10.0).speed(0.01)
+			.build();
+		    let mut falloff = constraints.falloff as i32;
+		    ui.slider_int(im_str!("Falloff (points)"), &mut falloff, 0, 20).build();
+		    constraints.falloff = falloff as usize;
+		    if let Some(ref warning) = edit_state.solver_warning {
+			ui.text_colored([1.0, 0.3, 0.3, 1.0], warning);
+		    }
+		    ui.separator();
+
+		    if gui_state.using_gizmo && !gui_state.using_oversketch {
+			// Works on the selection, or the whole cylinder if nothing is selected
+			ui.text(im_str!("Transform (selected points or whole cylinder)"));
+			let gizmo = &mut edit_state.gizmo;
+			ui.radio_button(im_str!("Free"), &mut gizmo.axis, gizmo::Axis::Free);
+			ui.radio_button(im_str!("X axis"), &mut gizmo.axis, gizmo::Axis::X);
+			ui.radio_button(im_str!("Y axis"), &mut gizmo.axis, gizmo::Axis::Y);
+			ui.input_float2(im_str!("Translation"), &mut gizmo.translation).build();
+			ui.input_float(im_str!("Angle (degrees)"), &mut gizmo.angle).build();
+			ui.input_float(im_str!("Scale"), &mut gizmo.scale).build();
+			if gizmo.scale <= 0.0 {
+			    gizmo.scale = 1.0;
+			}
+			if ui.button(im_str!("Apply transform"), [200.0, 30.0]) {
+			    gizmo::apply_numeric_transform(edit_state, session);
+			}
+			ui.separator();
+		    }
+
+		    if !gui_state.using_peeling && !gui_state.using_oversketch && !gui_state.using_gizmo {
+			ui.text(im_str!("Select (shift adds, ctrl removes)"));
+			ui.radio_button(im_str!("Points"), &mut gui_state.selection_tool, edit::SelectionTool::Points);
+			ui.radio_button(im_str!("Box"), &mut gui_state.selection_tool, edit::SelectionTool::Box);
+			ui.radio_button(im_str!("Lasso"), &mut gui_state.selection_tool, edit::SelectionTool::Lasso);
+
+			if edit_state.selected_indices.len() == 2 &&
+			    ui.button(im_str!("Select between"), [200.0, 30.0]) {
+				edit_state.select_between(session);
+			    }
+
+			ui.radio_button(im_str!("Size"), &mut gui_state.annotation_selection,
+					annotation::AnnotationKind::Size);
+			ui.radio_button(im_str!("Stiffness"), &mut gui_state.annotation_selection,
+					annotation::AnnotationKind::Stiffness);
+			ui.radio_button(im_str!("Pinned"), &mut gui_state.annotation_selection,
+					annotation::AnnotationKind::Pin);
+			ui.radio_button(im_str!("Anchored"), &mut gui_state.annotation_selection,
+					annotation::AnnotationKind::Anchor);
+			ui.radio_button(im_str!("Free"), &mut gui_state.annotation_selection,
+					annotation::AnnotationKind::Free);
+			// Shift adds to the selection and ctrl subtracts from it, as with the other tools
+			if ui.button(im_str!("Select annotated"), [200.0, 30.0]) {
+			    edit_state.select_annotated(session, gui_state.annotation_selection,
+							edit::get_selection_op(key_state));
+			}
+			ui.separator();
+		    }
+
+		    // Properties of the active cylinder
+		    let cylinder = &mut session.cylinders[edit_state.curr_cylinder];
+
+		    let mut name = ImString::with_capacity(64);
+		    name.push_str(cylinder.get_name());
+		    if ui.input_text(im_str!("Name"), &mut name).build() {
+			cylinder.set_name(name.to_str());
+		    }
+
+		    let mut radius = cylinder.get_radius();
+		    let radius_changed = ui.drag_float(im_str!("Radius"), &mut radius)
+			.min(0.01).max(1.0).speed(0.005)
+			.build();
+
+		    let mut circ_resolution = cylinder.get_circ_resolution() as i32;
+		    let circ_resolution_changed = !gui_state.tessellation_settings.adaptive &&
+			ui.slider_int(im_str!("Circumferential resolution"), &mut circ_resolution,
+				      cylinder::MIN_CIRC_RESOLUTION as i32,
+				      cylinder::MAX_CIRC_RESOLUTION as i32).build();
+
+		    let mut caps_changed = false;
+		    for (end, label) in vec![(cylinder::START_CAP, "Start cap"), (cylinder::END_CAP, "End cap")] {
+			let mut cap = cylinder.get_cap(end);
+			ui.text(label);
+			edit_cap(&ui, end, &mut cap);
+			if cap != cylinder.get_cap(end) {
+			    cylinder.set_cap(end, cap);
+			    caps_changed = true;
+			}
+		    }
+
+		    // Subtractive cylinders carve into the others, live or in the blended surface
+		    let mut mode = cylinder.get_mode();
+		    ui.text(im_str!("Mode"));
+		    ui.radio_button(im_str!("Add"), &mut mode, cylinder::CombineMode::Add);
+		    ui.radio_button(im_str!("Subtract"), &mut mode, cylinder::CombineMode::Subtract);
+		    cylinder.set_mode(mode);
+		    if mode == cylinder::CombineMode::Subtract && !cylinder.is_closed() {
+			ui.text_colored([1.0, 0.3, 0.3, 1.0], im_str!("Open ends enclose nothing, so this carves nothing"));
+		    }
+
+		    let mut bend_handling = cylinder.get_bend_handling();
+		    ui.text(im_str!("Tight bends"));
+		    ui.radio_button(im_str!("Warn"), &mut bend_handling, cylinder::BendHandling::Warn);
+		    ui.radio_button(im_str!("Clamp radius"), &mut bend_handling, cylinder::BendHandling::ClampRadius);
+		    ui.radio_button(im_str!("Fix inner side"), &mut bend_handling, cylinder::BendHandling::FixInnerSide);
+		    let bend_handling_changed = bend_handling != cylinder.get_bend_handling();
+		    cylinder.set_bend_handling(bend_handling);
+
+		    let folded_rings = cylinder.get_folded_rings();
+		    if folded_rings > 0 {
+			if bend_handling == cylinder::BendHandling::Warn {
+			    ui.text_colored([1.0, 0.3, 0.3, 1.0],
+					    format!("{} rings fold over at tight bends", folded_rings));
+			} else {
+			    ui.text(format!("{} rings adjusted at tight bends", folded_rings));
+			}
+		    }
+
+		    if radius_changed || circ_resolution_changed || caps_changed || bend_handling_changed {
+			cylinder.set_radius(radius);
+			cylinder.set_circ_resolution(circ_resolution as usize);
+			cylinder.update_mesh(&session.annotations[edit_state.curr_cylinder]);
+		    }
+
+		    let mut degree = cylinder.spline.get_degree() as i32;
+		    let mut resolution = cylinder.spline.get_resolution() as i32;
+
+		    let degree_changed = ui.slider_int(im_str!("Spline degree"), &mut degree,
+						       1, splinedraw::MAX_SPLINE_DEGREE as i32).build();
+		    let resolution_changed = ui.slider_int(im_str!("Spline resolution"), &mut resolution,
+							   1, splinedraw::MAX_SPLINE_RESOLUTION as i32).build();
+
+		    let old_spline_type = cylinder.spline.spline_type;
+		    ui.text(im_str!("Spline type"));
+		    ui.radio_button(im_str!("Uniform B-spline"), &mut cylinder.spline.spline_type,
+				    splinedraw::SplineType::UniformBSpline);
+		    ui.radio_button(im_str!("Clamped B-spline"), &mut cylinder.spline.spline_type,
+				    splinedraw::SplineType::ClampedBSpline);
+		    ui.radio_button(im_str!("Catmull-Rom"), &mut cylinder.spline.spline_type,
+				    splinedraw::SplineType::CatmullRom);
+		    ui.radio_button(im_str!("Centripetal Catmull-Rom"), &mut cylinder.spline.spline_type,
+				    splinedraw::SplineType::CentripetalCatmullRom);
+
+		    let mut closed = cylinder.spline.closed;
+		    let closed_changed = ui.checkbox(im_str!("Closed loop"), &mut closed) &&
+			cylinder.spline.control_points.len() >= 3;
+		    if closed_changed {
+			cylinder.spline.closed = closed;
+		    }
+
+		    if degree_changed || resolution_changed || closed_changed ||
+			cylinder.spline.spline_type != old_spline_type {
+			cylinder.spline.set_degree(degree as usize);
+			cylinder.spline.set_resolution(resolution as usize);
+			
+			// Spline points must be regenerated before the mesh is swept along them
+			cylinder.spline.update_gpu_state();
+			cylinder.update_mesh(&session.annotations[edit_state.curr_cylinder]);
+		    }
 		},
 		program::ProgramState::Annotate(ref annotation_state) => {
 		    ui.text(im_str!("You go annotate!"));
@@ -111,7 +420,7 @@ pub fn run_gui(session: &mut program::Session,
                         for anni in 0..session.annotations[annotation_state.curr_cylinder_index as usize].len() {
                             let ann = &session.annotations[annotation_state.curr_cylinder_index as usize][anni];
                             let i = ann.as_ref().get_render_index();
-                            if annotation_state.curr_render_index == i as i32 {
+                            if annotation_state.curr_render_index == i as i32 && ann.as_ref().alters_size() {
                                 already = anni as i32;
                             }
                         }
@@ -132,6 +441,126 @@ pub fn run_gui(session: &mut program::Session,
                                 session.annotations[annotation_state.curr_cylinder_index as usize].push(Box::<annotation::SizeAnnotation>::from(ann));
                             }
                         }
+
+                        ui.separator();
+                        let annotations = &mut session.annotations[annotation_state.curr_cylinder_index as usize];
+                        let index = annotation_state.curr_render_index as usize;
+
+                        let stiffness_ann = annotations.iter()
+                            .position(|ann| ann.get_render_index() == index && ann.alters_stiffness());
+                        match stiffness_ann {
+                            Some(anni) => {
+                                let mut f = annotations[anni].get_stiffness();
+                                ui.drag_float(im_str!("Stiffness"), &mut f)
+                                    .min(0.1).max(10.0).speed(0.05)
+                                    .build();
+                                annotations[anni].set_stiffness(f);
+                            },
+                            None => {
+                                if ui.button(im_str!("Create stiffness annotation"), [200.0, 30.0]) {
+                                    let ann = annotation::StiffnessAnnotation { stiffness: 2.0,
+                                                                                position: glm::vec3(0.0, 0.0, 0.0),
+                                                                                index };
+                                    annotations.push(Box::<annotation::StiffnessAnnotation>::from(ann));
+                                }
+                            }
+                        }
+
+                        // Caps can only be annotated on the ends of the cylinder
+                        let num_points = session.cylinders[annotation_state.curr_cylinder_index as usize]
+                            .spline.control_points.len();
+                        if index == 0 || index + 1 == num_points {
+                            let cap_ann = annotations.iter()
+                                .position(|ann| ann.get_render_index() == index && ann.get_cap().is_some());
+                            match cap_ann {
+                                Some(anni) => {
+                                    let mut cap = annotations[anni].get_cap().unwrap();
+                                    ui.text(im_str!("Cap"));
+                                    edit_cap(&ui, index, &mut cap);
+                                    annotations[anni].set_cap(cap);
+                                },
+                                None => {
+                                    if ui.button(im_str!("Create cap annotation"), [200.0, 30.0]) {
+                                        let ann = annotation::CapAnnotation { cap: cylinder::Cap::new(cylinder::CapStyle::Flat),
+                                                                              position: glm::vec3(0.0, 0.0, 0.0),
+                                                                              index };
+                                        annotations.push(Box::<annotation::CapAnnotation>::from(ann));
+                                    }
+                                }
+                            }
+                        }
+
+                        let color_ann = annotations.iter()
+                            .position(|ann| ann.get_render_index() == index && ann.get_paint().is_some());
+                        match color_ann {
+                            Some(anni) => {
+                                let mut paint = annotations[anni].get_paint().unwrap();
+                                ui.color_edit(im_str!("Color"), &mut paint.color).build();
+                                ui.drag_float(im_str!("Color variation"), &mut paint.variation.amplitude)
+                                    .min(0.0).max(1.0).speed(0.01)
+                                    .build();
+                                if paint.variation.amplitude > 0.0 {
+                                    edit_noise(&ui, &mut paint.variation, "Variation");
+                                }
+                                annotations[anni].set_paint(paint);
+                            },
+                            None => {
+                                if ui.button(im_str!("Create color annotation"), [200.0, 30.0]) {
+                                    let ann = annotation::ColorAnnotation { paint: cylinder::Paint::new([0.8, 0.3, 0.3]),
+                                                                            position: glm::vec3(0.0, 0.0, 0.0),
+                                                                            index };
+                                    annotations.push(Box::<annotation::ColorAnnotation>::from(ann));
+                                }
+                            }
+                        }
+
+                        // Noise displaces the whole cylinder, wherever it is annotated
+                        let noise_ann = annotations.iter()
+                            .position(|ann| ann.get_render_index() == index && ann.get_noise().is_some());
+                        match noise_ann {
+                            Some(anni) => {
+                                let mut noise = annotations[anni].get_noise().unwrap();
+                                ui.text(im_str!("Noise displacement"));
+                                ui.drag_float(im_str!("Amplitude"), &mut noise.amplitude)
+                                    .min(0.0).max(0.1).speed(0.001)
+                                    .build();
+                                edit_noise(&ui, &mut noise, "Displacement");
+                                annotations[anni].set_noise(noise);
+
+                                if !gui_state.tessellation_settings.adaptive {
+                                    ui.text(im_str!("Use the adaptive mesh to show the detail"));
+                                }
+                            },
+                            None => {
+                                if ui.button(im_str!("Create noise annotation"), [200.0, 30.0]) {
+                                    let ann = annotation::NoiseAnnotation { noise: noise::NoiseSettings::new(noise::NoiseKind::Perlin),
+                                                                            position: glm::vec3(0.0, 0.0, 0.0),
+                                                                            index };
+                                    annotations.push(Box::<annotation::NoiseAnnotation>::from(ann));
+                                }
+                            }
+                        }
+
+                        let constraint_ann = annotations.iter()
+                            .position(|ann| ann.get_render_index() == index && ann.get_constraint().is_some());
+                        match constraint_ann {
+                            Some(anni) => {
+                                let mut kind = annotations[anni].get_constraint().unwrap();
+                                ui.text(im_str!("Constraint"));
+                                ui.radio_button(im_str!("Pin"), &mut kind, laplacian::ConstraintKind::Pin);
+                                ui.radio_button(im_str!("Anchor"), &mut kind, laplacian::ConstraintKind::Anchor);
+                                ui.radio_button(im_str!("Free"), &mut kind, laplacian::ConstraintKind::Free);
+                                annotations[anni].set_constraint(kind);
+                            },
+                            None => {
+                                if ui.button(im_str!("Create constraint annotation"), [200.0, 30.0]) {
+                                    let ann = annotation::ConstraintAnnotation { kind: laplacian::ConstraintKind::Pin,
+                                                                                 position: glm::vec3(0.0, 0.0, 0.0),
+                                                                                 index };
+                                    annotations.push(Box::<annotation::ConstraintAnnotation>::from(ann));
+                                }
+                            }
+                        }
                     }
                     
                     /* if annotation_state.curr_cylinder_index >= 0 {
@@ -147,6 +576,46 @@ pub fn run_gui(session: &mut program::Session,
 		},
 		program::ProgramState::Draw => {
 		    ui.text(im_str!("Do some drawing already!"));
+		    ui.separator();
+
+		    let settings = &mut gui_state.stroke_settings;
+		    
+		    ui.text(im_str!("Stroke smoothing"));
+		    ui.radio_button(im_str!("None"), &mut settings.filter, stroke::StrokeFilter::Off);
+		    ui.radio_button(im_str!("Gaussian"), &mut settings.filter, stroke::StrokeFilter::Gaussian);
+		    ui.radio_button(im_str!("One-euro"), &mut settings.filter, stroke::StrokeFilter::OneEuro);
+
+		    match settings.filter {
+			stroke::StrokeFilter::Gaussian => {
+			    ui.drag_float(im_str!("Sigma (samples)"), &mut settings.gaussian_sigma)
+				.min(0.0).max(10.0).speed(0.05)
+				.build();
+			},
+			stroke::StrokeFilter::OneEuro => {
+			    ui.drag_float(im_str!("Min cutoff (Hz)"), &mut settings.one_euro_min_cutoff)
+				.min(0.01).max(10.0).speed(0.01)
+				.build();
+			    ui.drag_float(im_str!("Beta"), &mut settings.one_euro_beta)
+				.min(0.0).max(10.0).speed(0.01)
+				.build();
+			},
+			stroke::StrokeFilter::Off => {}
+		    }
+		    
+		    ui.separator();
+		    ui.drag_float(im_str!("Point spacing"), &mut settings.spacing)
+			.min(0.01).max(0.2).speed(0.001)
+			.build();
+		    ui.drag_float(im_str!("Curvature adaptivity"), &mut settings.curvature_adaptivity)
+			.min(0.0).max(5.0).speed(0.01)
+			.build();
+		    ui.drag_float(im_str!("Simplification"), &mut settings.simplify_tolerance)
+			.min(0.0).max(0.05).speed(0.0005)
+			.build();
+
+		    let mut max_points = settings.max_points as i32;
+		    ui.slider_int(im_str!("Max points"), &mut max_points, 2, 1000).build();
+		    settings.max_points = max_points as usize;
 		}
 	    }
 	    
@@ -157,3 +626,36 @@ pub fn run_gui(session: &mut program::Session,
     glfw_state.imgui_glfw_context.draw(ui, &mut glfw_state.window);
 }
 
+// Style and depth of a cap. id keeps the widgets of several caps apart
+fn edit_cap(ui: &imgui_glfw_rs::imgui::Ui, id: usize, cap: &mut cylinder::Cap) {
+    ui.radio_button(&im_str!("Hemisphere##{}", id), &mut cap.style, cylinder::CapStyle::Hemisphere);
+    ui.radio_button(&im_str!("Flat##{}", id), &mut cap.style, cylinder::CapStyle::Flat);
+    ui.radio_button(&im_str!("Cone##{}", id), &mut cap.style, cylinder::CapStyle::Cone);
+    ui.radio_button(&im_str!("Ellipsoid##{}", id), &mut cap.style, cylinder::CapStyle::Ellipsoid);
+    ui.radio_button(&im_str!("Open##{}", id), &mut cap.style, cylinder::CapStyle::Open);
+
+    if cap.style == cylinder::CapStyle::Cone || cap.style == cylinder::CapStyle::Ellipsoid {
+	ui.drag_float(&im_str!("Depth##{}", id), &mut cap.depth)
+	    .min(0.0).max(5.0).speed(0.02)
+	    .build();
+    }
+}
+
+// Kind, frequency, octaves and seed. Callers edit the amplitude, whose units differ
+fn edit_noise(ui: &imgui_glfw_rs::imgui::Ui, noise: &mut noise::NoiseSettings, id: &str) {
+    ui.radio_button(&im_str!("Perlin##{}", id), &mut noise.kind, noise::NoiseKind::Perlin);
+    ui.radio_button(&im_str!("Simplex##{}", id), &mut noise.kind, noise::NoiseKind::Simplex);
+    ui.radio_button(&im_str!("Worley##{}", id), &mut noise.kind, noise::NoiseKind::Worley);
+
+    ui.drag_float(&im_str!("Frequency##{}", id), &mut noise.frequency)
+	.min(1.0).max(200.0).speed(0.5)
+	.build();
+
+    let mut octaves = noise.octaves as i32;
+    ui.slider_int(&im_str!("Octaves##{}", id), &mut octaves, 1, 8).build();
+    noise.octaves = octaves as usize;
+
+    let mut seed = noise.seed as i32;
+    ui.input_int(&im_str!("Seed##{}", id), &mut seed).build();
+    noise.seed = seed.max(0) as u32;
+}
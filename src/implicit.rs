@@ -0,0 +1,575 @@
+extern crate glm;
+
+use crate::program;
+use crate::cylinder;
+use crate::objects;
+use crate::lineobjects;
+use crate::Object;
+
+use glm::builtin::*;
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+// A blended surface of the whole session. Every cylinder is a skeleton of segments with
+// linearly varying radius, each carrying a smooth kernel. The segments of a cylinder are
+// joined with max so that its own joints do not bulge, and the cylinders are summed so
+// that they blend where they meet. The surface is polygonized by dual contouring on a
+// grid that encloses the field, which makes it watertight. Rounded ends come with the
+// segments; other cap styles cut the segments off at the plane of the end ring and put
+// a kernel of their own shape beyond it. Cylinders with open ends enclose nothing and
+// are left out, like in the mesh booleans. Subtractive cylinders make a field of their
+// own that is cut out of the rest with a sharp edge, where the carving field reaches
+// the iso value.
+
+pub static MIN_RESOLUTION: usize = 16;
+pub static MAX_RESOLUTION: usize = 160;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ImplicitSettings {
+    pub enabled: bool,
+    pub resolution: usize, // Grid cells along the longest side of the session
+    pub blend: f32,        // How far each cylinder reaches, in multiples of its radius
+}
+
+impl ImplicitSettings {
+    pub fn new() -> ImplicitSettings {
+	ImplicitSettings { enabled: false,
+			   resolution: 64,
+			   blend: 2.0 }
+    }
+}
+
+struct Segment {
+    a: glm::Vec3,
+    b: glm::Vec3,
+    radius_a: f32,
+    radius_b: f32,
+    min: [f32; 3], // Bounds of the kernel's support
+    max: [f32; 3],
+    cuts: Vec<(glm::Vec3, glm::Vec3)>, // Planes of the caps, as a point and the outward normal
+}
+
+impl Segment {
+    fn new(a: glm::Vec3, b: glm::Vec3, radius_a: f32, radius_b: f32, blend: f32) -> Segment {
+	let reach = blend * radius_a.max(radius_b);
+	Segment { a, b, radius_a, radius_b,
+		  min: [a.x.min(b.x) - reach, a.y.min(b.y) - reach, a.z.min(b.z) - reach],
+		  max: [a.x.max(b.x) + reach, a.y.max(b.y) + reach, a.z.max(b.z) + reach],
+		  cuts: Vec::new() }
+    }
+
+    fn contains(&self, p: glm::Vec3) -> bool {
+	p.x >= self.min[0] && p.x <= self.max[0] &&
+	    p.y >= self.min[1] && p.y <= self.max[1] &&
+	    p.z >= self.min[2] && p.z <= self.max[2]
+    }
+
+    // Distance to the segment, over the radius at the closest point. Nothing reaches
+    // past the caps that cut the segment
+    fn get_ratio(&self, p: glm::Vec3) -> f32 {
+	if self.cuts.iter().any(|(c, n)| dot(p - *c, *n) > 0.0) {
+	    return f32::INFINITY;
+	}
+
+	let ab = self.b - self.a;
+	let t = (dot(p - self.a, ab) / dot(ab, ab).max(1e-12)).max(0.0).min(1.0);
+	let radius = self.radius_a + (self.radius_b - self.radius_a) * t;
+
+	length(p - (self.a + ab * t)) / radius.max(1e-6)
+    }
+}
+
+// A flat, conical or ellipsoidal cap beyond the plane of an end ring
+struct EndCap {
+    center: glm::Vec3,
+    direction: glm::Vec3, // Outward along the spline
+    radius: f32,
+    cap: cylinder::Cap,
+    min: [f32; 3],
+    max: [f32; 3],
+}
+
+impl EndCap {
+    fn new(center: glm::Vec3, direction: glm::Vec3, radius: f32, cap: cylinder::Cap, blend: f32) -> EndCap {
+	let reach = blend * radius * cap.depth.max(1.0);
+	EndCap { center, direction, radius, cap,
+		 min: [center.x - reach, center.y - reach, center.z - reach],
+		 max: [center.x + reach, center.y + reach, center.z + reach] }
+    }
+
+    fn contains(&self, p: glm::Vec3) -> bool {
+	p.x >= self.min[0] && p.x <= self.max[0] &&
+	    p.y >= self.min[1] && p.y <= self.max[1] &&
+	    p.z >= self.min[2] && p.z <= self.max[2]
+    }
+
+    // Like the segments, 1 on the surface of the cap and growing away from it. The
+    // height above the plane and the distance from the axis are relative to the radius
+    fn get_ratio(&self, p: glm::Vec3) -> f32 {
+	let height = dot(p - self.center, self.direction);
+	if height <= 0.0 {
+	    return f32::INFINITY;
+	}
+
+	let h = height / self.radius.max(1e-6);
+	let q = length(p - self.center - self.direction * height) / self.radius.max(1e-6);
+	let depth = self.cap.depth.max(1e-3);
+
+	match self.cap.style {
+	    cylinder::CapStyle::Cone => q + h / depth,
+	    cylinder::CapStyle::Ellipsoid => ((h / depth).powi(2) + q * q).sqrt(),
+	    cylinder::CapStyle::Hemisphere => (h * h + q * q).sqrt(),
+	    cylinder::CapStyle::Flat | cylinder::CapStyle::Open => if q <= 1.0 {
+		1.0 + h
+	    } else {
+		1.0 + (h * h + (q - 1.0).powi(2)).sqrt()
+	    },
+	}
+    }
+}
+
+struct Tube {
+    segments: Vec<Segment>,
+    caps: Vec<EndCap>,
+    color: [f32; 3],
+}
+
+struct Field {
+    tubes: Vec<Tube>,
+    carving: Vec<Tube>,
+    blend: f32,
+}
+
+impl Field {
+    // Falls from 1 on the skeleton to 0 at blend times the radius
+    fn kernel(&self, ratio: f32) -> f32 {
+	let q = ratio / self.blend;
+	if q >= 1.0 {
+	    0.0
+	} else {
+	    let s = 1.0 - q * q;
+	    s * s * s
+	}
+    }
+
+    // The surface of a lone tube lies at its radius
+    fn get_iso(&self) -> f32 {
+	self.kernel(1.0)
+    }
+
+    fn get_tube_value(&self, tube: &Tube, p: glm::Vec3) -> f32 {
+	let value = tube.segments.iter()
+	    .filter(|s| s.contains(p))
+	    .fold(0.0f32, |m, s| m.max(self.kernel(s.get_ratio(p))));
+
+	tube.caps.iter()
+	    .filter(|c| c.contains(p))
+	    .fold(value, |m, c| m.max(self.kernel(c.get_ratio(p))))
+    }
+
+    fn get_value(&self, p: glm::Vec3) -> f32 {
+	let value = self.tubes.iter().map(|t| self.get_tube_value(t, p)).sum();
+	if self.carving.len() == 0 {
+	    return value;
+	}
+
+	let carving : f32 = self.carving.iter().map(|t| self.get_tube_value(t, p)).sum();
+	self.carve(value, carving)
+    }
+
+    // Inside where the value is above the iso value and the carving value below it
+    fn carve(&self, value: f32, carving: f32) -> f32 {
+	value.min(2.0 * self.get_iso() - carving)
+    }
+
+    fn get_gradient(&self, p: glm::Vec3, h: f32) -> glm::Vec3 {
+	let dx = glm::vec3(h, 0.0, 0.0);
+	let dy = glm::vec3(0.0, h, 0.0);
+	let dz = glm::vec3(0.0, 0.0, h);
+
+	glm::vec3(self.get_value(p + dx) - self.get_value(p - dx),
+		  self.get_value(p + dy) - self.get_value(p - dy),
+		  self.get_value(p + dz) - self.get_value(p - dz)) / (2.0 * h)
+    }
+
+    // Colors of the tubes, weighted by their share of the field
+    fn get_color(&self, p: glm::Vec3) -> [f32; 3] {
+	let mut color = [0.0; 3];
+	let mut total = 0.0;
+
+	for tube in &self.tubes {
+	    let w = self.get_tube_value(tube, p);
+	    for k in 0..3 {
+		color[k] += w * tube.color[k];
+	    }
+	    total += w;
+	}
+
+	if total > 1e-9 {
+	    [color[0] / total, color[1] / total, color[2] / total]
+	} else {
+	    [1.0, 1.0, 1.0]
+	}
+    }
+}
+
+fn get_field(session: &program::Session, blend: f32) -> Field {
+    let closed = session.cylinders.iter().filter(|c| c.is_closed());
+    let (tubes, carving) : (Vec<(Tube, bool)>, Vec<(Tube, bool)>) = closed.map(|cylinder| {
+	let rings = cylinder.get_rings();
+	let radius = cylinder.get_radius();
+	let n = rings.len();
+	let num_segments = if cylinder.spline.closed { n } else { n.max(1) - 1 };
+
+	let mut segments : Vec<Segment> = (0..num_segments)
+	    .map(|i| {
+		let (a, b) = (rings[i], rings[(i + 1) % n]);
+		Segment::new(a.center, b.center, radius * a.scale, radius * b.scale, blend)
+	    })
+	    .collect();
+
+	// A single ring still makes a ball
+	if n == 1 {
+	    segments.push(Segment::new(rings[0].center, rings[0].center,
+				       radius * rings[0].scale, radius * rings[0].scale, blend));
+	}
+
+	// Rounded ends come with the segments. Other caps cut off the segments that reach
+	// past the plane of their end ring, and take over beyond it
+	let mut caps = Vec::new();
+	if !cylinder.spline.closed && n > 1 {
+	    for end in 0..2 {
+		let cap = cylinder.get_cap(end);
+		if cap.style == cylinder::CapStyle::Hemisphere {
+		    continue;
+		}
+
+		let (last, inner) = if end == cylinder::START_CAP { (0, 1) } else { (n - 1, n - 2) };
+		let center = rings[last].center;
+		let direction = normalize(center - rings[inner].center);
+		let end_radius = radius * rings[last].scale;
+
+		// Any segment within the reach of the kernels may bulge past the plane
+		let order : Vec<usize> = if end == cylinder::START_CAP {
+		    (0..num_segments).collect()
+		} else {
+		    (0..num_segments).rev().collect()
+		};
+		for i in order {
+		    let s = &mut segments[i];
+		    let reach = blend * s.radius_a.max(s.radius_b);
+		    if dot(s.a - center, direction) < -reach && dot(s.b - center, direction) < -reach {
+			break;
+		    }
+		    s.cuts.push((center, direction));
+		}
+
+		caps.push(EndCap::new(center, direction, end_radius, cap, blend));
+	    }
+	}
+
+	// The average color of the cylinder
+	let colors = &cylinder.object.colors;
+	let mut color = [0.0; 3];
+	for c in colors.chunks(3) {
+	    for k in 0..3 {
+		color[k] += c[k] / (colors.len() / 3).max(1) as f32;
+	    }
+	}
+	if colors.len() == 0 {
+	    color = [1.0, 1.0, 1.0];
+	}
+
+	(Tube { segments, caps, color }, cylinder.get_mode() == cylinder::CombineMode::Add)
+    }).partition(|(_, add)| *add);
+
+    Field { tubes: tubes.into_iter().map(|(t, _)| t).collect(),
+	    carving: carving.into_iter().map(|(t, _)| t).collect(),
+	    blend }
+}
+
+// Samples of the field on a regular grid
+struct Grid {
+    origin: glm::Vec3,
+    cell: f32,
+    dims: [usize; 3], // Number of samples along each axis
+    values: Vec<f32>,
+}
+
+impl Grid {
+    fn index(&self, i: usize, j: usize, k: usize) -> usize {
+	(k * self.dims[1] + j) * self.dims[0] + i
+    }
+
+    fn position(&self, i: usize, j: usize, k: usize) -> glm::Vec3 {
+	self.origin + glm::vec3(i as f32, j as f32, k as f32) * self.cell
+    }
+
+    // Range of samples within [min, max] along an axis
+    fn range(&self, axis: usize, min: f32, max: f32) -> (usize, usize) {
+	let origin = [self.origin.x, self.origin.y, self.origin.z][axis];
+	let first = ((min - origin) / self.cell).ceil().max(0.0) as usize;
+	let last = (((max - origin) / self.cell).floor().max(0.0) as usize).min(self.dims[axis] - 1);
+	(first, last)
+    }
+}
+
+fn sample_field(field: &Field, resolution: usize) -> Option<Grid> {
+    let mut min = [f32::INFINITY; 3];
+    let mut max = [f32::NEG_INFINITY; 3];
+    for tube in &field.tubes {
+	let bounds = tube.segments.iter().map(|s| (s.min, s.max))
+	    .chain(tube.caps.iter().map(|c| (c.min, c.max)));
+	for (lo, hi) in bounds {
+	    for k in 0..3 {
+		min[k] = min[k].min(lo[k]);
+		max[k] = max[k].max(hi[k]);
+	    }
+	}
+    }
+
+    if !min[0].is_finite() {
+	return None;
+    }
+
+    let resolution = resolution.max(MIN_RESOLUTION).min(MAX_RESOLUTION);
+    let longest = (0..3).fold(0.0f32, |m, k| m.max(max[k] - min[k]));
+    let cell = longest.max(1e-6) / resolution as f32;
+
+    // One cell of padding keeps the surface off the border of the grid, so that it closes
+    let origin = glm::vec3(min[0] - cell, min[1] - cell, min[2] - cell);
+    let mut dims = [0; 3];
+    for k in 0..3 {
+	dims[k] = ((max[k] - min[k]) / cell).ceil() as usize + 3;
+    }
+
+    let mut grid = Grid { origin, cell, dims, values: vec![0.0; dims[0] * dims[1] * dims[2]] };
+    grid.values = splat_tubes(field, &grid, &field.tubes);
+
+    if field.carving.len() > 0 {
+	let carving = splat_tubes(field, &grid, &field.carving);
+	for (v, c) in grid.values.iter_mut().zip(carving.iter()) {
+	    *v = field.carve(*v, *c);
+	}
+    }
+
+    Some(grid)
+}
+
+// The sum of the tubes at the samples of the grid
+fn splat_tubes(field: &Field, grid: &Grid, tubes: &Vec<Tube>) -> Vec<f32> {
+    let mut values = vec![0.0f32; grid.values.len()];
+    let mut tube_values = vec![0.0f32; grid.values.len()];
+
+    // Every segment only touches the samples within its bounds
+    for tube in tubes {
+	for v in tube_values.iter_mut() {
+	    *v = 0.0;
+	}
+
+	for segment in &tube.segments {
+	    splat(field, grid, segment.min, segment.max, &mut tube_values, |p| segment.get_ratio(p));
+	}
+	for cap in &tube.caps {
+	    splat(field, grid, cap.min, cap.max, &mut tube_values, |p| cap.get_ratio(p));
+	}
+
+	for (v, t) in values.iter_mut().zip(tube_values.iter()) {
+	    *v += *t;
+	}
+    }
+
+    values
+}
+
+// The largest of the values and a kernel, at the samples within its bounds
+fn splat<F: Fn(glm::Vec3) -> f32>(field: &Field, grid: &Grid, min: [f32; 3], max: [f32; 3],
+				  values: &mut Vec<f32>, get_ratio: F) {
+    let (i0, i1) = grid.range(0, min[0], max[0]);
+    let (j0, j1) = grid.range(1, min[1], max[1]);
+    let (k0, k1) = grid.range(2, min[2], max[2]);
+
+    for k in k0..=k1 {
+	for j in j0..=j1 {
+	    for i in i0..=i1 {
+		let index = grid.index(i, j, k);
+		let value = field.kernel(get_ratio(grid.position(i, j, k)));
+		values[index] = values[index].max(value);
+	    }
+	}
+    }
+}
+
+pub struct BlendedMesh {
+    pub vertices: Vec<f32>,
+    pub normals: Vec<f32>,
+    pub colors: Vec<f32>,
+    pub indices: Vec<u32>,
+}
+
+// Dual contouring. Each grid cell that the surface passes through gets a vertex, at the
+// average of the crossings on its edges moved onto the surface, and each grid edge that
+// crosses the surface gets a quad between the four cells around it
+fn polygonize(field: &Field, grid: &Grid) -> BlendedMesh {
+    let iso = field.get_iso();
+    let [nx, ny, nz] = grid.dims;
+    let inside = |i: usize, j: usize, k: usize| grid.values[grid.index(i, j, k)] > iso;
+
+    let cell_index = |i: usize, j: usize, k: usize| (k * (ny - 1) + j) * (nx - 1) + i;
+    let mut cell_vertices = vec![u32::max_value(); (nx - 1) * (ny - 1) * (nz - 1)];
+
+    let mut vertices : Vec<f32> = Vec::new();
+    let mut normals : Vec<f32> = Vec::new();
+    let mut colors : Vec<f32> = Vec::new();
+
+    let corners : Vec<[usize; 3]> = (0..8).map(|c| [c & 1, (c >> 1) & 1, (c >> 2) & 1]).collect();
+    let edges : Vec<(usize, usize)> = (0..8)
+	.flat_map(|a| (0..3).map(move |axis| (a, a | (1 << axis))).filter(move |(a, b)| a != b))
+	.collect();
+
+    for k in 0..(nz - 1) {
+	for j in 0..(ny - 1) {
+	    for i in 0..(nx - 1) {
+		let values : Vec<f32> = corners.iter()
+		    .map(|c| grid.values[grid.index(i + c[0], j + c[1], k + c[2])])
+		    .collect();
+
+		let num_inside = values.iter().filter(|v| **v > iso).count();
+		if num_inside == 0 || num_inside == 8 {
+		    continue;
+		}
+
+		let mut sum = glm::vec3(0.0, 0.0, 0.0);
+		let mut count = 0.0;
+		for (a, b) in &edges {
+		    if (values[*a] > iso) != (values[*b] > iso) {
+			let t = (iso - values[*a]) / (values[*b] - values[*a]);
+			let pa = grid.position(i + corners[*a][0], j + corners[*a][1], k + corners[*a][2]);
+			let pb = grid.position(i + corners[*b][0], j + corners[*b][1], k + corners[*b][2]);
+			sum = sum + pa + (pb - pa) * t;
+			count += 1.0;
+		    }
+		}
+
+		// One Newton step towards the surface, kept within the cell
+		let mut p = sum / count;
+		let gradient = field.get_gradient(p, grid.cell / 4.0);
+		let g2 = dot(gradient, gradient);
+		if g2 > 1e-12 {
+		    p = p - gradient * ((field.get_value(p) - iso) / g2);
+		}
+		let low = grid.position(i, j, k);
+		let high = grid.position(i + 1, j + 1, k + 1);
+		p = glm::vec3(p.x.max(low.x).min(high.x),
+			      p.y.max(low.y).min(high.y),
+			      p.z.max(low.z).min(high.z));
+
+		// The field grows inwards
+		let gradient = field.get_gradient(p, grid.cell / 4.0);
+		let normal = if length(gradient) > 1e-9 {
+		    normalize(gradient) * -1.0
+		} else {
+		    glm::vec3(0.0, 0.0, 1.0)
+		};
+		let color = field.get_color(p);
+
+		cell_vertices[cell_index(i, j, k)] = (vertices.len() / 3) as u32;
+		vertices.extend_from_slice(&[p.x, p.y, p.z]);
+		normals.extend_from_slice(&[normal.x, normal.y, normal.z]);
+		colors.extend_from_slice(&color);
+	    }
+	}
+    }
+
+    // Around an edge along axis a, the cells are visited counter-clockwise seen from
+    // the positive end of a, which faces outwards when the edge leaves the inside
+    let mut indices : Vec<u32> = Vec::new();
+
+    for k in 1..(nz - 1) {
+	for j in 1..(ny - 1) {
+	    for i in 1..(nx - 1) {
+		let p = [i, j, k];
+		let here = inside(i, j, k);
+
+		for a in 0..3 {
+		    let mut q = p;
+		    q[a] += 1;
+		    if q[a] >= grid.dims[a] - 1 || inside(q[0], q[1], q[2]) == here {
+			continue;
+		    }
+
+		    let (b, c) = ((a + 1) % 3, (a + 2) % 3);
+		    let cell = |db: usize, dc: usize| {
+			let mut r = p;
+			r[b] -= db;
+			r[c] -= dc;
+			cell_vertices[cell_index(r[0], r[1], r[2])]
+		    };
+
+		    let mut quad = [cell(1, 1), cell(0, 1), cell(0, 0), cell(1, 0)];
+		    if quad.iter().any(|v| *v == u32::max_value()) {
+			continue;
+		    }
+		    if !here {
+			quad.reverse();
+		    }
+
+		    indices.extend_from_slice(&[quad[0], quad[1], quad[2], quad[0], quad[2], quad[3]]);
+		}
+	    }
+	}
+    }
+
+    BlendedMesh { vertices, normals, colors, indices }
+}
+
+pub fn get_blended_mesh(session: &program::Session,
+			settings: &ImplicitSettings) -> Option<BlendedMesh> {
+    let field = get_field(session, settings.blend.max(1.1));
+    let grid = sample_field(&field, settings.resolution)?;
+    let mesh = polygonize(&field, &grid);
+
+    if mesh.indices.len() > 0 {
+	Some(mesh)
+    } else {
+	None
+    }
+}
+
+// The polygonized surface, shown and exported in place of the cylinders
+pub struct BlendedSurface {
+    pub object: Object,
+    pub line_object: lineobjects::LineObject,
+}
+
+pub fn create_blended_surface(session: &program::Session,
+			      settings: &ImplicitSettings) -> Option<BlendedSurface> {
+    let mesh = get_blended_mesh(session, settings)?;
+
+    Some(BlendedSurface {
+	line_object: lineobjects::create_line_object(&mesh.vertices, &mesh.indices),
+	object: objects::create_object_with_colors(mesh.vertices, mesh.normals, mesh.colors, mesh.indices) })
+}
+
+// Remakes the blended surface when the cylinders or the settings have changed since it was
+// last made
+pub fn update_blended_surface(session: &mut program::Session, settings: &ImplicitSettings) {
+    if !settings.enabled {
+	session.blended = None;
+	session.blended_fingerprint = None;
+	return;
+    }
+
+    let mut hasher = DefaultHasher::new();
+    program::get_fingerprint(session).hash(&mut hasher);
+    settings.resolution.hash(&mut hasher);
+    settings.blend.to_bits().hash(&mut hasher);
+    let fingerprint = hasher.finish();
+    if session.blended_fingerprint == Some(fingerprint) {
+	return;
+    }
+
+    session.blended = create_blended_surface(session, settings);
+    session.blended_fingerprint = Some(fingerprint);
+}
@@ -5,56 +5,382 @@ use nalgebra as na;
 use na::sparse as nsp;
 
 use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::solver;
 
 extern crate glm;
 extern crate generic_array;
 
+// Default weight of hard pins
 static FIXED_POINT_FACTOR: f32 = 10.0;
+static ANCHOR_FACTOR: f32 = 1.0;
+
+// Relative weight of the identity added to singular transformation fits
+static REGULARIZATION_FACTOR: f32 = 1e-4;
+// Added to the diagonal of the normal equations if their factorization fails
+static NORMAL_REGULARIZATION: f32 = 1e-4;
+// Local/global iterations per solve in as-rigid-as-possible mode. Each solve starts from
+// the previous positions, so the iterations continue over the frames of a drag
+static ARAP_ITERATIONS: usize = 5;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum LaplacianError {
+    NotFactorized,     // solve was called on a system without a valid factorization
+    FactorizationFailed,
+    SolveFailed,
+    NonFiniteSolution,
+}
+
+impl fmt::Display for LaplacianError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	match self {
+	    LaplacianError::NotFactorized =>
+		write!(f, "The editing system has not been set up"),
+	    LaplacianError::FactorizationFailed =>
+		write!(f, "The editing system could not be factorized"),
+	    LaplacianError::SolveFailed =>
+		write!(f, "The editing system could not be solved"),
+	    LaplacianError::NonFiniteSolution =>
+		write!(f, "The editing system produced invalid points"),
+	}
+    }
+}
+
+// Planar editing works on x and y only and fits 2D rotations and scales.
+// Spatial editing works on all three coordinates with linearized 3D rotations
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum LaplacianMode {
+    Planar,
+    Spatial,
+}
+
+impl LaplacianMode {
+    pub fn dimensions(&self) -> usize {
+	match self {
+	    LaplacianMode::Planar => 2,
+	    LaplacianMode::Spatial => 3,
+	}
+    }
+}
 
+// Linearized editing fits each point's transformation once, from the original points,
+// which makes large rotations scale the curve. As-rigid-as-possible editing alternates
+// between fitting a rotation to each point's neighborhood and solving for the points,
+// which keeps the segment lengths (Sorkine and Alexa, 2007)
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DeformationMethod {
+    Linearized,
+    AsRigidAsPossible,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ConstraintKind {
+    Pin,    // Stays where it is, as far as the solver allows
+    Anchor, // Pulled towards where it is, but gives in to the drag
+    Free,   // Not constrained at all
+}
+
+#[derive(Copy, Clone)]
+pub struct ConstraintSettings {
+    pub pin_weight: f32,
+    pub anchor_weight: f32,
+    pub falloff: usize, // In control points. 0 pins every point outside the moving region fully
+}
+
+impl ConstraintSettings {
+    pub fn new() -> ConstraintSettings {
+	ConstraintSettings { pin_weight: FIXED_POINT_FACTOR,
+			     anchor_weight: ANCHOR_FACTOR,
+			     falloff: 0 }
+    }
+
+    pub fn weight(&self, kind: ConstraintKind) -> f32 {
+	match kind {
+	    ConstraintKind::Pin => self.pin_weight,
+	    ConstraintKind::Anchor => self.anchor_weight,
+	    ConstraintKind::Free => 0.0,
+	}
+    }
+
+    // Weight of an unannotated point the given number of control points away
+    // from the moving region. Rises smoothly from 0 to the pin weight over the falloff
+    pub fn falloff_weight(&self, distance: usize) -> f32 {
+	if distance == 0 {
+	    return 0.0;
+	}
+
+	let t = (distance as f32 / (self.falloff + 1) as f32).min(1.0);
+	self.pin_weight * t * t * (3.0 - 2.0 * t)
+    }
+}
+
+// The system is split in the shape rows M x = b, which only depend on the original points and
+// the stiffness, and the constraint rows. The solver keeps whatever it derives from M between
+// calls to setup_fixed_points, so changing the constraints during a drag stays cheap.
+// In as-rigid-as-possible mode, M holds the differences along the edges of the graph and
+// b changes with the fitted rotations, so b is rebuilt in every iteration.
 pub struct LaplacianEditingSystem {
-    pub original_matrix_trans: nsp::CsMatrix<f32, na::Dynamic>,
-    pub system_composition: nsp::CsCholesky<f32, na::Dynamic>,
-    rhs:           na::MatrixMN::<f32, na::Dynamic, na::U1>,
+    solver:        Box<dyn solver::LeastSquaresSolver>,
+    shape_ready:   bool, // Whether the solver holds the shape rows of the current points
+    shape_rhs:     na::MatrixMN::<f32, na::Dynamic, na::U1>,  // b
+    edges:         Vec<(usize, usize)>, // Rows of M in as-rigid-as-possible mode
     original_points : na::MatrixMN::<f32, na::Dynamic, na::U1>,
-    fixed:         Vec<usize>,
+    fixed:         Vec<(usize, f32)>, // Constrained point and its weight
+    stiffness:     Vec<f32>,          // Scales each point's Laplacian rows. Empty means 1 everywhere
+    adjacency:     Vec<Vec<usize>>,   // Neighbors of each point
+    mode:          LaplacianMode,
+    method:        DeformationMethod,
 }
 
 impl LaplacianEditingSystem {
+    // Positions are only written if the solve succeeds
     pub fn solve(&mut self,
-		 new_positions : &mut Vec<glm::Vec3>) {
-	
- 	let l = self.system_composition.l().unwrap();
-
-	let n = l.ncols() / 2;
-	for i in 0..self.fixed.len() {
-	    self.rhs[2 * n + i] = FIXED_POINT_FACTOR * new_positions[self.fixed[i]].x;
-	    self.rhs[2 * n + self.fixed.len() + i] = FIXED_POINT_FACTOR * new_positions[self.fixed[i]].y;
-	    // self.rhs[2 * n + i] = FIXED_POINT_FACTOR * self.original_points[self.fixed[i]];
-	    // self.rhs[2 * n + self.fixed.len() + i] = FIXED_POINT_FACTOR * self.original_points[self.fixed[i] + n];
-	}
+		 new_positions : &mut Vec<glm::Vec3>) -> Result<(), LaplacianError> {
 	
-	let rhs = na::Matrix::from(&self.original_matrix_trans * &na::CsMatrix::from(self.rhs.clone()));
-	let a = l.solve_lower_triangular(&rhs);
-	let a = l.tr_solve_lower_triangular(&a.unwrap());
-	
-	if a == None {
-	    println!("Could not solve system for b = {:?}",
-		     rhs);
-	    std::process::exit(-1);
+	let dims = self.mode.dimensions();
+	let n = self.original_points.len() / dims;
+
+	// The constrained points are held at their given positions, so those are both
+	// the constraint targets and the starting point for iterative solvers
+	let mut current = na::MatrixMN::<f32, na::Dynamic, na::U1>::zeros(n * dims);
+	for i in 0..n {
+	    current[i * dims] = new_positions[i].x;
+	    current[i * dims + 1] = new_positions[i].y;
+	    if dims == 3 {
+		current[i * dims + 2] = new_positions[i].z;
+	    }
 	}
 
-	let a = a.unwrap();
+	let a = match self.method {
+	    DeformationMethod::Linearized => self.solve_least_squares(&self.shape_rhs, &current, &current)?,
+	    DeformationMethod::AsRigidAsPossible => self.solve_rigid(current)?,
+	};
+
+	if a.iter().any(|v| !v.is_finite()) {
+	    return Err(LaplacianError::NonFiniteSolution);
+	}
 	
-	let n = a.len() / 2;
+	let n = a.len() / dims;
 	for i in 0..n {
-	    new_positions[i].x = a[i];
-	    new_positions[i].y = a[i + n];
+	    new_positions[i].x = a[i * dims];
+	    new_positions[i].y = a[i * dims + 1];
+	    if dims == 3 {
+		new_positions[i].z = a[i * dims + 2];
+	    }
+	}
+
+	Ok(())
+    }
+
+    // Solves for the given right hand side of the shape rows and constraint targets
+    fn solve_least_squares(&self,
+			   rhs: &na::MatrixMN::<f32, na::Dynamic, na::U1>,
+			   targets: &na::MatrixMN::<f32, na::Dynamic, na::U1>,
+			   guess: &na::MatrixMN::<f32, na::Dynamic, na::U1>)
+			   -> Result<na::MatrixMN::<f32, na::Dynamic, na::U1>, LaplacianError> {
+	if !self.solver.is_ready() {
+	    return Err(LaplacianError::NotFactorized);
 	}
+
+	self.solver.solve(rhs, targets, guess).ok_or(LaplacianError::SolveFailed)
     }
 
+    // Alternates between fitting the rotations to the current points and solving for
+    // the points that best follow the rotated original segments
+    fn solve_rigid(&self,
+		   targets: na::MatrixMN::<f32, na::Dynamic, na::U1>)
+		   -> Result<na::MatrixMN::<f32, na::Dynamic, na::U1>, LaplacianError> {
+	let dims = self.mode.dimensions();
+	let mut current = targets.clone();
+
+	for _ in 0..ARAP_ITERATIONS {
+	    let rotations = self.fit_rotations(&current);
+
+	    // The row of edge (i, j) asks for x_i - x_j = (R_i + R_j) / 2 (q_i - q_j),
+	    // with q the original points
+	    let mut rhs = na::MatrixMN::<f32, na::Dynamic, na::U1>::zeros(self.edges.len() * dims);
+	    for e in 0..self.edges.len() {
+		let (i, j) = self.edges[e];
+		let qi = get_point(&self.original_points, i, dims);
+		let qj = get_point(&self.original_points, j, dims);
+		let b = (rotations[i] + rotations[j]) * (qi - qj) * (0.5 * self.edge_weight(i, j).sqrt());
+		for d in 0..dims {
+		    rhs[e * dims + d] = b[d];
+		}
+	    }
+
+	    current = self.solve_least_squares(&rhs, &targets, &current)?;
+	}
+
+	Ok(current)
+    }
+
+    // The rotation of each point that best maps its original segments to the current ones
+    fn fit_rotations(&self,
+		     current: &na::MatrixMN::<f32, na::Dynamic, na::U1>) -> Vec<na::Matrix3<f32>> {
+	let dims = self.mode.dimensions();
+	let n = self.original_points.len() / dims;
+
+	(0..n).map(|i| {
+	    let qi = get_point(&self.original_points, i, dims);
+	    let pi = get_point(current, i, dims);
+
+	    match self.mode {
+		LaplacianMode::Planar => {
+		    // In 2D, the best rotation angle follows directly from the summed
+		    // dot and cross products of the segments
+		    let mut dot = 0.0;
+		    let mut cross = 0.0;
+		    for j in self.neighbors(i) {
+			let eq = qi - get_point(&self.original_points, j, dims);
+			let ep = pi - get_point(current, j, dims);
+			let w = self.edge_weight(i, j);
+			dot += w * (eq.x * ep.x + eq.y * ep.y);
+			cross += w * (eq.x * ep.y - eq.y * ep.x);
+		    }
+
+		    let angle = cross.atan2(dot);
+		    na::Matrix3::new(angle.cos(), -angle.sin(), 0.0,
+				     angle.sin(), angle.cos(), 0.0,
+				     0.0, 0.0, 1.0)
+		},
+		LaplacianMode::Spatial => {
+		    let mut covariance = na::Matrix3::<f32>::zeros();
+		    for j in self.neighbors(i) {
+			let eq = qi - get_point(&self.original_points, j, dims);
+			let ep = pi - get_point(current, j, dims);
+			covariance += eq * ep.transpose() * self.edge_weight(i, j);
+		    }
+
+		    closest_rotation(covariance)
+		}
+	    }
+	}).collect()
+    }
+
+    // Segments between stiffer points are held closer to their rotated original
+    fn edge_weight(&self, i: usize, j: usize) -> f32 {
+	0.5 * (self.get_stiffness(i) + self.get_stiffness(j))
+    }
+
+    // Indices of the points connected to point i
+    fn neighbors(&self, i: usize) -> Vec<usize> {
+	self.adjacency[i].clone()
+    }
+
+    pub fn set_stiffness(&mut self, stiffness: Vec<f32>) {
+	self.stiffness = stiffness;
+	self.shape_ready = false;
+    }
+
+    fn get_stiffness(&self, i: usize) -> f32 {
+	if i < self.stiffness.len() {
+	    self.stiffness[i]
+	} else {
+	    1.0
+	}
+    }
+
+    // Constraints with a zero weight are left out of the system. Setting up the same
+    // constraints again, as peeling does every frame, does no work at all
     pub fn setup_fixed_points(&mut self,
-			      fixed: Vec<usize>) {
-	let n = self.original_points.len() / 2;
+			      fixed: Vec<(usize, f32)>) -> Result<(), LaplacianError> {
+	let fixed : Vec<(usize, f32)> = fixed.into_iter().filter(|(_, weight)| *weight > 0.0).collect();
+
+	if !self.shape_ready {
+	    self.setup_shape();
+	} else if fixed == self.fixed && self.solver.is_ready() {
+	    return Ok(());
+	}
+
+	let dims = self.mode.dimensions();
+	let n = self.original_points.len() / dims;
+	let m = dims * n;
+
+	// Each constraint row adds its squared weight to the diagonal of the normal equations
+	let mut diagonal_values = vec![0.0; m];
+	for (index, weight) in &fixed {
+	    for d in 0..dims {
+		diagonal_values[*index * dims + d] += weight * weight;
+	    }
+	}
+
+	let mut factorized = self.solver.set_constraints(&diagonal_values);
+
+	// Few fixed points or coincident points can leave the normal equations singular.
+	// A small diagonal term picks the solution closest to zero movement in those directions
+	if !factorized {
+	    for v in diagonal_values.iter_mut() {
+		*v += NORMAL_REGULARIZATION;
+	    }
+	    factorized = self.solver.set_constraints(&diagonal_values);
+	}
+
+	self.fixed = fixed;
+
+	if factorized {
+	    Ok(())
+	} else {
+	    Err(LaplacianError::FactorizationFailed)
+	}
+    }
+
+    // Builds the shape rows, i.e. everything but the constraints, and hands them to the solver
+    fn setup_shape(&mut self) {
+	let (shape, rhs) = match self.method {
+	    DeformationMethod::Linearized => self.setup_linearized_shape(),
+	    DeformationMethod::AsRigidAsPossible => self.setup_rigid_shape(),
+	};
+
+	self.solver.set_shape(shape);
+	self.shape_rhs = rhs;
+	self.shape_ready = true;
+    }
+
+    // In as-rigid-as-possible mode, there is one row per edge and coordinate, holding the
+    // difference of its end points. The right hand side depends on the rotations
+    fn setup_rigid_shape(&mut self) -> (solver::Triplets,
+					na::MatrixMN::<f32, na::Dynamic, na::U1>) {
+	let dims = self.mode.dimensions();
+	let n = self.original_points.len() / dims;
+
+	self.edges.clear();
+	for i in 0..n {
+	    for j in self.neighbors(i) {
+		if i < j {
+		    self.edges.push((i, j));
+		}
+	    }
+	}
+
+	let mut rows : Vec<usize> = Vec::with_capacity(2 * dims * self.edges.len());
+	let mut cols : Vec<usize> = Vec::with_capacity(2 * dims * self.edges.len());
+	let mut vals : Vec<f32>   = Vec::with_capacity(2 * dims * self.edges.len());
+
+	for e in 0..self.edges.len() {
+	    let (i, j) = self.edges[e];
+	    let w = self.edge_weight(i, j).sqrt();
+	    for d in 0..dims {
+		rows.push(e * dims + d);
+		cols.push(i * dims + d);
+		vals.push(w);
+
+		rows.push(e * dims + d);
+		cols.push(j * dims + d);
+		vals.push(-w);
+	    }
+	}
+
+	let shape = solver::Triplets { nrows: self.edges.len() * dims, ncols: n * dims, rows, cols, vals };
+	(shape, na::MatrixMN::<f32, na::Dynamic, na::U1>::zeros(self.edges.len() * dims))
+    }
+
+    fn setup_linearized_shape(&self) -> (solver::Triplets,
+					 na::MatrixMN::<f32, na::Dynamic, na::U1>) {
+	let dims = self.mode.dimensions();
+	let n = self.original_points.len() / dims;
+	let m = dims * n;
 	
 	let mut rows : Vec<usize> = Vec::with_capacity(3 * n);
 	let mut cols : Vec<usize> = Vec::with_capacity(3 * n);
@@ -63,164 +389,304 @@ impl LaplacianEditingSystem {
 	let mut index_map : BTreeMap<(usize, usize), usize> = BTreeMap::new();
 
 	
-	// Now, since we are only dealing with a single spline, we assume that every node
-	// is only connected to its neighbors in the point list.
+	// The points form a graph given by the adjacency lists. For a single spline, every node
+	// is only connected to its neighbors in the point list (wrapping around if the spline is closed),
+	// while junctions between connected cylinders have three or more neighbors.
 	
-	// Create the Laplacian so that it has separate elements for x- and y-components of input points
-	// (In other words, we will multiply the Laplacian by a vector representing 2D points such that
-	// it first holds all x-components of the points, then the y-components)    
+	// Create the Laplacian so that it has separate elements for each component of input points
+	// (In other words, we will multiply the Laplacian by a vector representing the points such that
+	// it holds the x-, y- (and in spatial mode z-) components of the first point, then of the second
+	// point and so on. Keeping the components of a point together keeps the normal equations banded,
+	// which avoids fill-in in the Cholesky factorization)
 	for i in 0..n {
-	    if i > 0 {
-		let ci = i - 1;
-
-		let val = if i == n - 1 { - 1.0 } else { - 0.5 };
-		
-		insert_triplet(&mut rows, &mut cols, &mut vals,
-			       i, ci, val,
-			       &mut index_map);
-
-		insert_triplet(&mut rows, &mut cols, &mut vals,
-			       i + n, ci + n, val,
-			       &mut index_map);
+	    let neighbors = self.neighbors(i);
+	    // Isolated points have no Laplacian coordinates to preserve
+	    let val = if neighbors.len() > 0 { - 1.0 / neighbors.len() as f32 } else { 0.0 };
+	    
+	    for ci in neighbors {
+		for d in 0..dims {
+		    insert_triplet(&mut rows, &mut cols, &mut vals,
+				   i * dims + d, ci * dims + d, val,
+				   &mut index_map);
+		}
 	    }
 
-	    if i < n - 1 {
-
-		let ci = i + 1;
-		
-		let val = if i == 0 { -1.0 } else { -0.5 };
-		
-		insert_triplet(&mut rows, &mut cols, &mut vals,
-			       i, ci, val,
-			       &mut index_map);
+	    for d in 0..dims {
 		insert_triplet(&mut rows, &mut cols, &mut vals,
-			       i + n, ci + n, val,
+			       i * dims + d, i * dims + d, 1.0,
 			       &mut index_map);
 	    }
-
-	    insert_triplet(&mut rows, &mut cols, &mut vals,
-			   i, i, 1.0,
-			   &mut index_map);
-
-	    
-	    insert_triplet(&mut rows, &mut cols, &mut vals,
-			   i + n, i + n, 1.0,
-			   &mut index_map);
 	}
 
-	let laplacian = nsp::CsMatrix::from_triplet(2 * n + 2 * fixed.len(), 2 * n,
-						    &rows, &cols, &vals);
+	let laplacian = nsp::CsMatrix::from_triplet(m, m, &rows, &cols, &vals);
 
 	let delta_vector = na::Matrix::from(&laplacian * &na::CsMatrix::from(self.original_points.clone()));
 
-	// Redeclare these, to construct a new matrix
+	// Keep the Laplacian entries, and start on T_tilde. The shape rows are T_tilde - L
+	let laplacian_entries : Vec<(usize, usize, f32)> = (0..rows.len()).map(|k| (rows[k], cols[k], vals[k])).collect();
 	rows.clear();
 	cols.clear();
 	vals.clear();
 	index_map.clear();
 	
 
+	let degenerate = match self.mode {
+	    LaplacianMode::Planar => self.insert_planar_transforms(&delta_vector, &mut rows, &mut cols,
+								   &mut vals, &mut index_map),
+	    LaplacianMode::Spatial => self.insert_spatial_transforms(&delta_vector, &mut rows, &mut cols,
+								     &mut vals, &mut index_map),
+	};
+
+	for (row, col, val) in laplacian_entries {
+	    insert_triplet(&mut rows, &mut cols, &mut vals,
+			   row, col, -val,
+			   &mut index_map);
+	}
+
+	// Stiffer points weigh their Laplacian rows higher, so they resist bending more
+	for k in 0..vals.len() {
+	    vals[k] *= self.get_stiffness(rows[k] / dims);
+	}
+
+	let mut rhs_vector = na::Matrix::<f32, na::Dynamic, na::U1, _>::zeros(m);
+
+	// Points without a transformation keep their original Laplacian coordinates,
+	// i.e. their rows become -L x = -delta
+	for i in degenerate {
+	    for d in 0..dims {
+		rhs_vector[i * dims + d] = -self.get_stiffness(i) * delta_vector[i * dims + d];
+	    }
+	}
+
+	(solver::Triplets { nrows: m, ncols: m, rows, cols, vals }, rhs_vector)
+    }
+
+    // Rows of T_tilde for 2D editing. Each point's transformation is a rotation and
+    // uniform scale (s, w), fitted in the least squares sense to the point's neighborhood.
+    // Returns the points whose neighborhood was too degenerate for a fit
+    fn insert_planar_transforms(&self,
+				delta_vector: &na::MatrixMN::<f32, na::Dynamic, na::U1>,
+				rows: &mut Vec<usize>,
+				cols: &mut Vec<usize>,
+				vals: &mut Vec<f32>,
+				index_map: &mut BTreeMap<(usize, usize), usize>) -> Vec<usize> {
+	let n = self.original_points.len() / 2;
+	
+	let mut degenerate = Vec::new();
+	
 	#[allow(non_snake_case)]
 	for i in 0..n {
-	    let neighbors =
-		if i == 0 {
-		    vec![i, i + 1]
-		} else if i == n - 1 {
-		    vec![i - 1, i]
-		} else {
-		    vec![i - 1, i, i + 1]
-		};
+	    let mut neighbors = self.neighbors(i);
+	    neighbors.push(i);
 
 	    let un = neighbors.len();
 	    let mut C = na::Matrix::<f32, na::Dynamic, na::U2, _>::zeros(2 * un);
 
 	    let point_vector = &self.original_points;
 	    for j in 0..neighbors.len() {
-		C[(j, 0)] = point_vector[neighbors[j]];
-		C[(j, 1)] = point_vector[neighbors[j] + n];
+		C[(j, 0)] = point_vector[2 * neighbors[j]];
+		C[(j, 1)] = point_vector[2 * neighbors[j] + 1];
 
-		C[(j + un, 0)] = point_vector[neighbors[j] + n];
-		C[(j + un, 1)] = point_vector[neighbors[j]];
+		C[(j + un, 0)] = point_vector[2 * neighbors[j] + 1];
+		C[(j + un, 1)] = point_vector[2 * neighbors[j]];
 	    }
 
 	    let tmp1 = &C.transpose() * &C;
 	    
-	    let inn = tmp1.try_inverse();
-	    if inn == None {
-		println!("Matrix inversion failed");
-		::std::process::exit(-1);
-	    }
+	    let inn = match regularized_inverse(tmp1) {
+		Some(inn) => inn,
+		None => {
+		    degenerate.push(i);
+		    continue;
+		}
+	    };
+
+	    let M = &inn * &C.transpose();
 
-	    let M = &inn.unwrap() * &C.transpose();
+	    let dx = delta_vector[2 * i];
+	    let dy = delta_vector[2 * i + 1];
 
 	    for j in 0..neighbors.len() {
 		let neigh = neighbors[j];
 		
-		insert_triplet(&mut rows, &mut cols, &mut vals,
-			       i, neigh,
-			       delta_vector[i] * M[(0, j)] - delta_vector[i + n] * M[(1, j)],
-			       &mut index_map);
-		insert_triplet(&mut rows, &mut cols, &mut vals,
-			       i, neigh + n,
-			       delta_vector[i] * M[(0, j + un)] - delta_vector[i + n] * M[(1, j + un)], 
-			       &mut index_map);
-
-		insert_triplet(&mut rows, &mut cols, &mut vals,
-			       i + n, neigh,
-			       delta_vector[i + n] * M[(0, j)] + delta_vector[i] * M[(1, j)], 
-			       &mut index_map);
-		insert_triplet(&mut rows, &mut cols, &mut vals,
-			       i + n, neigh + n,
-			       delta_vector[i + n] * M[(0, j + un)] + delta_vector[i] * M[(1, j + un)], 
-			       &mut index_map); 
+		insert_triplet(rows, cols, vals,
+			       2 * i, 2 * neigh,
+			       dx * M[(0, j)] - dy * M[(1, j)],
+			       index_map);
+		insert_triplet(rows, cols, vals,
+			       2 * i, 2 * neigh + 1,
+			       dx * M[(0, j + un)] - dy * M[(1, j + un)], 
+			       index_map);
+
+		insert_triplet(rows, cols, vals,
+			       2 * i + 1, 2 * neigh,
+			       dy * M[(0, j)] + dx * M[(1, j)], 
+			       index_map);
+		insert_triplet(rows, cols, vals,
+			       2 * i + 1, 2 * neigh + 1,
+			       dy * M[(0, j + un)] + dx * M[(1, j + un)], 
+			       index_map); 
 	    }
 	}
 
-	let rhs_vector = na::Matrix::<f32, na::Dynamic, na::U1, _>::zeros(2 * n + 2 * fixed.len());
-
-	// We multiply the fixed points and their weights by a factor, so that they
-	// will prefer standing still even more
-	for i in 0..fixed.len() {
+	degenerate
+    }
 
-	    insert_triplet(&mut rows, &mut cols, &mut vals,
-			   2 * n + i, fixed[i], FIXED_POINT_FACTOR * 1.0, &mut index_map);
-	    insert_triplet(&mut rows, &mut cols, &mut vals,
-			   2 * n + fixed.len() + i, n + fixed[i], FIXED_POINT_FACTOR * 1.0,
-			   &mut index_map);
-	}
+    // Rows of T_tilde for 3D editing. As in Sorkine et al. (2004), the rotation is linearized,
+    // so each point's transformation is s * I + [h]_x with the unknowns (s, h1, h2, h3):
+    //   T * (x, y, z) = (s x - h3 y + h2 z,  h3 x + s y - h1 z,  -h2 x + h1 y + s z)
+    fn insert_spatial_transforms(&self,
+				 delta_vector: &na::MatrixMN::<f32, na::Dynamic, na::U1>,
+				 rows: &mut Vec<usize>,
+				 cols: &mut Vec<usize>,
+				 vals: &mut Vec<f32>,
+				 index_map: &mut BTreeMap<(usize, usize), usize>) -> Vec<usize> {
+	let n = self.original_points.len() / 3;
 	
-	let t_tilde = nsp::CsMatrix::from_triplet(2 * n + 2 * fixed.len(), 2 * n,
-						  &rows, &cols, &vals);
+	let mut degenerate = Vec::new();
 	
-	let system = &t_tilde + &(laplacian * (-1.0));
+	#[allow(non_snake_case)]
+	for i in 0..n {
+	    let mut neighbors = self.neighbors(i);
+	    neighbors.push(i);
+
+	    let un = neighbors.len();
+	    let mut C = na::Matrix::<f32, na::Dynamic, na::U4, _>::zeros(3 * un);
+
+	    let point_vector = &self.original_points;
+	    for j in 0..neighbors.len() {
+		let x = point_vector[3 * neighbors[j]];
+		let y = point_vector[3 * neighbors[j] + 1];
+		let z = point_vector[3 * neighbors[j] + 2];
 
-	// Now, we would like to compute a least square approximation of x in 
-	// system * x = rhs_vector
-	// Sadly, I don't think nalgebra has that feature, so we will just do it in the poor man's way
+		C[(j, 0)] = x;
+		C[(j, 2)] = z;
+		C[(j, 3)] = -y;
 
-	let ssh = system.transpose();
-	let system2 = &ssh * &system;
+		C[(j + un, 0)] = y;
+		C[(j + un, 1)] = -z;
+		C[(j + un, 3)] = x;
 
-	self.original_matrix_trans = ssh;
-	self.system_composition = nsp::CsCholesky::new(&system2);
-	self.rhs = rhs_vector;
-	self.fixed = fixed;
+		C[(j + 2 * un, 0)] = z;
+		C[(j + 2 * un, 1)] = y;
+		C[(j + 2 * un, 2)] = -x;
+	    }
+
+	    let tmp1 = &C.transpose() * &C;
+	    
+	    let inn = match regularized_inverse(tmp1) {
+		Some(inn) => inn,
+		None => {
+		    degenerate.push(i);
+		    continue;
+		}
+	    };
+
+	    let M = &inn * &C.transpose();
+
+	    let dx = delta_vector[3 * i];
+	    let dy = delta_vector[3 * i + 1];
+	    let dz = delta_vector[3 * i + 2];
+
+	    for j in 0..neighbors.len() {
+		let neigh = neighbors[j];
+
+		// Coordinate d of neighbor j is column j + d * un of M
+		for d in 0..3 {
+		    let c = j + d * un;
+
+		    insert_triplet(rows, cols, vals,
+				   3 * i, 3 * neigh + d,
+				   dx * M[(0, c)] + dz * M[(2, c)] - dy * M[(3, c)],
+				   index_map);
+		    insert_triplet(rows, cols, vals,
+				   3 * i + 1, 3 * neigh + d,
+				   dy * M[(0, c)] + dx * M[(3, c)] - dz * M[(1, c)],
+				   index_map);
+		    insert_triplet(rows, cols, vals,
+				   3 * i + 2, 3 * neigh + d,
+				   dz * M[(0, c)] + dy * M[(1, c)] - dx * M[(2, c)],
+				   index_map);
+		}
+	    }
+	}
+
+	degenerate
     }
 
     pub fn empty() -> LaplacianEditingSystem {
 	unsafe {
 	    LaplacianEditingSystem {
-		original_matrix_trans : nsp::CsMatrix::<f32, na::Dynamic>::new_uninitialized_generic(na::Dynamic::new(1), na::Dynamic::new(1), 0),
-		system_composition : nsp::CsCholesky::new_symbolic(&nsp::CsMatrix::<f32, na::Dynamic, na::Dynamic>::new_uninitialized_generic(na::Dynamic::new(1), na::Dynamic::new(1), 0)),
-		rhs:            na::MatrixMN::<f32, na::Dynamic, na::U1>::new_uninitialized_generic(na::Dynamic::new(1), na::U1),
+		solver:         solver::SolverBackend::default().create(),
+		shape_ready:    false,
+		shape_rhs:      na::MatrixMN::<f32, na::Dynamic, na::U1>::new_uninitialized_generic(na::Dynamic::new(1), na::U1),
+		edges:          Vec::new(),
 		original_points: na::MatrixMN::<f32, na::Dynamic, na::U1>::new_uninitialized_generic(na::Dynamic::new(1), na::U1),
 		fixed:          Vec::new(),
+		stiffness:      Vec::new(),
+		adjacency:      Vec::new(),
+		mode:           LaplacianMode::Planar,
+		method:         DeformationMethod::Linearized,
 	    }
 	}
     }
 }
 
 
+// Inverts the normal matrix of a transformation fit. Coincident or collinear neighborhoods
+// make it singular, in which case a small multiple of the identity is added before giving up
+fn regularized_inverse<D>(m: na::MatrixN<f32, D>) -> Option<na::MatrixN<f32, D>>
+where D: na::DimName,
+      na::DefaultAllocator: na::allocator::Allocator<f32, D, D> {
+    if let Some(inv) = m.clone().try_inverse() {
+	if inv.iter().all(|v| v.is_finite()) {
+	    return Some(inv);
+	}
+    }
+
+    let epsilon = REGULARIZATION_FACTOR * (m.trace() / D::dim() as f32).max(1e-6);
+    let inv = (m + na::MatrixN::<f32, D>::identity() * epsilon).try_inverse()?;
+
+    if inv.iter().all(|v| v.is_finite()) {
+	Some(inv)
+    } else {
+	None
+    }
+}
+
+// The rotation closest to the covariance of original and deformed segments,
+// as in the orthogonal Procrustes problem. Reflections are turned into rotations
+// by flipping the axis with the smallest singular value
+fn closest_rotation(covariance: na::Matrix3<f32>) -> na::Matrix3<f32> {
+    let svd = covariance.svd(true, true);
+    let (mut u, v_t) = match (svd.u, svd.v_t) {
+	(Some(u), Some(v_t)) => (u, v_t),
+	_ => return na::Matrix3::identity(),
+    };
+
+    let mut rotation = v_t.transpose() * u.transpose();
+    if rotation.determinant() < 0.0 {
+	let smallest = svd.singular_values.imin();
+	u.column_mut(smallest).neg_mut();
+	rotation = v_t.transpose() * u.transpose();
+    }
+
+    if rotation.iter().all(|v| v.is_finite()) {
+	rotation
+    } else {
+	na::Matrix3::identity()
+    }
+}
+
+// Point i of an interleaved coordinate vector. In planar mode, z is 0
+fn get_point(vector: &na::MatrixMN::<f32, na::Dynamic, na::U1>, i: usize, dims: usize) -> na::Vector3<f32> {
+    if dims == 3 {
+	na::Vector3::new(vector[3 * i], vector[3 * i + 1], vector[3 * i + 2])
+    } else {
+	na::Vector3::new(vector[2 * i], vector[2 * i + 1], 0.0)
+    }
+}
+
 // Here's a fun story:
 // Actual mature sparse linear algebra libraries (like builtin in Julia
 // or Eigen for C++), you may specify each row-col index multiple times in the
@@ -246,30 +712,65 @@ fn insert_triplet(rows: &mut Vec<usize>,
     }
 }
 
-pub fn setup_original_points(points : &Vec<glm::Vec3>) -> LaplacianEditingSystem {
+// Neighbors of the points of a single spline
+pub fn chain_adjacency(n : usize, closed : bool) -> Vec<Vec<usize>> {
+    (0..n).map(|i| {
+	if closed && n >= 3 {
+	    vec![(i + n - 1) % n, (i + 1) % n]
+	} else if n == 1 {
+	    vec![]
+	} else if i == 0 {
+	    vec![i + 1]
+	} else if i == n - 1 {
+	    vec![i - 1]
+	} else {
+	    vec![i - 1, i + 1]
+	}
+    }).collect()
+}
+
+pub fn setup_graph_points(points : &Vec<glm::Vec3>,
+			  adjacency : Vec<Vec<usize>>,
+			  mode : LaplacianMode,
+			  method : DeformationMethod,
+			  backend : solver::SolverBackend) -> LaplacianEditingSystem {
     
     let n = points.len();
-    let mut point_vector = na::MatrixMN::<f32, na::Dynamic, na::U1>::zeros(n * 2);
+    let dims = mode.dimensions();
+    let mut point_vector = na::MatrixMN::<f32, na::Dynamic, na::U1>::zeros(n * dims);
     for i in 0..n {
-	point_vector[i] = points[i].x;
-	point_vector[i + n] = points[i].y;
+	point_vector[i * dims] = points[i].x;
+	point_vector[i * dims + 1] = points[i].y;
+	if dims == 3 {
+	    point_vector[i * dims + 2] = points[i].z;
+	}
     }
 
     let mut system = LaplacianEditingSystem::empty();
     system.original_points = point_vector;
+    system.adjacency = adjacency;
+    system.mode = mode;
+    system.method = method;
+    system.solver = backend.create();
 
     system
 }
 
 pub fn setup_system (points : &Vec<glm::Vec3>,
-		     fixed : Vec<usize>)
-		     -> LaplacianEditingSystem {
+		     adjacency : Vec<Vec<usize>>,
+		     fixed : Vec<(usize, f32)>,
+		     stiffness : Vec<f32>,
+		     mode : LaplacianMode,
+		     method : DeformationMethod,
+		     backend : solver::SolverBackend)
+		     -> Result<LaplacianEditingSystem, LaplacianError> {
     
-    // Construct vector separating x- and y-coordinates
+    // Construct vector separating the coordinates
 
-    let mut final_system = setup_original_points(points);
+    let mut final_system = setup_graph_points(points, adjacency, mode, method, backend);
+    final_system.set_stiffness(stiffness);
     
-    final_system.setup_fixed_points(fixed);
+    final_system.setup_fixed_points(fixed)?;
 
-    final_system
+    Ok(final_system)
 }
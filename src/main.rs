@@ -19,14 +19,26 @@ mod cylinder;
 mod edit;
 mod utils;
 mod laplacian;
+mod solver;
 mod gui;
 mod annotation;
+mod stroke;
+mod oversketch;
+mod skeleton;
+mod gizmo;
+mod tessellation;
+mod export;
+mod noise;
+mod implicit;
+mod csg;
 
 pub struct Object {
     vao: gl::types::GLuint, 
     vbo: gl::types::GLuint,
     ebo: gl::types::GLuint,
     vertices: Vec<f32>,
+    normals: Vec<f32>,
+    colors: Vec<f32>,
     indices: Vec<u32>,
 }
 
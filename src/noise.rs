@@ -0,0 +1,191 @@
+extern crate glm;
+
+// Procedural noise for displacing cylinder surfaces. Every kind returns values in about
+// [-1, 1], and octaves of doubling frequency and halving amplitude are summed for detail.
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum NoiseKind {
+    Perlin,
+    Simplex,
+    Worley,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct NoiseSettings {
+    pub kind: NoiseKind,
+    pub frequency: f32, // Cycles per unit of length
+    pub amplitude: f32, // Largest displacement, in world units
+    pub octaves: usize,
+    pub seed: u32,
+}
+
+impl NoiseSettings {
+    pub fn new(kind: NoiseKind) -> NoiseSettings {
+	NoiseSettings { kind,
+			frequency: 20.0,
+			amplitude: 0.01,
+			octaves: 3,
+			seed: 0 }
+    }
+
+    // Wavelength of the finest octave, which the mesh has to resolve
+    pub fn get_feature_size(&self) -> f32 {
+	let octaves = self.octaves.max(1).min(16);
+	1.0 / (self.frequency.max(1e-3) * (1 << (octaves - 1)) as f32)
+    }
+
+    pub fn get_displacement(&self, p: glm::Vec3) -> f32 {
+	let mut value = 0.0;
+	let mut weight = 1.0;
+	let mut total_weight = 0.0;
+	let mut frequency = self.frequency;
+
+	for octave in 0..self.octaves.max(1).min(16) {
+	    let q = [p.x * frequency, p.y * frequency, p.z * frequency];
+	    let seed = self.seed.wrapping_add(octave as u32);
+
+	    value += weight * match self.kind {
+		NoiseKind::Perlin => perlin(q, seed),
+		NoiseKind::Simplex => simplex(q, seed),
+		NoiseKind::Worley => worley(q, seed),
+	    };
+
+	    total_weight += weight;
+	    weight *= 0.5;
+	    frequency *= 2.0;
+	}
+
+	self.amplitude * value / total_weight
+    }
+}
+
+fn mix(mut h: u32) -> u32 {
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846ca68b);
+    h ^= h >> 16;
+    h
+}
+
+fn hash(x: i32, y: i32, z: i32, seed: u32) -> u32 {
+    mix(seed.wrapping_mul(0x9e3779b9)
+	^ mix((x as u32).wrapping_mul(0x85ebca6b)
+	      ^ mix((y as u32).wrapping_mul(0xc2b2ae35)
+		    ^ mix((z as u32).wrapping_mul(0x27d4eb2f)))))
+}
+
+// A number in [0, 1) from a hash
+fn to_unit(h: u32) -> f32 {
+    (h >> 8) as f32 / (1 << 24) as f32
+}
+
+// Dot product with one of the twelve gradients towards the edges of a cube
+fn gradient(h: u32, x: f32, y: f32, z: f32) -> f32 {
+    match h % 12 {
+	0 => x + y,
+	1 => -x + y,
+	2 => x - y,
+	3 => -x - y,
+	4 => x + z,
+	5 => -x + z,
+	6 => x - z,
+	7 => -x - z,
+	8 => y + z,
+	9 => -y + z,
+	10 => y - z,
+	_ => -y - z,
+    }
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+fn perlin(p: [f32; 3], seed: u32) -> f32 {
+    let cell = [p[0].floor(), p[1].floor(), p[2].floor()];
+    let (x, y, z) = (p[0] - cell[0], p[1] - cell[1], p[2] - cell[2]);
+    let (i, j, k) = (cell[0] as i32, cell[1] as i32, cell[2] as i32);
+
+    let corner = |di: i32, dj: i32, dk: i32|
+	gradient(hash(i + di, j + dj, k + dk, seed), x - di as f32, y - dj as f32, z - dk as f32);
+
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+
+    lerp(lerp(lerp(corner(0, 0, 0), corner(1, 0, 0), u),
+	      lerp(corner(0, 1, 0), corner(1, 1, 0), u), v),
+	 lerp(lerp(corner(0, 0, 1), corner(1, 0, 1), u),
+	      lerp(corner(0, 1, 1), corner(1, 1, 1), u), v), w)
+}
+
+// Simplex noise, summing the four corners of the tetrahedron that contains the point
+fn simplex(p: [f32; 3], seed: u32) -> f32 {
+    const F3: f32 = 1.0 / 3.0;
+    const G3: f32 = 1.0 / 6.0;
+
+    let s = (p[0] + p[1] + p[2]) * F3;
+    let (i, j, k) = ((p[0] + s).floor() as i32, (p[1] + s).floor() as i32, (p[2] + s).floor() as i32);
+    let t = (i + j + k) as f32 * G3;
+    let x0 = [p[0] - (i as f32 - t), p[1] - (j as f32 - t), p[2] - (k as f32 - t)];
+
+    // Corners are visited in the order of the largest coordinates
+    let (o1, o2) = if x0[0] >= x0[1] {
+	if x0[1] >= x0[2] {
+	    ([1, 0, 0], [1, 1, 0])
+	} else if x0[0] >= x0[2] {
+	    ([1, 0, 0], [1, 0, 1])
+	} else {
+	    ([0, 0, 1], [1, 0, 1])
+	}
+    } else {
+	if x0[1] < x0[2] {
+	    ([0, 0, 1], [0, 1, 1])
+	} else if x0[0] < x0[2] {
+	    ([0, 1, 0], [0, 1, 1])
+	} else {
+	    ([0, 1, 0], [1, 1, 0])
+	}
+    };
+
+    let mut value = 0.0;
+    for (c, offset) in [[0, 0, 0], o1, o2, [1, 1, 1]].iter().enumerate() {
+	let g = c as f32 * G3;
+	let x = x0[0] - offset[0] as f32 + g;
+	let y = x0[1] - offset[1] as f32 + g;
+	let z = x0[2] - offset[2] as f32 + g;
+
+	let falloff = 0.6 - x * x - y * y - z * z;
+	if falloff > 0.0 {
+	    let h = hash(i + offset[0], j + offset[1], k + offset[2], seed);
+	    value += falloff.powi(4) * gradient(h, x, y, z);
+	}
+    }
+
+    32.0 * value
+}
+
+// Cellular noise from the distance to the nearest of one random point per cell.
+// Raised at the points and lowered at the borders between cells
+fn worley(p: [f32; 3], seed: u32) -> f32 {
+    let (i, j, k) = (p[0].floor() as i32, p[1].floor() as i32, p[2].floor() as i32);
+    let mut nearest = f32::INFINITY;
+
+    for di in -1..=1 {
+	for dj in -1..=1 {
+	    for dk in -1..=1 {
+		let h = hash(i + di, j + dj, k + dk, seed);
+		let point = [(i + di) as f32 + to_unit(h),
+			     (j + dj) as f32 + to_unit(mix(h)),
+			     (k + dk) as f32 + to_unit(mix(mix(h)))];
+		let (x, y, z) = (p[0] - point[0], p[1] - point[1], p[2] - point[2]);
+		nearest = nearest.min(x * x + y * y + z * z);
+	    }
+	}
+    }
+
+    (1.0 - 2.0 * nearest.sqrt()).max(-1.0)
+}
@@ -20,9 +20,13 @@ pub fn create_vbo(vertices: &Vec<f32>)
     vbo
 }
 
+// The attributes are interleaved in the bound vertex buffer, in the given order
 pub fn create_vao(attrib_sizes : Vec<u32>)
                   -> gl::types::GLuint {
     let mut vao: gl::types::GLuint = 0;
+    let stride : u32 = attrib_sizes.iter().sum();
+    let mut offset : u32 = 0;
+
     unsafe {
         gl::GenVertexArrays(1, &mut vao);
         gl::BindVertexArray(vao);
@@ -30,11 +34,12 @@ pub fn create_vao(attrib_sizes : Vec<u32>)
         for (index, elem) in attrib_sizes.iter().enumerate() {
             gl::VertexAttribPointer(
                 index as u32, *elem as i32, gl::FLOAT,
-                gl::FALSE, (*elem as usize * std::mem::size_of::<f32>()) as gl::types::GLint,
-                std::ptr::null());
+                gl::FALSE, (stride as usize * std::mem::size_of::<f32>()) as gl::types::GLint,
+                (offset as usize * std::mem::size_of::<f32>()) as *const gl::types::GLvoid);
         
             gl::EnableVertexAttribArray(index as u32);
-                
+
+            offset += *elem;
         }
     }
 
@@ -58,10 +63,75 @@ pub fn create_ebo(indices : &Vec<u32>)
     ebo
 }
 
+// Smooth normals, averaged over the triangles around each vertex weighted by their area
+pub fn compute_normals(vertices: &Vec<f32>,
+		       indices: &Vec<u32>) -> Vec<f32> {
+    let mut normals : Vec<f32> = vec![0.0; vertices.len()];
+    let get = |i: u32| glm::vec3(vertices[3 * i as usize + 0],
+				 vertices[3 * i as usize + 1],
+				 vertices[3 * i as usize + 2]);
+
+    for t in indices.chunks(3) {
+	if t.len() < 3 {
+	    break;
+	}
+
+	// The cross product is twice the area of the triangle
+	let n = glm::builtin::cross(get(t[1]) - get(t[0]), get(t[2]) - get(t[0]));
+	for i in t {
+	    normals[3 * *i as usize + 0] += n.x;
+	    normals[3 * *i as usize + 1] += n.y;
+	    normals[3 * *i as usize + 2] += n.z;
+	}
+    }
+
+    for n in normals.chunks_mut(3) {
+	let l = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt();
+	if l > 1e-12 {
+	    n[0] /= l;
+	    n[1] /= l;
+	    n[2] /= l;
+	}
+    }
+
+    normals
+}
+
+// Positions, normals and colors in the layout of create_vao(vec![3, 3, 3])
+fn interleave(vertices: &Vec<f32>,
+	      normals: &Vec<f32>,
+	      colors: &Vec<f32>) -> Vec<f32> {
+    let mut data : Vec<f32> = Vec::with_capacity(vertices.len() * 3);
+
+    for ((p, n), c) in vertices.chunks(3).zip(normals.chunks(3)).zip(colors.chunks(3)) {
+	data.extend_from_slice(p);
+	data.extend_from_slice(n);
+	data.extend_from_slice(c);
+    }
+
+    data
+}
+
 pub fn create_object(vertices: Vec<f32>,
 		     indices: Vec<u32>) -> Object {
-    let vbo = create_vbo(&vertices);
-    let vao = create_vao(vec![3]);
+    let normals = compute_normals(&vertices, &indices);
+    create_object_with_normals(vertices, normals, indices)
+}
+
+// Objects are white unless given colors
+pub fn create_object_with_normals(vertices: Vec<f32>,
+				  normals: Vec<f32>,
+				  indices: Vec<u32>) -> Object {
+    let colors = vec![1.0; vertices.len()];
+    create_object_with_colors(vertices, normals, colors, indices)
+}
+
+pub fn create_object_with_colors(vertices: Vec<f32>,
+				 normals: Vec<f32>,
+				 colors: Vec<f32>,
+				 indices: Vec<u32>) -> Object {
+    let vbo = create_vbo(&interleave(&vertices, &normals, &colors));
+    let vao = create_vao(vec![3, 3, 3]);
     let ebo = create_ebo(&indices);
 
     unsafe {
@@ -70,7 +140,7 @@ pub fn create_object(vertices: Vec<f32>,
 	gl::BindVertexArray(0);
     }
 
-    Object { vbo, ebo, vao, vertices: vertices, indices: indices } 
+    Object { vbo, ebo, vao, vertices, normals, colors, indices } 
 }
 
 impl Drop for Object {
@@ -85,13 +155,24 @@ impl Drop for Object {
 
 
 impl Object {
+    // Normals are recomputed and colors reset to white if they do not match the vertices
     pub fn update_gpu_state(self : &mut Object) {
+	if self.normals.len() != self.vertices.len() {
+	    self.normals = compute_normals(&self.vertices, &self.indices);
+	}
+
+	if self.colors.len() != self.vertices.len() {
+	    self.colors = vec![1.0; self.vertices.len()];
+	}
+
+	let data = interleave(&self.vertices, &self.normals, &self.colors);
+
         unsafe {
             gl::BindBuffer(gl::ARRAY_BUFFER, self.vbo);
             gl::BufferData(
                 gl::ARRAY_BUFFER,
-                (self.vertices.len() * std::mem::size_of::<f32>()) as gl::types::GLsizeiptr,
-                self.vertices.as_ptr() as *const gl::types::GLvoid,
+                (data.len() * std::mem::size_of::<f32>()) as gl::types::GLsizeiptr,
+                data.as_ptr() as *const gl::types::GLvoid,
                 gl::DYNAMIC_DRAW);
 
             gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, self.ebo);
@@ -208,7 +289,10 @@ pub fn create_sphere_object(radius: f32,
             iresolution * 2 * (iresolution - 2) + ((ii + 1) % (2 * iresolution));
     }
 
-    create_object(data, indices)
+    // Every vertex lies on the sphere, so its normal points away from the center
+    let normals : Vec<f32> = data.iter().map(|x| x / radius).collect();
+
+    create_object_with_normals(data, normals, indices)
 }
 
 
@@ -0,0 +1,193 @@
+extern crate glm;
+
+use crate::program;
+use crate::edit;
+use crate::cylinder;
+use crate::splinedraw;
+use crate::annotation;
+use crate::skeleton;
+
+use glm::builtin::*;
+
+// How close (in normalized window coordinates) the ends of an oversketch stroke
+// must be to the spline for the stroke to be used
+static OVERSKETCH_SENSITIVITY: f32 = 0.1;
+
+// Number of points on each side of a seam that are smoothed after replacement
+static BLEND_WIDTH: usize = 3;
+static BLEND_ITERATIONS: usize = 4;
+
+fn closest_point(points: &Vec<glm::Vec3>, p: glm::Vec3) -> (usize, f32) {
+    let mut mind = 1e9;
+    let mut mindi = 0;
+
+    for i in 0..points.len() {
+	let d = length(glm::vec3(points[i].x - p.x, points[i].y - p.y, 0.0));
+	if d < mind {
+	    mind = d;
+	    mindi = i;
+	}
+    }
+
+    (mindi, mind)
+}
+
+// Smooths the points around a seam, with weights falling off with the distance from it
+fn blend_seam(points: &mut Vec<glm::Vec3>, seam: usize, closed: bool) {
+    let n = points.len();
+
+    for _ in 0..BLEND_ITERATIONS {
+	let old = points.clone();
+
+	for k in 0..(2 * BLEND_WIDTH + 1) {
+	    let i = seam as i32 + k as i32 - BLEND_WIDTH as i32;
+
+	    let i = if closed {
+		i.rem_euclid(n as i32) as usize
+	    } else if i <= 0 || i >= n as i32 - 1 {
+		// The ends of open splines stay where they are
+		continue;
+	    } else {
+		i as usize
+	    };
+
+	    let prev = old[(i + n - 1) % n];
+	    let next = old[(i + 1) % n];
+
+	    let weight = 1.0 - (k as f32 - BLEND_WIDTH as f32).abs() / (BLEND_WIDTH + 1) as f32;
+	    let smoothed = (prev + next) * 0.5;
+
+	    points[i] = old[i] + (smoothed - old[i]) * (0.5 * weight);
+	}
+    }
+}
+
+// Maps an old control point index onto the new point list, where the points
+// first..=last have been replaced by num_new points
+fn remap_index(index: usize, first: usize, last: usize, num_new: usize) -> usize {
+    if index < first {
+	index
+    } else if index > last {
+	index - (last - first + 1) + num_new
+    } else {
+	let t = (index - first) as f32 / (last - first) as f32;
+	first + (t * (num_new - 1) as f32).round() as usize
+    }
+}
+
+// Replaces the part of the spline between the points closest to the stroke's start and end
+// by the stroke. The stroke is given in normalized window coordinates.
+// Junctions to the cylinder (at index cylinder_index) follow their control points.
+// Returns false if the stroke was not close enough to the spline.
+pub fn apply_oversketch(cylinder: &mut cylinder::GeneralizedCylinder,
+			cylinder_index: usize,
+			annotations: &mut Vec<Box<dyn annotation::Annotation>>,
+			junctions: &mut Vec<skeleton::Junction>,
+			stroke: &Vec<glm::Vec3>) -> bool {
+
+    let mut control_points = cylinder.spline.control_points.clone();
+    let n = control_points.len();
+
+    if stroke.len() < 2 || n < 2 {
+	return false;
+    }
+
+    // Convert to world coordinates
+    let mut stroke : Vec<glm::Vec3> = stroke.iter().map(|p| glm::vec3(p.x, -p.y, 0.0)).collect();
+
+    let (mut first, first_distance) = closest_point(&control_points, stroke[0]);
+    let (mut last, last_distance) = closest_point(&control_points, stroke[stroke.len() - 1]);
+
+    if first_distance > OVERSKETCH_SENSITIVITY ||
+	last_distance > OVERSKETCH_SENSITIVITY ||
+	first == last {
+	    return false;
+	}
+
+    // The stroke may be drawn in either direction
+    if first > last {
+	stroke.reverse();
+	std::mem::swap(&mut first, &mut last);
+    }
+
+    // Closed splines get the shorter way round replaced, as in select_between. When that
+    // crosses the seam, the points are rotated to start at its far end, which leaves the
+    // curve as it was
+    let mut rotation = 0;
+    if cylinder.spline.closed && n - (last - first) < last - first {
+	rotation = last;
+	control_points.rotate_left(rotation);
+	stroke.reverse();
+	last = first + n - rotation;
+	first = 0;
+    }
+    let rotate = |index: usize| (index + n - rotation) % n;
+
+    // Keep the depth of the replaced part, interpolated between the two seams
+    let z_first = control_points[first].z;
+    let z_last = control_points[last].z;
+    let m = stroke.len();
+    for i in 0..m {
+	let t = i as f32 / (m - 1) as f32;
+	stroke[i].z = z_first * (1.0 - t) + z_last * t;
+    }
+
+    let mut new_points : Vec<glm::Vec3> = Vec::with_capacity(control_points.len() - (last - first + 1) + m);
+    new_points.extend_from_slice(&control_points[..first]);
+    new_points.extend_from_slice(&stroke);
+    new_points.extend_from_slice(&control_points[(last + 1)..]);
+
+    let closed = cylinder.spline.closed;
+    blend_seam(&mut new_points, first, closed);
+    blend_seam(&mut new_points, first + m - 1, closed);
+
+    for ann in annotations.iter_mut() {
+	let index = remap_index(rotate(ann.get_render_index()), first, last, m);
+	ann.set_render_index(index);
+    }
+
+    for junction in junctions.iter_mut() {
+	if junction.child == cylinder_index {
+	    junction.child_index = remap_index(rotate(junction.child_index), first, last, m);
+	}
+	if junction.parent == cylinder_index {
+	    junction.parent_index = remap_index(rotate(junction.parent_index), first, last, m);
+	}
+    }
+
+    cylinder.spline.point_colors = vec![glm::vec4(0.0, 0.0, 0.0, 1.0); new_points.len()];
+    cylinder.spline.control_points = new_points;
+    cylinder.spline.update_gpu_state();
+
+    true
+}
+
+pub fn handle_edit_oversketch(input_state: &program::InputState,
+			      edit_state: &mut edit::EditState,
+			      session: &mut program::Session) {
+    splinedraw::handle_spline_draw(&input_state.mouse_state,
+				   &input_state.gui_state.stroke_settings,
+				   &mut edit_state.oversketch_stroke);
+
+    // Apply the stroke once the user lets go of the mouse button
+    if !input_state.mouse_state.button1_pressed &&
+	input_state.mouse_state.button1_was_pressed {
+	    edit_state.clear_selected(&mut session.cylinders);
+
+	    let stroke = edit_state.oversketch_stroke.control_points.clone();
+
+	    let applied = apply_oversketch(&mut session.cylinders[edit_state.curr_cylinder],
+					   edit_state.curr_cylinder,
+					   &mut session.annotations[edit_state.curr_cylinder],
+					   &mut session.junctions,
+					   &stroke);
+
+	    edit_state.solver_warning = if applied {
+		None
+	    } else {
+		Some(String::from("Oversketch strokes must start and end near the spline"))
+	    };
+
+	    edit_state.oversketch_stroke = splinedraw::SplineState::new();
+	}
+}
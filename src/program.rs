@@ -9,6 +9,8 @@ use std::ffi::{CString};
 use std::f32;
 use std::mem;
 use std::cell::Cell;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 
 use crate::settings;
 use crate::shaders;
@@ -20,9 +22,17 @@ use crate::utils;
 use crate::edit;
 use crate::gui;
 use crate::annotation;
+use crate::stroke;
+use crate::laplacian;
+use crate::solver;
+use crate::skeleton;
+use crate::tessellation;
+use crate::implicit;
+use crate::csg;
 
 pub struct MouseState {
     pub pos: glm::Vec2,
+    pub time: f64, // Time of the last cursor event
     pub button1_pressed: bool,
     pub button1_was_pressed: bool,
     pub in_window: bool,
@@ -43,6 +53,27 @@ pub enum ProgramState {
 pub struct Session {
     pub cylinders: Vec<cylinder::GeneralizedCylinder>,
     pub annotations: Vec<Vec<Box<dyn annotation::Annotation>>>, // One vector per cylinder
+    pub junctions: Vec<skeleton::Junction>,
+    pub blended: Option<implicit::BlendedSurface>, // Shown and exported instead of the cylinders
+    pub blended_fingerprint: Option<u64>, // Of the cylinders and settings it was last made from
+    pub carved: Option<csg::CarvedSurface>, // Shown instead of the cylinders while some are subtractive
+    pub carved_fingerprint: Option<u64>,
+}
+
+// Changes whenever the mesh, colors or mode of a cylinder do
+pub fn get_fingerprint(session: &Session) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for cylinder in &session.cylinders {
+	(cylinder.get_mode() == cylinder::CombineMode::Add).hash(&mut hasher);
+	cylinder.object.indices.hash(&mut hasher);
+	for v in &cylinder.object.vertices {
+	    v.to_bits().hash(&mut hasher);
+	}
+	for c in &cylinder.object.colors {
+	    c.to_bits().hash(&mut hasher);
+	}
+    }
+    hasher.finish()
 }
 
 pub static PS_DRAW_NUM : usize = 0;
@@ -62,6 +93,8 @@ impl ProgramState {
 // What keys are pressed?
 pub struct KeyState {
     pub enter: bool,
+    pub shift: bool,
+    pub ctrl: bool,
 }
 
 pub struct InputState {
@@ -95,20 +128,32 @@ pub fn setup_objects() -> ModelerState {
 pub fn handle_gui_update(session: &mut Session,
 			 program_state: &mut ProgramState,
 			 mut glfw_state: &mut crate::GLFWState,
+			 key_state: &KeyState,
 			 mut gui_state: &mut gui::GUIState) {
     let old_gui_state = gui_state.clone();
     let old_program_num = program_state.to_num();
-    gui::run_gui(session, program_state, &mut glfw_state, &mut gui_state);
+    gui::run_gui(session, program_state, &mut glfw_state, key_state, &mut gui_state);
 
     match program_state {
 	ProgramState::Edit(ref mut edit_state) => {
 	    if gui_state.using_peeling != old_gui_state.using_peeling ||
+		gui_state.using_oversketch != old_gui_state.using_oversketch ||
+		gui_state.laplacian_mode != old_gui_state.laplacian_mode ||
+		gui_state.deformation_method != old_gui_state.deformation_method ||
+		gui_state.solver_backend != old_gui_state.solver_backend ||
 		PS_EDIT_NUM != old_program_num {
 		    edit_state.clear_selected(&mut session.cylinders);
 		}
 	},
 	_ => {}
     }
+
+    if gui_state.tessellation_settings != old_gui_state.tessellation_settings {
+	for c in 0..session.cylinders.len() {
+	    session.cylinders[c].set_tessellation(gui_state.tessellation_settings);
+	    session.cylinders[c].update_mesh(&session.annotations[c]);
+	}
+    }
 }
 
 
@@ -119,28 +164,93 @@ fn handle_draw_operation(mut spline_state : &mut splinedraw::SplineState,
 	if spline_state.control_points.len() >= 2 {
 	    let mut tmp_spline = splinedraw::SplineState::new();
 	    mem::swap(&mut tmp_spline, spline_state);
-	    let cylinder_object = cylinder::create_cylinder(0.1, 5, tmp_spline);
+	    splinedraw::detect_closed_loop(&mut tmp_spline);
+	    let mut cylinder_object = cylinder::create_cylinder(cylinder::DEFAULT_RADIUS,
+								cylinder::DEFAULT_CIRC_RESOLUTION,
+								tmp_spline);
+	    cylinder_object.set_name(&format!("Cylinder {}", session.cylinders.len()));
+	    cylinder_object.set_tessellation(input_state.gui_state.tessellation_settings);
 	    // *spline_state = splinedraw::SplineState::new();
 
 	    return Some(cylinder_object);
 	}
     } else {
-	splinedraw::handle_spline_draw(&input_state.mouse_state, &mut spline_state);
+	splinedraw::handle_spline_draw(&input_state.mouse_state,
+				       &input_state.gui_state.stroke_settings,
+				       &mut spline_state);
     }
 
     None
 }
 			 
 
+// The blended surface replaces the bodies of the cylinders, whose splines are still shown
+fn draw_session(session: &Session,
+		body_program: &shaders::ShaderProgram,
+		lit_program: &shaders::ShaderProgram,
+		line_program: &shaders::ShaderProgram,
+		trans: &glm::Mat4,
+		shading: cylinder::ShadingMode,
+		eye: glm::Vec3) {
+    match &session.blended {
+	Some(blended) => {
+	    if shading == cylinder::ShadingMode::Toon {
+		cylinder::draw_toon_body(&blended.object, &blended.line_object, body_program, trans);
+	    } else {
+		cylinder::draw_lit_body(&blended.object, lit_program, trans, shading, eye);
+	    }
+
+	    for c in &session.cylinders {
+		cylinder::draw_spline_overlay(&c, line_program, trans);
+	    }
+	},
+	None => {
+	    if let Some(carved) = &session.carved {
+		if shading == cylinder::ShadingMode::Toon {
+		    cylinder::draw_toon_body(&carved.object, &carved.line_object, body_program, trans);
+		} else {
+		    cylinder::draw_lit_body(&carved.object, lit_program, trans, shading, eye);
+		}
+	    }
+
+	    // Subtractive cylinders only show their splines, and the carving. Open ones are
+	    // not carved, and drawn as they are
+	    for c in &session.cylinders {
+		if (session.carved.is_some() && c.is_closed()) || c.get_mode() == cylinder::CombineMode::Subtract {
+		    cylinder::draw_spline_overlay(&c, line_program, trans);
+		} else {
+		    cylinder::draw_cylinder(&c, body_program, lit_program, line_program, trans, shading, eye);
+		}
+	    }
+	}
+    }
+}
+
 pub fn run_loop(mut glfw_state: GLFWState, modeler_state: ModelerState) {
 
     let mouse_state = MouseState { pos: glm::vec2(0.0, 0.0),
+				   time: 0.0,
 				       button1_pressed: false,
 				       button1_was_pressed: false,
 				       in_window: true, };
-    let key_state = KeyState { enter: false, };
+    let key_state = KeyState { enter: false, shift: false, ctrl: false };
     let gui_state = gui::GUIState { using_peeling: false,
-				    used_mouse: false };
+				    using_oversketch: false,
+				    using_gizmo: false,
+				    laplacian_mode: laplacian::LaplacianMode::Planar,
+				    deformation_method: laplacian::DeformationMethod::Linearized,
+				    solver_backend: solver::SolverBackend::default(),
+				    selection_tool: edit::SelectionTool::Points,
+				    annotation_selection: annotation::AnnotationKind::Size,
+				    constraint_settings: laplacian::ConstraintSettings::new(),
+				    used_mouse: false,
+				    stroke_settings: stroke::StrokeSettings::new(),
+				    tessellation_settings: tessellation::TessellationSettings::new(),
+				    implicit_settings: implicit::ImplicitSettings::new(),
+				    shading_mode: cylinder::ShadingMode::Toon,
+				    export_path: String::from("model"),
+				    export_union: false,
+				    export_status: String::new() };
 
     let mut input_state = InputState { mouse_state, key_state, gui_state };
 
@@ -181,6 +291,11 @@ pub fn run_loop(mut glfw_state: GLFWState, modeler_state: ModelerState) {
 	&CString::new(include_str!("shaders/line.frag")).unwrap()
     ).unwrap();
 
+    let lit_program = shaders::create_simple_shader(
+	&CString::new(include_str!("shaders/lit.vert")).unwrap(),
+	&CString::new(include_str!("shaders/lit.frag")).unwrap()
+    ).unwrap();
+
     
     shader_program.activate();
 
@@ -190,7 +305,12 @@ pub fn run_loop(mut glfw_state: GLFWState, modeler_state: ModelerState) {
     let mut program_state = ProgramState::Draw;
 
     let mut session = Session { cylinders: Vec::new(),
-				annotations: Vec::new(), };
+				annotations: Vec::new(),
+				junctions: Vec::new(),
+				blended: None,
+				blended_fingerprint: None,
+				carved: None,
+				carved_fingerprint: None, };
         
     // Loop until the user closes the window
     while !glfw_state.window.should_close() {
@@ -207,10 +327,11 @@ pub fn run_loop(mut glfw_state: GLFWState, modeler_state: ModelerState) {
 	// 100.0);
 	let proj = utils::ortho(-1.0, 1.0, -1.0, 1.0, -100.0, 100.0);
 	
+	let eye = glm::vec3(r * th.sin() * phi.cos(),
+			    -r * phi.sin(),
+			    r * th.cos() * phi.cos());
 	let trans = proj *
-	    glm::ext::look_at(glm::vec3(r * th.sin() * phi.cos(),
-					-r * phi.sin(),
-					r * th.cos() * phi.cos()),
+	    glm::ext::look_at(eye,
 			      glm::vec3(0.0, 0.0, 0.0),
 			      glm::vec3(0.0, 1.0, 0.0));
 
@@ -218,30 +339,43 @@ pub fn run_loop(mut glfw_state: GLFWState, modeler_state: ModelerState) {
 	    gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
 	}
 
+	// The blended surface and the carving follow the edits of the cylinders once the
+	// mouse is let go, so that dragging doesn't remake them every frame. The last ones
+	// are shown until then
+	if glfw_state.window.get_mouse_button(glfw::MouseButton::Button1) == Action::Release {
+	    implicit::update_blended_surface(&mut session, &input_state.gui_state.implicit_settings);
+	    if session.blended.is_none() {
+		csg::update_carved_surface(&mut session);
+	    }
+	}
+
 	shader_program.activate();
 	match program_state {
-	    ProgramState::Edit(ref edit_state) => {
-		cylinder::draw_cylinder(&session.cylinders[edit_state.curr_cylinder],
-					&shader_program,
-					&world_line_program,
-					&trans);
-		
+	    ProgramState::Draw | ProgramState::Edit(_) => {
+		// Other cylinders are shown so new ones can be attached to them
+		draw_session(&session,
+			     &shader_program,
+			     &lit_program,
+			     &world_line_program,
+			     &trans,
+			     input_state.gui_state.shading_mode,
+			     eye);
 	    },
 	    ProgramState::Annotate(ref annotation_state) => {
 
-		for c in &session.cylinders {
-		    cylinder::draw_cylinder(&c,
-					    &shader_program,
-					    &world_line_program,
-					    &trans);
-		}
+		draw_session(&session,
+			     &shader_program,
+			     &lit_program,
+			     &world_line_program,
+			     &trans,
+			     input_state.gui_state.shading_mode,
+			     eye);
 		
 		annotation::draw_annotations(&session,
 					     &annotation_state,
 					     &world_line_program,
 					     &trans);
 	    },
-	    _ => {}
 	}
 
 	// Handle GUI
@@ -249,6 +383,7 @@ pub fn run_loop(mut glfw_state: GLFWState, modeler_state: ModelerState) {
 	handle_gui_update(&mut session,
 			  &mut program_state,
 			  &mut glfw_state,
+			  &input_state.key_state,
 			  &mut input_state.gui_state);
 	
 	// Poll for and process events
@@ -258,7 +393,7 @@ pub fn run_loop(mut glfw_state: GLFWState, modeler_state: ModelerState) {
 
 	input_state.mouse_state.tick();
 	
-        for (_, event) in flushed_events {
+        for (time, event) in flushed_events {
 
 	    glfw_state.imgui_glfw_context.handle_event(&mut glfw_state.imgui_context, &event);
 	    
@@ -274,9 +409,18 @@ pub fn run_loop(mut glfw_state: GLFWState, modeler_state: ModelerState) {
 			    Action::Repeat => input_state.key_state.enter
 			};
 		},
+		glfw::WindowEvent::Key(Key::LeftShift, _, action, _) |
+		glfw::WindowEvent::Key(Key::RightShift, _, action, _) => {
+		    input_state.key_state.shift = action != Action::Release;
+		},
+		glfw::WindowEvent::Key(Key::LeftControl, _, action, _) |
+		glfw::WindowEvent::Key(Key::RightControl, _, action, _) => {
+		    input_state.key_state.ctrl = action != Action::Release;
+		},
 		glfw::WindowEvent::CursorPos(x, y) => {
 		    if !input_state.gui_state.used_mouse {
 			input_state.mouse_state.pos = glm::vec2(x as f32, y as f32);
+			input_state.mouse_state.time = time;
 		    }
 		},
 		glfw::WindowEvent::CursorEnter(bb) => {
@@ -306,14 +450,22 @@ pub fn run_loop(mut glfw_state: GLFWState, modeler_state: ModelerState) {
 						       &input_state,
 						       &session);
 		match o_cylinder {
-		    Some(cylinder) => {
+		    Some(mut cylinder) => {
 			let mut edit = edit::EditState::new();
+			let index = session.cylinders.len();
+
+			// Ends drawn close to existing cylinders are attached to them
+			let junctions = skeleton::attach_ends(index, &mut cylinder.spline, &session.cylinders);
 			
-			annotation::push_default_annotations(session.cylinders.len(),
+			annotation::push_default_annotations(index,
 							     cylinder.spline.control_points.len(),
 							     &mut session.annotations);
+			// The first mesh is made without annotations or tessellation settings
+			cylinder.update_mesh(&session.annotations[index]);
+			
 			session.cylinders.push(cylinder);
-			edit.curr_cylinder = 0;
+			session.junctions.extend(junctions);
+			edit.curr_cylinder = index;
 			program_state = ProgramState::Edit(edit);
 
 		    },
@@ -329,6 +481,24 @@ pub fn run_loop(mut glfw_state: GLFWState, modeler_state: ModelerState) {
 					    &input_state,
 					    edit_state,
 					    &mut session);
+
+		// Stroke currently being oversketched
+		if edit_state.oversketch_stroke.control_points.len() >= 2 {
+		    screen_line_program.activate();
+		    edit_state.oversketch_stroke.update_gpu_state();
+		    splinedraw::draw_spline_lines(&edit_state.oversketch_stroke);
+		}
+
+		if input_state.gui_state.using_gizmo && !input_state.gui_state.using_oversketch {
+		    edit_state.gizmo.draw(&world_line_program, &trans);
+		}
+
+		// Box or lasso currently being drawn
+		if edit_state.selection_outline.control_points.len() >= 2 {
+		    screen_line_program.activate();
+		    edit_state.selection_outline.update_gpu_state();
+		    splinedraw::draw_spline_lines(&edit_state.selection_outline);
+		}
 	    },
 	    ProgramState::Annotate(ref mut annotation_state) => {
 		annotation::handle_annotation(&proj,
@@ -1,11 +1,17 @@
 #version 430 core
 
+in vec3 frag_color;
+
 out vec4 color;
 
 uniform vec4 uni_color;
+uniform int vertex_colors; // Multiplies the color with the color of the vertices
 
 void main()
 {
     color = uni_color;
+    if (vertex_colors != 0) {
+	color.rgb *= frag_color;
+    }
     // color = vec4(0.0, 1.0, 0.0, 1.0);
 }
\ No newline at end of file
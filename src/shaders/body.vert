@@ -1,6 +1,9 @@
 #version 430 core
 
-in vec3 position;
+layout(location = 0) in vec3 position;
+layout(location = 2) in vec3 vertex_color; // Only used by meshes with colors
+
+out vec3 frag_color;
 
 uniform mat4 trans;
 uniform vec4 displacement;
@@ -8,4 +11,6 @@ uniform vec4 displacement;
 void main()
 {
     gl_Position = trans * vec4(position, 1.0f) + displacement;
+
+    frag_color = vertex_color;
 }
\ No newline at end of file
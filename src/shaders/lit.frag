@@ -0,0 +1,31 @@
+#version 430 core
+
+in vec3 frag_normal;
+in vec3 frag_color;
+
+out vec4 color;
+
+uniform vec4 uni_color;
+uniform vec3 light_dir; // Towards the light, in world coordinates
+uniform vec3 view_dir;  // Towards the viewer, in world coordinates
+uniform int blinn;      // Adds a Blinn-Phong highlight to the Lambert shading
+
+const float ambient = 0.2;
+const float specular = 0.4;
+const float shininess = 32.0;
+
+void main()
+{
+    vec3 n = normalize(frag_normal);
+    vec3 l = normalize(light_dir);
+
+    float diffuse = max(dot(n, l), 0.0);
+    vec3 shade = uni_color.rgb * frag_color * (ambient + (1.0 - ambient) * diffuse);
+
+    if (blinn != 0 && diffuse > 0.0) {
+	vec3 h = normalize(l + normalize(view_dir));
+	shade += vec3(specular * pow(max(dot(n, h), 0.0), shininess));
+    }
+
+    color = vec4(shade, uni_color.a);
+}
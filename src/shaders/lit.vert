@@ -0,0 +1,18 @@
+#version 430 core
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 normal;
+layout(location = 2) in vec3 vertex_color;
+
+out vec3 frag_normal;
+out vec3 frag_color;
+
+uniform mat4 trans;
+
+void main()
+{
+    gl_Position = trans * vec4(position, 1.0f);
+
+    frag_normal = normal;
+    frag_color = vertex_color;
+}
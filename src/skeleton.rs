@@ -0,0 +1,276 @@
+extern crate glm;
+
+use crate::cylinder;
+use crate::splinedraw;
+use crate::annotation;
+use crate::laplacian;
+
+use glm::builtin::*;
+
+use std::collections::VecDeque;
+
+// Cylinders attached to each other at junctions form a skeleton graph, which is
+// edited as one Laplacian system. An attached end point of a child cylinder shares
+// its graph node with a control point of the parent cylinder.
+
+// How close (in world coordinates) the end of a new stroke must be to an
+// existing control point to be attached to it
+static SNAP_DISTANCE: f32 = 0.05;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Junction {
+    pub child: usize,        // Cylinder whose end point is attached
+    pub child_index: usize,  // First or last control point of the child
+    pub parent: usize,
+    pub parent_index: usize,
+}
+
+pub struct SkeletonGraph {
+    pub points: Vec<glm::Vec3>,
+    pub adjacency: Vec<Vec<usize>>,
+    pub cylinders: Vec<usize>,   // Cylinders in the graph, starting with the one it was built from
+    nodes: Vec<Vec<usize>>,      // Node of each control point, for each entry in cylinders
+}
+
+impl SkeletonGraph {
+    pub fn empty() -> SkeletonGraph {
+	SkeletonGraph { points: Vec::new(),
+			adjacency: Vec::new(),
+			cylinders: Vec::new(),
+			nodes: Vec::new() }
+    }
+
+    pub fn node(&self, cylinder: usize, index: usize) -> Option<usize> {
+	let c = self.cylinders.iter().position(|c| *c == cylinder)?;
+	self.nodes[c].get(index).cloned()
+    }
+
+    // Number of edges from the closest of the sources to each node
+    pub fn distances(&self, sources: &Vec<usize>) -> Vec<usize> {
+	let mut distances = vec![usize::max_value(); self.points.len()];
+	let mut queue : VecDeque<usize> = VecDeque::new();
+
+	for s in sources {
+	    distances[*s] = 0;
+	    queue.push_back(*s);
+	}
+
+	while let Some(node) = queue.pop_front() {
+	    for neighbor in &self.adjacency[node] {
+		if distances[*neighbor] == usize::max_value() {
+		    distances[*neighbor] = distances[node] + 1;
+		    queue.push_back(*neighbor);
+		}
+	    }
+	}
+
+	distances
+    }
+
+    // Per-node stiffness from the annotations of all cylinders in the graph
+    pub fn get_stiffness(&self, annotations: &Vec<Vec<Box<dyn annotation::Annotation>>>) -> Vec<f32> {
+	let mut stiffness = vec![1.0; self.points.len()];
+
+	for c in 0..self.cylinders.len() {
+	    let cylinder_stiffness = annotation::get_stiffness(&annotations[self.cylinders[c]],
+							       self.nodes[c].len());
+	    for i in 0..self.nodes[c].len() {
+		if cylinder_stiffness[i] != 1.0 {
+		    stiffness[self.nodes[c][i]] = cylinder_stiffness[i];
+		}
+	    }
+	}
+
+	stiffness
+    }
+
+    // Per-node constraint kinds from the annotations of all cylinders in the graph
+    pub fn get_constraints(&self, annotations: &Vec<Vec<Box<dyn annotation::Annotation>>>)
+			   -> Vec<Option<laplacian::ConstraintKind>> {
+	let mut constraints = vec![None; self.points.len()];
+
+	for c in 0..self.cylinders.len() {
+	    let cylinder_constraints = annotation::get_constraints(&annotations[self.cylinders[c]],
+								   self.nodes[c].len());
+	    for i in 0..self.nodes[c].len() {
+		if cylinder_constraints[i].is_some() {
+		    constraints[self.nodes[c][i]] = cylinder_constraints[i];
+		}
+	    }
+	}
+
+	constraints
+    }
+
+    // Copies the node positions back to the control points of the cylinders
+    pub fn write_back(&self, cylinders: &mut Vec<cylinder::GeneralizedCylinder>) {
+	for c in 0..self.cylinders.len() {
+	    let spline = &mut cylinders[self.cylinders[c]].spline;
+	    for i in 0..self.nodes[c].len() {
+		spline.control_points[i] = self.points[self.nodes[c][i]];
+	    }
+	}
+    }
+}
+
+// Ignores junctions whose indices do not match the cylinders, e.g. after points were removed
+fn is_valid(junction: &Junction, cylinders: &Vec<cylinder::GeneralizedCylinder>) -> bool {
+    junction.child < cylinders.len() && junction.parent < cylinders.len() &&
+	junction.child_index < cylinders[junction.child].spline.control_points.len() &&
+	junction.parent_index < cylinders[junction.parent].spline.control_points.len()
+}
+
+fn find(parents: &mut Vec<usize>, i: usize) -> usize {
+    let mut root = i;
+    while parents[root] != root {
+	root = parents[root];
+    }
+
+    let mut i = i;
+    while parents[i] != root {
+	let next = parents[i];
+	parents[i] = root;
+	i = next;
+    }
+
+    root
+}
+
+// Builds the graph of all cylinders connected to the root cylinder through junctions
+pub fn build_graph(cylinders: &Vec<cylinder::GeneralizedCylinder>,
+		   junctions: &Vec<Junction>,
+		   root: usize) -> SkeletonGraph {
+    let junctions : Vec<&Junction> = junctions.iter().filter(|j| is_valid(j, cylinders)).collect();
+
+    // Find the connected cylinders
+    let mut component = vec![root];
+    let mut queue : VecDeque<usize> = VecDeque::new();
+    queue.push_back(root);
+    while let Some(c) = queue.pop_front() {
+	for j in &junctions {
+	    let other = if j.child == c {
+		j.parent
+	    } else if j.parent == c {
+		j.child
+	    } else {
+		continue;
+	    };
+
+	    if !component.contains(&other) {
+		component.push(other);
+		queue.push_back(other);
+	    }
+	}
+    }
+
+    // Every control point starts out as its own node, then junction points are merged
+    let mut offsets : Vec<usize> = Vec::with_capacity(component.len());
+    let mut num_points = 0;
+    for c in &component {
+	offsets.push(num_points);
+	num_points += cylinders[*c].spline.control_points.len();
+    }
+
+    let offset_of = |c: usize| offsets[component.iter().position(|x| *x == c).unwrap()];
+
+    let mut parents : Vec<usize> = (0..num_points).collect();
+    for j in &junctions {
+	if !component.contains(&j.child) {
+	    continue;
+	}
+
+	let a = find(&mut parents, offset_of(j.child) + j.child_index);
+	let b = find(&mut parents, offset_of(j.parent) + j.parent_index);
+	parents[a] = b;
+    }
+
+    // Number the merged nodes consecutively
+    let mut node_of_root = vec![usize::max_value(); num_points];
+    let mut nodes : Vec<Vec<usize>> = Vec::with_capacity(component.len());
+    let mut sums : Vec<glm::Vec3> = Vec::new();
+    let mut counts : Vec<f32> = Vec::new();
+
+    for k in 0..component.len() {
+	let points = &cylinders[component[k]].spline.control_points;
+	let mut cylinder_nodes = Vec::with_capacity(points.len());
+
+	for i in 0..points.len() {
+	    let r = find(&mut parents, offsets[k] + i);
+	    if node_of_root[r] == usize::max_value() {
+		node_of_root[r] = sums.len();
+		sums.push(glm::vec3(0.0, 0.0, 0.0));
+		counts.push(0.0);
+	    }
+
+	    let node = node_of_root[r];
+	    sums[node] = sums[node] + points[i];
+	    counts[node] += 1.0;
+	    cylinder_nodes.push(node);
+	}
+
+	nodes.push(cylinder_nodes);
+    }
+
+    let points : Vec<glm::Vec3> = (0..sums.len()).map(|i| sums[i] / counts[i]).collect();
+
+    // Connect the nodes along each spline
+    let mut adjacency : Vec<Vec<usize>> = vec![Vec::new(); points.len()];
+    for k in 0..component.len() {
+	let spline = &cylinders[component[k]].spline;
+	let chain = laplacian::chain_adjacency(spline.control_points.len(), spline.closed);
+
+	for i in 0..chain.len() {
+	    for ci in &chain[i] {
+		let (a, b) = (nodes[k][i], nodes[k][*ci]);
+		if a != b && !adjacency[a].contains(&b) {
+		    adjacency[a].push(b);
+		}
+	    }
+	}
+    }
+
+    SkeletonGraph { points, adjacency, cylinders: component, nodes }
+}
+
+// Attaches the ends of a newly drawn spline (in world coordinates) to the closest control
+// points of existing cylinders, if they are close enough. The attached ends are moved onto
+// the control points they are attached to. Closed splines have no ends to attach.
+pub fn attach_ends(child: usize,
+		   spline: &mut splinedraw::SplineState,
+		   cylinders: &Vec<cylinder::GeneralizedCylinder>) -> Vec<Junction> {
+    let mut junctions : Vec<Junction> = Vec::new();
+    let n = spline.control_points.len();
+
+    if spline.closed || n < 2 {
+	return junctions;
+    }
+
+    for child_index in &[0, n - 1] {
+	let end = spline.control_points[*child_index];
+
+	let mut closest = SNAP_DISTANCE;
+	let mut attachment : Option<(usize, usize)> = None;
+
+	for c in 0..cylinders.len() {
+	    let points = &cylinders[c].spline.control_points;
+	    for i in 0..points.len() {
+		let d = length(glm::vec3(points[i].x - end.x, points[i].y - end.y, 0.0));
+		if d < closest {
+		    closest = d;
+		    attachment = Some((c, i));
+		}
+	    }
+	}
+
+	if let Some((parent, parent_index)) = attachment {
+	    spline.control_points[*child_index] = cylinders[parent].spline.control_points[parent_index];
+	    junctions.push(Junction { child, child_index: *child_index, parent, parent_index });
+	}
+    }
+
+    if junctions.len() > 0 {
+	spline.update_gpu_state();
+    }
+
+    junctions
+}
@@ -0,0 +1,420 @@
+extern crate nalgebra;
+
+use nalgebra as na;
+use na::sparse as nsp;
+
+// Solvers for the sparse least squares problems of Laplacian editing:
+//   min |A x - b|^2 + sum_k d_k (x_k - c_k)^2
+// A holds the shape rows, which rarely change, while the constraint weights d and
+// the right hand sides b and c change during a drag.
+
+// LSQR stops once the residual of the normal equations is this small, relative to |A| |r|
+static LSQR_TOLERANCE: f64 = 1e-8;
+// Upper bound on the LSQR iterations, per unknown. Linearized editing of long, dense
+// curves needs close to this many
+static LSQR_ITERATIONS_PER_UNKNOWN: usize = 20;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SolverBackend {
+    CholeskyF32, // Cholesky factorization of the normal equations in single precision
+    CholeskyF64, // The same in double precision
+    Lsqr,        // Iterative least squares on A itself, without squaring its condition number
+}
+
+// The double-precision feature makes the f64 Cholesky the default
+impl Default for SolverBackend {
+    fn default() -> SolverBackend {
+	if cfg!(feature = "double-precision") {
+	    SolverBackend::CholeskyF64
+	} else {
+	    SolverBackend::CholeskyF32
+	}
+    }
+}
+
+impl SolverBackend {
+    pub fn create(&self) -> Box<dyn LeastSquaresSolver> {
+	match self {
+	    SolverBackend::CholeskyF32 => Box::new(NormalCholesky::<f32>::new()),
+	    SolverBackend::CholeskyF64 => Box::new(NormalCholesky::<f64>::new()),
+	    SolverBackend::Lsqr => Box::new(Lsqr::new()),
+	}
+    }
+}
+
+// A sparse matrix as a list of entries. Every (row, col) pair occurs at most once
+pub struct Triplets {
+    pub nrows: usize,
+    pub ncols: usize,
+    pub rows: Vec<usize>,
+    pub cols: Vec<usize>,
+    pub vals: Vec<f32>,
+}
+
+pub trait LeastSquaresSolver {
+    // Sets the shape rows A. This is the expensive part of the setup
+    fn set_shape(&mut self, shape: Triplets);
+
+    // Sets the constraint weights d, one per unknown. Returns false if the
+    // problem can not be solved with these weights
+    fn set_constraints(&mut self, diagonal: &Vec<f32>) -> bool;
+
+    // Whether solve can be called
+    fn is_ready(&self) -> bool;
+
+    // Solves for the right hand side b of the shape rows and the constraint targets c.
+    // guess is a starting point for iterative solvers
+    fn solve(&self,
+	     rhs: &na::MatrixMN::<f32, na::Dynamic, na::U1>,
+	     targets: &na::MatrixMN::<f32, na::Dynamic, na::U1>,
+	     guess: &na::MatrixMN::<f32, na::Dynamic, na::U1>)
+	     -> Option<na::MatrixMN::<f32, na::Dynamic, na::U1>>;
+}
+
+// Solves the normal equations (A^T A + diag(d)) x = A^T b + d c. A^T A is kept between calls to
+// set_constraints, which only add to its diagonal. Since every diagonal entry is always present,
+// the nonzero pattern never changes and the symbolic factorization is reused as well.
+// The normal equations square the condition number of A, which f64 makes up for on long curves.
+pub struct NormalCholesky<N: na::RealField> {
+    shape: Option<Triplets>,
+    normal: Option<nsp::CsMatrix<N, na::Dynamic>>, // A^T A
+    factorization: nsp::CsCholesky<N, na::Dynamic>,
+    symbolic_ready: bool, // Whether factorization holds the pattern of the current normal equations
+    factorized: bool,
+    diagonal: Vec<f32>,
+}
+
+impl<N: na::RealField> NormalCholesky<N> {
+    pub fn new() -> NormalCholesky<N> {
+	NormalCholesky {
+	    shape: None,
+	    normal: None,
+	    factorization: nsp::CsCholesky::new_symbolic(&nsp::CsMatrix::<N, na::Dynamic, na::Dynamic>::new_uninitialized_generic(na::Dynamic::new(1), na::Dynamic::new(1), 0)),
+	    symbolic_ready: false,
+	    factorized: false,
+	    diagonal: Vec::new(),
+	}
+    }
+}
+
+impl<N: na::RealField> LeastSquaresSolver for NormalCholesky<N> {
+    fn set_shape(&mut self, shape: Triplets) {
+	let vals : Vec<N> = shape.vals.iter().map(|v| na::convert::<f64, N>(*v as f64)).collect();
+	let a = nsp::CsMatrix::from_triplet(shape.nrows, shape.ncols, &shape.rows, &shape.cols, &vals);
+
+	self.normal = Some(&a.transpose() * &a);
+	self.shape = Some(shape);
+	self.symbolic_ready = false;
+	self.factorized = false;
+    }
+
+    fn set_constraints(&mut self, diagonal: &Vec<f32>) -> bool {
+	let m = diagonal.len();
+	let indices : Vec<usize> = (0..m).collect();
+	let vals : Vec<N> = diagonal.iter().map(|v| na::convert::<f64, N>(*v as f64)).collect();
+	let constraints = nsp::CsMatrix::from_triplet(m, m, &indices, &indices, &vals);
+
+	let mut system = match self.normal {
+	    Some(ref normal) => normal + &constraints,
+	    None => return false,
+	};
+
+	self.factorized = if self.symbolic_ready {
+	    let values : Vec<N> = system.values_mut().map(|v| *v).collect();
+	    self.factorization.decompose_left_looking(&values)
+	} else {
+	    self.factorization = nsp::CsCholesky::new(&system);
+	    self.symbolic_ready = true;
+	    self.factorization.l().is_some()
+	};
+	self.diagonal = diagonal.clone();
+
+	self.factorized
+    }
+
+    fn is_ready(&self) -> bool {
+	self.factorized && self.factorization.l().is_some()
+    }
+
+    fn solve(&self,
+	     rhs: &na::MatrixMN::<f32, na::Dynamic, na::U1>,
+	     targets: &na::MatrixMN::<f32, na::Dynamic, na::U1>,
+	     _guess: &na::MatrixMN::<f32, na::Dynamic, na::U1>)
+	     -> Option<na::MatrixMN::<f32, na::Dynamic, na::U1>> {
+	if !self.factorized {
+	    return None;
+	}
+	let l = self.factorization.l()?;
+	let shape = self.shape.as_ref()?;
+
+	// A^T b + d c
+	let mut normal_rhs = na::MatrixMN::<N, na::Dynamic, na::U1>::zeros(shape.ncols);
+	for k in 0..shape.vals.len() {
+	    normal_rhs[shape.cols[k]] += na::convert::<f64, N>(shape.vals[k] as f64 * rhs[shape.rows[k]] as f64);
+	}
+	for k in 0..self.diagonal.len() {
+	    normal_rhs[k] += na::convert::<f64, N>(self.diagonal[k] as f64 * targets[k] as f64);
+	}
+
+	let x = l.solve_lower_triangular(&normal_rhs)
+	    .and_then(|a| l.tr_solve_lower_triangular(&a))?;
+
+	Some(x.map(|v| na::try_convert::<N, f64>(v).unwrap_or(std::f64::NAN) as f32))
+    }
+}
+
+// LSQR (Paige and Saunders, 1982) on the stacked system [A; sqrt(d)] x = [b; sqrt(d) c].
+// It never forms A^T A, so it keeps the accuracy of A, but needs many iterations when A
+// is badly conditioned, which makes it far slower than the Cholesky backends on long curves.
+// The columns are scaled to unit length, which helps with that, and every solve starts from the guess
+pub struct Lsqr {
+    shape: Option<Triplets>,
+    diagonal: Vec<f64>,
+    scales: Vec<f64>, // Inverse column norms of the stacked system
+}
+
+impl Lsqr {
+    pub fn new() -> Lsqr {
+	Lsqr { shape: None, diagonal: Vec::new(), scales: Vec::new() }
+    }
+
+    // [A; sqrt(d)] S x, with S the column scaling
+    fn apply(&self, shape: &Triplets, x: &Vec<f64>) -> Vec<f64> {
+	let mut out = vec![0.0; shape.nrows + shape.ncols];
+	for k in 0..shape.vals.len() {
+	    out[shape.rows[k]] += shape.vals[k] as f64 * self.scales[shape.cols[k]] * x[shape.cols[k]];
+	}
+	for k in 0..shape.ncols {
+	    out[shape.nrows + k] = self.diagonal[k].sqrt() * self.scales[k] * x[k];
+	}
+	out
+    }
+
+    // S [A; sqrt(d)]^T y
+    fn apply_transposed(&self, shape: &Triplets, y: &Vec<f64>) -> Vec<f64> {
+	let mut out = vec![0.0; shape.ncols];
+	for k in 0..shape.vals.len() {
+	    out[shape.cols[k]] += shape.vals[k] as f64 * y[shape.rows[k]];
+	}
+	for k in 0..shape.ncols {
+	    out[k] = self.scales[k] * (out[k] + self.diagonal[k].sqrt() * y[shape.nrows + k]);
+	}
+	out
+    }
+}
+
+fn norm(v: &Vec<f64>) -> f64 {
+    v.iter().map(|x| x * x).sum::<f64>().sqrt()
+}
+
+fn scale(v: &mut Vec<f64>, s: f64) {
+    for x in v.iter_mut() {
+	*x *= s;
+    }
+}
+
+impl LeastSquaresSolver for Lsqr {
+    fn set_shape(&mut self, shape: Triplets) {
+	self.diagonal = vec![0.0; shape.ncols];
+	self.scales = vec![1.0; shape.ncols];
+	self.shape = Some(shape);
+    }
+
+    fn set_constraints(&mut self, diagonal: &Vec<f32>) -> bool {
+	let shape = match self.shape {
+	    Some(ref shape) => shape,
+	    None => return false,
+	};
+
+	self.diagonal = diagonal.iter().map(|d| *d as f64).collect();
+
+	let mut squared_norms = self.diagonal.clone();
+	for k in 0..shape.vals.len() {
+	    squared_norms[shape.cols[k]] += (shape.vals[k] as f64).powi(2);
+	}
+
+	// Columns that are all zero are left alone, LSQR never moves them
+	self.scales = squared_norms.iter()
+	    .map(|n| if *n > 0.0 { 1.0 / n.sqrt() } else { 1.0 })
+	    .collect();
+
+	true
+    }
+
+    fn is_ready(&self) -> bool {
+	self.shape.is_some()
+    }
+
+    fn solve(&self,
+	     rhs: &na::MatrixMN::<f32, na::Dynamic, na::U1>,
+	     targets: &na::MatrixMN::<f32, na::Dynamic, na::U1>,
+	     guess: &na::MatrixMN::<f32, na::Dynamic, na::U1>)
+	     -> Option<na::MatrixMN::<f32, na::Dynamic, na::U1>> {
+	let shape = self.shape.as_ref()?;
+	let n = shape.ncols;
+
+	// Solve for the correction of the guess, in scaled coordinates
+	let x0 : Vec<f64> = (0..n).map(|k| guess[k] as f64 / self.scales[k]).collect();
+	let ax0 = self.apply(shape, &x0);
+	let mut u : Vec<f64> = (0..shape.nrows + n).map(|k| {
+	    let b = if k < shape.nrows {
+		rhs[k] as f64
+	    } else {
+		self.diagonal[k - shape.nrows].sqrt() * targets[k - shape.nrows] as f64
+	    };
+	    b - ax0[k]
+	}).collect();
+
+	let mut beta = norm(&u);
+	let initial_residual = beta;
+	let mut x = vec![0.0; n];
+
+	if beta > 0.0 {
+	    scale(&mut u, 1.0 / beta);
+	    let mut v = self.apply_transposed(shape, &u);
+	    let mut alpha = norm(&v);
+	    if alpha > 0.0 {
+		scale(&mut v, 1.0 / alpha);
+	    }
+
+	    let mut w = v.clone();
+	    let mut phi_bar = beta;
+	    let mut rho_bar = alpha;
+
+	    // The scaled columns have unit length, so this is the Frobenius norm of the operator
+	    let a_norm = (n as f64).sqrt();
+
+	    for _ in 0..(LSQR_ITERATIONS_PER_UNKNOWN * n).max(100) {
+		if alpha == 0.0 {
+		    break;
+		}
+
+		let av = self.apply(shape, &v);
+		for k in 0..u.len() {
+		    u[k] = av[k] - alpha * u[k];
+		}
+		beta = norm(&u);
+		if beta > 0.0 {
+		    scale(&mut u, 1.0 / beta);
+		}
+
+		let atu = self.apply_transposed(shape, &u);
+		for k in 0..n {
+		    v[k] = atu[k] - beta * v[k];
+		}
+		alpha = norm(&v);
+		if alpha > 0.0 {
+		    scale(&mut v, 1.0 / alpha);
+		}
+
+		let rho = (rho_bar * rho_bar + beta * beta).sqrt();
+		let c = rho_bar / rho;
+		let s = beta / rho;
+		let theta = s * alpha;
+		rho_bar = -c * alpha;
+		let phi = c * phi_bar;
+		phi_bar = s * phi_bar;
+
+		for k in 0..n {
+		    x[k] += (phi / rho) * w[k];
+		    w[k] = v[k] - (theta / rho) * w[k];
+		}
+
+		// phi_bar is the residual norm, phi_bar * alpha * |c| that of the normal equations
+		if phi_bar * alpha * c.abs() <= LSQR_TOLERANCE * a_norm * phi_bar ||
+		    phi_bar <= LSQR_TOLERANCE * initial_residual {
+		    break;
+		}
+	    }
+	}
+
+	Some(na::MatrixMN::<f32, na::Dynamic, na::U1>::from_iterator(
+	    n, (0..n).map(|k| ((x0[k] + x[k]) * self.scales[k]) as f32)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Vector = na::MatrixMN::<f32, na::Dynamic, na::U1>;
+
+    // Uniform Laplacian rows of an open curve of n points, x_i minus the mean of its neighbors
+    fn get_laplacian(n: usize) -> Triplets {
+	let mut shape = Triplets { nrows: n, ncols: n, rows: Vec::new(), cols: Vec::new(), vals: Vec::new() };
+	for i in 0..n {
+	    let neighbors : Vec<usize> = [i.wrapping_sub(1), i + 1].iter().cloned().filter(|j| *j < n).collect();
+	    shape.rows.push(i);
+	    shape.cols.push(i);
+	    shape.vals.push(1.0);
+	    for j in &neighbors {
+		shape.rows.push(i);
+		shape.cols.push(*j);
+		shape.vals.push(-1.0 / neighbors.len() as f32);
+	    }
+	}
+	shape
+    }
+
+    // The normal equations, solved densely in double precision
+    fn solve_dense(shape: &Triplets, diagonal: &Vec<f32>, rhs: &Vector, targets: &Vector) -> Vec<f64> {
+	let mut a = na::DMatrix::<f64>::zeros(shape.nrows, shape.ncols);
+	for k in 0..shape.vals.len() {
+	    a[(shape.rows[k], shape.cols[k])] = shape.vals[k] as f64;
+	}
+	let b = na::DVector::<f64>::from_iterator(shape.nrows, rhs.iter().map(|v| *v as f64));
+	let d = na::DVector::<f64>::from_iterator(shape.ncols, diagonal.iter().map(|v| *v as f64));
+	let c = na::DVector::<f64>::from_iterator(shape.ncols, targets.iter().map(|v| *v as f64));
+
+	let system = a.transpose() * &a + na::DMatrix::from_diagonal(&d);
+	let system_rhs = a.transpose() * b + d.component_mul(&c);
+	system.cholesky().unwrap().solve(&system_rhs).iter().cloned().collect()
+    }
+
+    #[test]
+    fn backends_agree() {
+	let n = 24;
+	let positions : Vec<f32> = (0..n).map(|i| (i as f32 * 0.4).sin()).collect();
+	let shape = get_laplacian(n);
+	let mut rhs = Vector::zeros(n);
+	for k in 0..shape.vals.len() {
+	    rhs[shape.rows[k]] += shape.vals[k] * positions[shape.cols[k]];
+	}
+	let guess = Vector::from_iterator(n, positions.iter().cloned());
+
+	let backends = [SolverBackend::CholeskyF32, SolverBackend::CholeskyF64, SolverBackend::Lsqr];
+	let mut solvers : Vec<Box<dyn LeastSquaresSolver>> = backends.iter().map(|b| b.create()).collect();
+	for solver in solvers.iter_mut() {
+	    solver.set_shape(get_laplacian(n));
+	}
+
+	// The targets pull the ends apart and the middle aside. The second set of weights
+	// refactors the normal equations with the kept symbolic factorization
+	let targets = Vector::from_iterator(n, positions.iter().enumerate()
+					    .map(|(i, p)| p + if i == n / 2 { 0.3 } else if i == 0 { -0.2 } else { 0.1 }));
+	for weights in &[vec![(0, 10.0), (n - 1, 10.0)],
+			 vec![(0, 1.0), (n / 2, 5.0), (n - 1, 2.0)]] {
+	    let mut diagonal = vec![0.0; n];
+	    for (k, w) in weights {
+		diagonal[*k] = *w;
+	    }
+	    let expected = solve_dense(&shape, &diagonal, &rhs, &targets);
+
+	    let results : Vec<Vector> = solvers.iter_mut().map(|solver| {
+		assert!(solver.set_constraints(&diagonal));
+		assert!(solver.is_ready());
+		solver.solve(&rhs, &targets, &guess).unwrap()
+	    }).collect();
+
+	    for (backend, x) in backends.iter().zip(results.iter()) {
+		for k in 0..n {
+		    assert!((x[k] as f64 - expected[k]).abs() < 1e-3,
+			    "{:?} gives {} for point {}, the dense solve {}", backend, x[k], k, expected[k]);
+		}
+	    }
+	    for k in 0..n {
+		assert!((results[0][k] - results[1][k]).abs() < 1e-3 && (results[1][k] - results[2][k]).abs() < 1e-3);
+	    }
+	}
+    }
+}
@@ -4,14 +4,23 @@ extern crate gl;
 use crate::program;
 use crate::edit;
 use crate::utils;
+use crate::stroke;
 
 use glm::builtin::*;
 
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
 pub static LINE_LIMIT: f32 = 1.0 / 20.0; // 5% of window width
-static MAX_NUM_POINTS: usize = 400;
 
-pub static SPLINE_RESOLUTION: usize = 5; // Points per control point
-static SPLINE_DEGREE:     usize = 3;
+// A stroke ending within this many LINE_LIMITs of its start becomes a closed loop
+static CLOSE_LOOP_FACTOR: f32 = 1.5;
+
+pub static DEFAULT_SPLINE_RESOLUTION: usize = 5; // Points per control point
+pub static DEFAULT_SPLINE_DEGREE:     usize = 3;
+
+pub static MAX_SPLINE_RESOLUTION: usize = 20;
+pub static MAX_SPLINE_DEGREE:     usize = 5;
 
 
 
@@ -28,11 +37,11 @@ pub struct SplineCoefficients {
     coefficients : Vec<Vec<f32> >
 }
 
-pub fn make_spline_coefficients() -> SplineCoefficients {
-    let mut coeffs = SplineCoefficients { coefficients: Vec::with_capacity(SPLINE_RESOLUTION) };
+pub fn make_spline_coefficients(degree: usize, resolution: usize) -> SplineCoefficients {
+    let mut coeffs = SplineCoefficients { coefficients: Vec::with_capacity(resolution) };
 
-    let k = SPLINE_DEGREE;
-    let m = SPLINE_RESOLUTION;
+    let k = degree;
+    let m = resolution;
     
     let mut bb = vec![vec![0.0; 2 * k + 1]; m];
     for i in 0..m {
@@ -60,15 +69,38 @@ pub fn make_spline_coefficients() -> SplineCoefficients {
 }
 
 lazy_static! {
-    pub static ref SPLINE_COEFFICIENTS : SplineCoefficients = make_spline_coefficients();
+    // Coefficient tables are shared between all splines using the same (degree, resolution) pair
+    static ref SPLINE_COEFFICIENTS : Mutex<HashMap<(usize, usize), Arc<SplineCoefficients>>> =
+	Mutex::new(HashMap::new());
+}
+
+pub fn get_spline_coefficients(degree: usize, resolution: usize) -> Arc<SplineCoefficients> {
+    let mut cache = SPLINE_COEFFICIENTS.lock().unwrap();
+    
+    cache.entry((degree, resolution))
+	.or_insert_with(|| Arc::new(make_spline_coefficients(degree, resolution)))
+	.clone()
 }
 
 
 
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SplineType {
+    UniformBSpline,        // Uniform B-spline, padded with the last control point
+    ClampedBSpline,        // B-spline on an open-uniform knot vector, hitting both end points
+    CatmullRom,            // Interpolating, uniform parametrization
+    CentripetalCatmullRom, // Interpolating, centripetal parametrization
+}
+
 pub struct SplineState {
     pub control_points    : Vec<glm::Vec3>,
     pub point_colors      : Vec<glm::Vec4>, // Colors of control points
     pub spline_points     : Vec<glm::Vec3>,
+    pub stroke_samples    : Vec<stroke::StrokeSample>, // Raw input the control points were made from
+    pub spline_type        : SplineType,
+    pub closed             : bool,
+    degree                 : usize,
+    resolution             : usize,
     spline_lines_vao       : gl::types::GLuint,
     spline_lines_vbo       : gl::types::GLuint,
     control_points_vao     : gl::types::GLuint,
@@ -100,11 +132,83 @@ impl SplineState {
 	self.point_colors.push(glm::vec4(0.0, 0.0, 0.0, 1.0));
     }
 
+    pub fn get_degree(&self) -> usize {
+	self.degree
+    }
+
+    pub fn set_degree(&mut self, degree: usize) {
+	self.degree = degree.max(1).min(MAX_SPLINE_DEGREE);
+    }
+
+    pub fn get_resolution(&self) -> usize {
+	self.resolution
+    }
+
+    pub fn set_resolution(&mut self, resolution: usize) {
+	self.resolution = resolution.max(1).min(MAX_SPLINE_RESOLUTION);
+    }
+
+    // Maps a control point index to the index of the spline point closest to it
+    pub fn control_point_to_spline_index(&self, index: usize) -> usize {
+	if self.spline_points.len() == 0 {
+	    return 0;
+	}
+	
+	let last = self.spline_points.len() - 1;
+
+	if self.closed {
+	    let center = match self.spline_type {
+		SplineType::UniformBSpline | SplineType::ClampedBSpline =>
+		    (index as f32 - (self.degree as f32 - 1.0) / 2.0) * self.resolution as f32,
+		SplineType::CatmullRom | SplineType::CentripetalCatmullRom =>
+		    (index * self.resolution) as f32,
+	    };
+
+	    // Wrap around the seam
+	    return (center.round() as i64).rem_euclid(self.spline_points.len() as i64) as usize;
+	}
+	
+	if index == 0 {
+	    return 0;
+	} else if index + 1 >= self.control_points.len() {
+	    return last;
+	}
+
+	let center = match self.spline_type {
+	    // Sample p is weighted around control point p / resolution + (degree - 1) / 2,
+	    // except at the ends, where the padding pulls the spline onto the end points
+	    SplineType::UniformBSpline =>
+		(index as f32 - (self.degree as f32 - 1.0) / 2.0) * self.resolution as f32,
+
+	    // Use the Greville abscissa of the control point
+	    SplineType::ClampedBSpline => {
+		let p = self.clamped_degree();
+		let mut greville = 0.0;
+		for i in (index + 1)..(index + p + 1) {
+		    greville += self.clamped_knot(i);
+		}
+		greville /= p as f32;
+
+		greville / (self.control_points.len() - p) as f32 * last as f32
+	    },
+
+	    // Interpolating splines pass through every control point
+	    SplineType::CatmullRom | SplineType::CentripetalCatmullRom =>
+		(index * self.resolution) as f32,
+	};
+	
+	(center.round().max(0.0) as usize).min(last)
+    }
     
     pub fn new() -> SplineState {
 	let mut spline_state = SplineState {control_points: Vec::new(),
 					    point_colors: Vec::new(),
 					    spline_points: Vec::new(),
+					    stroke_samples: Vec::new(),
+					    spline_type: SplineType::ClampedBSpline,
+					    closed: false,
+					    degree: DEFAULT_SPLINE_DEGREE,
+					    resolution: DEFAULT_SPLINE_RESOLUTION,
 					    spline_lines_vao: 0, spline_lines_vbo: 0,
 					    control_points_vao: 0, control_points_vbo: 0,
 					    point_color_vbo: 0, spline_color_vbo: 0};
@@ -158,54 +262,183 @@ impl SplineState {
 	spline_state
     }
 
-    pub fn update_gpu_state(self: &mut SplineState) {
+    // The original spline, starting in the first control point and then following
+    // the uniform B-spline basis with the last control point repeated as padding
+    fn evaluate_uniform_b_spline(&self) -> Vec<glm::Vec3> {
+	let mut spline_points : Vec<glm::Vec3> = Vec::new();
+	
+	spline_points.push(self.control_points[0]);
 
-	if self.control_points.len() >= 2 {
-	    /* for _i in 0..SPLINE_DEGREE {
-		// self.control_points.push(self.control_points[self.control_points.len() - 1]);
-		self.add_control_point(self.control_points[self.control_points.len() - 1]);
-	    } */
-	    
-	    self.spline_points.clear();
-	    // let mut spline_points : Vec<glm::Vec3> = Vec::new();
+	let coefficients = get_spline_coefficients(self.degree, self.resolution);
+
+	// Build from bottom up
+	let num_cp = self.control_points.len() - 1;
+	let num_points = num_cp * self.resolution;
 
-	    let mut spline_colors : Vec<glm::Vec4> = Vec::new();
+	for i in 0..num_points {
+	    let mut pp = glm::vec3(0.0, 0.0, 0.0);
+	    let curr_point =  (i + 1) / self.resolution; 
+
+	    let b_ind = (i + 1) % self.resolution;
 	    
-	    self.spline_points.push(self.control_points[0]);
-	    spline_colors.push(self.point_colors[0]);
+	    for j in 0..(self.degree+1) {
+		pp = pp + lookup_pad(&self.control_points, curr_point + j) *
+		    coefficients.coefficients[b_ind][j];
+	    }
 
-	    // Build from bottom up
-	    let num_cp = self.control_points.len() - 1;
-	    let num_points = num_cp * SPLINE_RESOLUTION;
+	    spline_points.push(pp);
+	}
 
-	    for i in 0..num_points {
-		let mut pp = glm::vec3(0.0, 0.0, 0.0);
-		let mut cp = glm::vec4(0.0, 0.0, 0.0, 1.0);
-		let curr_point =  (i + 1) / SPLINE_RESOLUTION; 
+	spline_points
+    }
 
-		let b_ind = (i + 1) % SPLINE_RESOLUTION;
-		
-		for j in 0..(SPLINE_DEGREE+1) {
-		    pp = pp + lookup_pad(&self.control_points, curr_point + j) *
-			SPLINE_COEFFICIENTS.coefficients[b_ind][j];
-		    // cp = cp + lookup_pad(&self.point_colors, curr_point + j) *
-		    // SPLINE_COEFFICIENTS.coefficients[b_ind][j];
-		}
+    // Periodic uniform B-spline, used for closed splines of both B-spline types.
+    // There is no duplicate of the first point at the end
+    fn evaluate_periodic_b_spline(&self) -> Vec<glm::Vec3> {
+	let n = self.control_points.len();
+	let num_points = n * self.resolution;
+	
+	let coefficients = get_spline_coefficients(self.degree, self.resolution);
 
-		cp[3] = 1.0;
+	let mut spline_points : Vec<glm::Vec3> = Vec::with_capacity(num_points);
+	
+	for i in 0..num_points {
+	    let mut pp = glm::vec3(0.0, 0.0, 0.0);
+	    let curr_point = i / self.resolution;
 
-		self.spline_points.push(pp);
-		spline_colors.push(cp);
+	    let b_ind = i % self.resolution;
 
-		if cp[0] != 0.0 || cp[1] != 0.0 || cp[2] != 0.0 {
-		    println!("{:?}", cp);
+	    for j in 0..(self.degree + 1) {
+		pp = pp + self.control_points[(curr_point + j) % n] *
+		    coefficients.coefficients[b_ind][j];
+	    }
+
+	    spline_points.push(pp);
+	}
+
+	spline_points
+    }
+
+    // Degree used for the clamped B-spline, which cannot exceed the number of segments
+    fn clamped_degree(&self) -> usize {
+	self.degree.min(self.control_points.len() - 1)
+    }
+
+    // Open-uniform knot vector: degree + 1 repeated knots at each end, so that the
+    // curve starts and ends exactly in the first and last control points
+    fn clamped_knot(&self, i: usize) -> f32 {
+	let p = self.clamped_degree();
+	let n = self.control_points.len();
+
+	if i <= p {
+	    0.0
+	} else if i >= n {
+	    (n - p) as f32
+	} else {
+	    (i - p) as f32
+	}
+    }
+
+    fn evaluate_clamped_b_spline(&self) -> Vec<glm::Vec3> {
+	let n = self.control_points.len();
+	let p = self.clamped_degree();
+	let num_points = (n - 1) * self.resolution + 1;
+	let end = (n - p) as f32;
+
+	let mut spline_points : Vec<glm::Vec3> = Vec::with_capacity(num_points);
+	let mut d = vec![glm::vec3(0.0, 0.0, 0.0); p + 1];
+
+	for s in 0..num_points {
+	    let u = end * s as f32 / (num_points - 1) as f32;
+
+	    // Knot span containing u, with the last span closed at the end
+	    let k = ((u.floor() as usize) + p).min(n - 1);
+
+	    // de Boor's algorithm
+	    for j in 0..(p + 1) {
+		d[j] = self.control_points[j + k - p];
+	    }
+
+	    for r in 1..(p + 1) {
+		for j in (r..(p + 1)).rev() {
+		    let t0 = self.clamped_knot(j + k - p);
+		    let t1 = self.clamped_knot(j + 1 + k - r);
+		    let alpha = (u - t0) / (t1 - t0);
+		    
+		    d[j] = d[j - 1] * (1.0 - alpha) + d[j] * alpha;
 		}
 	    }
 
-	    /* for _i in 0..SPLINE_DEGREE {
-		self.control_points.pop();
-		self.point_colors.pop();
-	    } */
+	    spline_points.push(d[p]);
+	}
+
+	spline_points
+    }
+
+    // Catmull-Rom spline in the Barry-Goldman formulation. alpha = 0 gives the uniform
+    // variant, alpha = 0.5 the centripetal one, which avoids cusps and self-intersections
+    // within a segment. The curve passes through every control point
+    fn evaluate_catmull_rom(&self, alpha: f32) -> Vec<glm::Vec3> {
+	let n = self.control_points.len();
+	let cps = &self.control_points;
+	let num_segments = if self.closed { n } else { n - 1 };
+	
+	let mut spline_points : Vec<glm::Vec3> = Vec::with_capacity(num_segments * self.resolution + 1);
+
+	for i in 0..num_segments {
+	    // Closed splines wrap around, open ones get phantom points mirrored through the end points
+	    let (p0, p1, p2, p3) = if self.closed {
+		(cps[(i + n - 1) % n], cps[i], cps[(i + 1) % n], cps[(i + 2) % n])
+	    } else {
+		(if i == 0 { cps[0] * 2.0 - cps[1] } else { cps[i - 1] },
+		 cps[i],
+		 cps[i + 1],
+		 if i + 2 >= n { cps[n - 1] * 2.0 - cps[n - 2] } else { cps[i + 2] })
+	    };
+
+	    let knot_step = |a: glm::Vec3, b: glm::Vec3| length(b - a).powf(alpha).max(1e-5);
+
+	    let t0 = 0.0;
+	    let t1 = t0 + knot_step(p0, p1);
+	    let t2 = t1 + knot_step(p1, p2);
+	    let t3 = t2 + knot_step(p2, p3);
+
+	    for s in 0..self.resolution {
+		let t = t1 + (t2 - t1) * s as f32 / self.resolution as f32;
+		
+		let a1 = p0 * ((t1 - t) / (t1 - t0)) + p1 * ((t - t0) / (t1 - t0));
+		let a2 = p1 * ((t2 - t) / (t2 - t1)) + p2 * ((t - t1) / (t2 - t1));
+		let a3 = p2 * ((t3 - t) / (t3 - t2)) + p3 * ((t - t2) / (t3 - t2));
+
+		let b1 = a1 * ((t2 - t) / (t2 - t0)) + a2 * ((t - t0) / (t2 - t0));
+		let b2 = a2 * ((t3 - t) / (t3 - t1)) + a3 * ((t - t1) / (t3 - t1));
+
+		spline_points.push(b1 * ((t2 - t) / (t2 - t1)) + b2 * ((t - t1) / (t2 - t1)));
+	    }
+	}
+
+	if !self.closed {
+	    spline_points.push(cps[n - 1]);
+	}
+
+	spline_points
+    }
+
+    pub fn update_gpu_state(self: &mut SplineState) {
+
+	if self.control_points.len() >= 2 {
+	    self.spline_points = match self.spline_type {
+		SplineType::UniformBSpline | SplineType::ClampedBSpline if self.closed =>
+		    self.evaluate_periodic_b_spline(),
+		SplineType::UniformBSpline => self.evaluate_uniform_b_spline(),
+		SplineType::ClampedBSpline => self.evaluate_clamped_b_spline(),
+		SplineType::CatmullRom => self.evaluate_catmull_rom(0.0),
+		SplineType::CentripetalCatmullRom => self.evaluate_catmull_rom(0.5),
+	    };
+
+	    let mut spline_colors : Vec<glm::Vec4> = vec![glm::vec4(0.0, 0.0, 0.0, 1.0);
+							  self.spline_points.len()];
+	    spline_colors[0] = self.point_colors[0];
 	    
 	    unsafe {
 		gl::BindBuffer(gl::ARRAY_BUFFER, self.spline_lines_vbo);
@@ -260,7 +493,8 @@ pub fn draw_spline_lines(spline_state: &SplineState ) {
 	gl::EnableVertexAttribArray(1);
 	gl::LineWidth(2.0);
 
-	gl::DrawArrays(gl::LINE_STRIP, 0, spline_state.spline_points.len() as i32);
+	let mode = if spline_state.closed { gl::LINE_LOOP } else { gl::LINE_STRIP };
+	gl::DrawArrays(mode, 0, spline_state.spline_points.len() as i32);
     }
 }
 
@@ -276,28 +510,50 @@ pub fn draw_control_points(spline_state: &SplineState ) {
     }
 }
 
-pub fn handle_spline_draw(mouse_state: &program::MouseState, spline_state: & mut SplineState) {
+// Closes the spline if the stroke ended close to where it started
+pub fn detect_closed_loop(spline_state: &mut SplineState) {
+    let n = spline_state.control_points.len();
+    if n < 4 {
+	return;
+    }
+
+    let gap = length(spline_state.control_points[n - 1] - spline_state.control_points[0]);
+    if gap < LINE_LIMIT * CLOSE_LOOP_FACTOR {
+	// Let the loop close through the first point if the last one nearly coincides with it
+	if gap < LINE_LIMIT * 0.5 {
+	    spline_state.control_points.pop();
+	    spline_state.point_colors.pop();
+	}
+	
+	spline_state.closed = true;
+    }
+}
+
+pub fn handle_spline_draw(mouse_state: &program::MouseState,
+			  stroke_settings: &stroke::StrokeSettings,
+			  spline_state: & mut SplineState) {
     
     if mouse_state.button1_pressed && mouse_state.in_window {
-	let clone = mouse_state.pos.clone();
-	let new_point = utils::normalize_point(clone);
-	let new_point  = glm::vec3(new_point.x, new_point.y, 0.0);
-	
-	if spline_state.control_points.len() == 0 {
+	let new_point = utils::normalize_point(mouse_state.pos);
+	let num_samples = spline_state.stroke_samples.len();
+
+	// Only record samples where the mouse actually moved
+	if num_samples == 0 ||
+	    spline_state.stroke_samples[num_samples - 1].position != new_point {
+		spline_state.stroke_samples.push(stroke::StrokeSample { position: new_point,
+									time: mouse_state.time });
+	    }
+    }
 
-	    spline_state.add_control_point(new_point);
-	} else if length(new_point - spline_state.control_points[spline_state.control_points.len() - 1] )
-	    >= LINE_LIMIT * 0.9 &&
-	    spline_state.control_points.len() < MAX_NUM_POINTS {
-		// Enforce edge length to be LINE_LIMIT:
+    // Rerun the pipeline every frame, so that changes to the settings show up immediately
+    if spline_state.stroke_samples.len() > 0 {
+	let points = stroke::process_stroke(&spline_state.stroke_samples, stroke_settings);
 
-		let vv = new_point - spline_state.control_points[spline_state.control_points.len() - 1];
-		let v2 = vv / length(vv) * LINE_LIMIT;
-		let new_point = spline_state.control_points[spline_state.control_points.len() - 1] + v2;
-		
-		
-		spline_state.add_control_point(new_point);
-	    }
+	spline_state.control_points.clear();
+	spline_state.point_colors.clear();
 	
+	for point in points {
+	    spline_state.add_control_point(glm::vec3(point.x, point.y, 0.0));
+	}
     }
 }
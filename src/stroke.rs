@@ -0,0 +1,282 @@
+extern crate glm;
+
+use glm::builtin::*;
+
+use std::f32;
+
+// Processing of raw mouse/tablet input into control points. The raw samples are
+// smoothed, resampled to a target spacing and finally simplified.
+
+#[derive(Copy, Clone)]
+pub struct StrokeSample {
+    pub position: glm::Vec2,
+    pub time: f64, // Seconds
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum StrokeFilter {
+    Off,
+    Gaussian,
+    OneEuro,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct StrokeSettings {
+    pub filter: StrokeFilter,
+    pub gaussian_sigma: f32,        // Standard deviation, in samples
+    pub one_euro_min_cutoff: f32,   // Cutoff frequency at low speeds, in Hz
+    pub one_euro_beta: f32,         // How fast the cutoff grows with speed
+    pub spacing: f32,               // Target distance between control points
+    pub curvature_adaptivity: f32,  // How much the spacing shrinks in curved parts. 0 gives uniform spacing
+    pub simplify_tolerance: f32,    // Ramer-Douglas-Peucker tolerance. 0 turns simplification off
+    pub max_points: usize,
+}
+
+impl StrokeSettings {
+    pub fn new() -> StrokeSettings {
+	StrokeSettings { filter: StrokeFilter::OneEuro,
+			 gaussian_sigma: 2.0,
+			 one_euro_min_cutoff: 1.0,
+			 one_euro_beta: 0.5,
+			 spacing: 1.0 / 20.0,
+			 curvature_adaptivity: 0.0,
+			 simplify_tolerance: 0.0,
+			 max_points: 400 }
+    }
+}
+
+// Removes samples that did not move, since they carry no shape information
+fn remove_duplicates(samples: &Vec<StrokeSample>) -> Vec<StrokeSample> {
+    let mut result : Vec<StrokeSample> = Vec::with_capacity(samples.len());
+
+    for sample in samples {
+	if result.len() == 0 ||
+	    length(sample.position - result[result.len() - 1].position) > 1e-6 {
+		result.push(*sample);
+	    }
+    }
+
+    result
+}
+
+// Gaussian smoothing over the sample indices. The end points are kept in place
+fn gaussian_filter(samples: &Vec<StrokeSample>, sigma: f32) -> Vec<glm::Vec2> {
+    let n = samples.len();
+    let mut result : Vec<glm::Vec2> = samples.iter().map(|s| s.position).collect();
+
+    if sigma <= 0.0 || n < 3 {
+	return result;
+    }
+
+    let radius = (3.0 * sigma).ceil() as i32;
+    let weights : Vec<f32> = (0..(radius + 1))
+	.map(|k| (-((k * k) as f32) / (2.0 * sigma * sigma)).exp())
+	.collect();
+
+    for i in 1..(n - 1) {
+	let mut sum = glm::vec2(0.0, 0.0);
+	let mut weight_sum = 0.0;
+
+	for k in (-radius)..(radius + 1) {
+	    let j = i as i32 + k;
+	    if j < 0 || j >= n as i32 {
+		continue;
+	    }
+
+	    let w = weights[k.abs() as usize];
+	    sum = sum + samples[j as usize].position * w;
+	    weight_sum += w;
+	}
+
+	result[i] = sum / weight_sum;
+    }
+
+    result
+}
+
+fn one_euro_alpha(dt: f32, cutoff: f32) -> f32 {
+    let tau = 1.0 / (2.0 * f32::consts::PI * cutoff);
+    1.0 / (1.0 + tau / dt)
+}
+
+// The one-euro filter (Casiez et al. 2012): a low-pass filter whose cutoff frequency
+// increases with speed, removing jitter in slow motion while keeping fast strokes responsive
+fn one_euro_filter(samples: &Vec<StrokeSample>, min_cutoff: f32, beta: f32) -> Vec<glm::Vec2> {
+    let n = samples.len();
+    let mut result : Vec<glm::Vec2> = samples.iter().map(|s| s.position).collect();
+
+    if n < 3 {
+	return result;
+    }
+
+    let derivative_cutoff = 1.0;
+    let mut filtered_derivative = glm::vec2(0.0, 0.0);
+
+    for i in 1..n {
+	// Samples are taken at least once per frame, guard against identical time stamps
+	let dt = ((samples[i].time - samples[i - 1].time) as f32).max(1e-3);
+
+	let derivative = (samples[i].position - result[i - 1]) / dt;
+	let a_d = one_euro_alpha(dt, derivative_cutoff);
+	filtered_derivative = filtered_derivative + (derivative - filtered_derivative) * a_d;
+
+	let cutoff = min_cutoff + beta * length(filtered_derivative);
+	let a = one_euro_alpha(dt, cutoff);
+
+	result[i] = result[i - 1] + (samples[i].position - result[i - 1]) * a;
+    }
+
+    // The filter lags behind, so let the stroke still end where the user stopped
+    result[n - 1] = samples[n - 1].position;
+
+    result
+}
+
+// Turning angle per unit length at each point
+fn get_curvatures(points: &Vec<glm::Vec2>) -> Vec<f32> {
+    let n = points.len();
+    let mut curvatures = vec![0.0; n];
+
+    for i in 1..(n - 1) {
+	let e0 = points[i] - points[i - 1];
+	let e1 = points[i + 1] - points[i];
+
+	let l0 = length(e0);
+	let l1 = length(e1);
+
+	if l0 < 1e-6 || l1 < 1e-6 {
+	    continue;
+	}
+
+	let cosang = (dot(e0, e1) / (l0 * l1)).max(-1.0).min(1.0);
+	curvatures[i] = cosang.acos() / ((l0 + l1) / 2.0);
+    }
+
+    curvatures
+}
+
+// Resamples the polyline by arc length. The spacing is reduced where the
+// curvature is high, as controlled by curvature_adaptivity
+fn resample(points: &Vec<glm::Vec2>, spacing: f32, curvature_adaptivity: f32) -> Vec<glm::Vec2> {
+    let n = points.len();
+    if n < 2 || spacing <= 0.0 {
+	return points.clone();
+    }
+
+    let curvatures = get_curvatures(points);
+
+    let mut result : Vec<glm::Vec2> = vec![points[0]];
+    let mut remaining = spacing;
+
+    for i in 0..(n - 1) {
+	let kappa = curvatures[i].max(curvatures[i + 1]);
+	let target = (spacing / (1.0 + curvature_adaptivity * kappa * spacing)).max(spacing * 0.25);
+	remaining = remaining.min(target);
+
+	let segment = points[i + 1] - points[i];
+	let segment_length = length(segment);
+	let mut travelled = 0.0;
+
+	while segment_length - travelled >= remaining {
+	    travelled += remaining;
+	    result.push(points[i] + segment * (travelled / segment_length));
+	    remaining = target;
+	}
+
+	remaining -= segment_length - travelled;
+    }
+
+    // Always end in the last point. Merge it with the last sample if that one is too close
+    let last = points[n - 1];
+    if result.len() > 1 && length(last - result[result.len() - 1]) < spacing * 0.5 {
+	result.pop();
+    }
+    result.push(last);
+
+    result
+}
+
+fn distance_to_segment(p: glm::Vec2, a: glm::Vec2, b: glm::Vec2) -> f32 {
+    let ab = b - a;
+    let ll = dot(ab, ab);
+
+    if ll < 1e-12 {
+	return length(p - a);
+    }
+
+    let t = (dot(p - a, ab) / ll).max(0.0).min(1.0);
+    length(p - (a + ab * t))
+}
+
+fn rdp_recursive(points: &Vec<glm::Vec2>, first: usize, last: usize,
+		 tolerance: f32, keep: &mut Vec<bool>) {
+    let mut max_distance = 0.0;
+    let mut max_index = first;
+
+    for i in (first + 1)..last {
+	let d = distance_to_segment(points[i], points[first], points[last]);
+	if d > max_distance {
+	    max_distance = d;
+	    max_index = i;
+	}
+    }
+
+    if max_distance > tolerance {
+	keep[max_index] = true;
+	rdp_recursive(points, first, max_index, tolerance, keep);
+	rdp_recursive(points, max_index, last, tolerance, keep);
+    }
+}
+
+// Ramer-Douglas-Peucker simplification, keeping both end points
+pub fn simplify(points: &Vec<glm::Vec2>, tolerance: f32) -> Vec<glm::Vec2> {
+    let n = points.len();
+    if n < 3 || tolerance <= 0.0 {
+	return points.clone();
+    }
+
+    let mut keep = vec![false; n];
+    keep[0] = true;
+    keep[n - 1] = true;
+
+    rdp_recursive(points, 0, n - 1, tolerance, &mut keep);
+
+    (0..n).filter(|i| keep[*i]).map(|i| points[i]).collect()
+}
+
+pub fn smooth(samples: &Vec<StrokeSample>, settings: &StrokeSettings) -> Vec<glm::Vec2> {
+    match settings.filter {
+	StrokeFilter::Off => samples.iter().map(|s| s.position).collect(),
+	StrokeFilter::Gaussian => gaussian_filter(samples, settings.gaussian_sigma),
+	StrokeFilter::OneEuro => one_euro_filter(samples,
+						 settings.one_euro_min_cutoff,
+						 settings.one_euro_beta),
+    }
+}
+
+// Runs the whole pipeline: smoothing, resampling and simplification
+pub fn process_stroke(samples: &Vec<StrokeSample>,
+		      settings: &StrokeSettings) -> Vec<glm::Vec2> {
+    let samples = remove_duplicates(samples);
+    if samples.len() == 0 {
+	return Vec::new();
+    }
+
+    let smoothed = smooth(&samples, settings);
+    let mut points = resample(&smoothed, settings.spacing, settings.curvature_adaptivity);
+
+    // Respect the point budget by spreading the points evenly over the stroke
+    if points.len() > settings.max_points && settings.max_points >= 2 {
+	let mut total_length = 0.0;
+	for i in 1..points.len() {
+	    total_length += length(points[i] - points[i - 1]);
+	}
+
+	points = resample(&points, total_length / (settings.max_points - 1) as f32, 0.0);
+	while points.len() > settings.max_points {
+	    points.remove(points.len() - 2);
+	}
+    }
+
+    simplify(&points, settings.simplify_tolerance)
+}
@@ -0,0 +1,249 @@
+extern crate glm;
+
+use crate::settings;
+
+use glm::builtin::*;
+
+// Adaptive tessellation of generalized cylinders. Rings are placed densely where the spline
+// bends or the radius changes quickly and sparsely where it is straight, and the number of
+// vertices around the rings keeps the surface within an error target given in pixels.
+//
+// The number of vertices around the rings is chosen once per cylinder, for its widest ring,
+// rather than in proportion to the radius of each ring. That keeps the mesh a regular grid
+// of rings, which the texture coordinates, the annotations and the caps rely on, at the cost
+// of more vertices than needed where the cylinder is thin.
+
+// Pixel errors at the lowest (interactive) and the highest (export) quality
+static MAX_PIXEL_ERROR: f32 = 2.0;
+static MIN_PIXEL_ERROR: f32 = 0.05;
+
+// Most rings placed between two spline points
+static MAX_SUBDIVISIONS: usize = 16;
+
+// Limits for resolving surface detail such as noise displacement
+pub static MAX_DETAIL_CIRC_RESOLUTION: usize = 64;
+static MAX_DETAIL_RINGS: usize = 4096;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct TessellationSettings {
+    pub adaptive: bool,
+    pub quality: f32, // From 0 (interactive) to 1 (export)
+}
+
+impl TessellationSettings {
+    pub fn new() -> TessellationSettings {
+	TessellationSettings { adaptive: false,
+			       quality: 0.0 }
+    }
+
+    pub fn get_pixel_error(&self) -> f32 {
+	let quality = self.quality.max(0.0).min(1.0);
+	MAX_PIXEL_ERROR * (MIN_PIXEL_ERROR / MAX_PIXEL_ERROR).powf(quality)
+    }
+
+    // The error target in world units. The view maps [-1, 1] onto the window,
+    // so the shorter side of the window decides the size of a pixel
+    pub fn get_tolerance(&self) -> f32 {
+	let pixels = settings::WINDOW_WIDTH.min(settings::WINDOW_HEIGHT) as f32;
+	self.get_pixel_error() * 2.0 / pixels
+    }
+}
+
+// A ring of the sweep, with the scale of the base radius at its center
+#[derive(Copy, Clone, Debug)]
+pub struct Ring {
+    pub center: glm::Vec3,
+    pub scale: f32,
+}
+
+// Half the number of vertices around a ring of the given radius, so that the edges
+// between them stay within the tolerance of the true circle
+pub fn get_circ_resolution(radius: f32,
+			   tolerance: f32,
+			   min_resolution: usize,
+			   max_resolution: usize) -> usize {
+    let ratio = (tolerance / radius.max(1e-6)).min(1.0);
+    let num_vertices = std::f32::consts::PI / (1.0 - ratio).acos();
+
+    if !num_vertices.is_finite() {
+	return max_resolution;
+    }
+
+    ((num_vertices / 2.0).ceil() as usize).max(min_resolution).min(max_resolution)
+}
+
+// Uniform Catmull-Rom interpolation between b and c
+fn interpolate(a: Ring, b: Ring, c: Ring, d: Ring, t: f32) -> Ring {
+    let t2 = t * t;
+    let t3 = t2 * t;
+
+    let w0 = -0.5 * t3 + t2 - 0.5 * t;
+    let w1 = 1.5 * t3 - 2.5 * t2 + 1.0;
+    let w2 = -1.5 * t3 + 2.0 * t2 + 0.5 * t;
+    let w3 = 0.5 * t3 - 0.5 * t2;
+
+    Ring { center: a.center * w0 + b.center * w1 + c.center * w2 + d.center * w3,
+	   scale: (a.scale * w0 + b.scale * w1 + c.scale * w2 + d.scale * w3).max(0.0) }
+}
+
+fn angle_between(a: glm::Vec3, b: glm::Vec3) -> f32 {
+    let la = length(a);
+    let lb = length(b);
+    if la < 1e-9 || lb < 1e-9 {
+	return 0.0;
+    }
+
+    (dot(a, b) / (la * lb)).max(-1.0).min(1.0).acos()
+}
+
+fn distance_to_segment(p: glm::Vec3, a: glm::Vec3, b: glm::Vec3) -> f32 {
+    let ab = b - a;
+    let t = (dot(p - a, ab) / dot(ab, ab).max(1e-12)).max(0.0).min(1.0);
+    length(p - (a + ab * t))
+}
+
+// Inserts rings between the spline points where a straight, linearly scaled piece
+// of tube would leave the tolerance
+fn refine(samples: &Vec<Ring>, radius: f32, closed: bool, tolerance: f32) -> Vec<Ring> {
+    let n = samples.len() as i64;
+    let get = |i: i64| -> Ring {
+	if closed {
+	    samples[i.rem_euclid(n) as usize]
+	} else {
+	    samples[i.max(0).min(n - 1) as usize]
+	}
+    };
+
+    let num_segments = if closed { n } else { n - 1 };
+    let mut rings = Vec::with_capacity(samples.len());
+
+    for i in 0..num_segments {
+	let (a, b, c, d) = (get(i - 1), get(i), get(i + 1), get(i + 2));
+
+	// The outer side of a bend of angle theta and length l is an arc of radius
+	// l / theta + r, whose chord is off by about (l theta + r theta^2) / 8
+	let bend = angle_between(b.center - a.center, d.center - c.center);
+	let l = length(c.center - b.center);
+	let r = radius * b.scale.max(c.scale);
+	let bend_error = (l * bend + r * bend * bend) / 8.0;
+
+	// Kinks in the radius profile
+	let radius_error = radius * (a.scale - 2.0 * b.scale + c.scale).abs()
+	    .max((b.scale - 2.0 * c.scale + d.scale).abs()) / 8.0;
+
+	let error = bend_error.max(radius_error);
+	let k = ((error / tolerance).sqrt().ceil() as usize).max(1).min(MAX_SUBDIVISIONS);
+
+	for j in 0..k {
+	    rings.push(interpolate(a, b, c, d, j as f32 / k as f32));
+	}
+    }
+
+    if !closed {
+	rings.push(samples[samples.len() - 1]);
+    }
+
+    rings
+}
+
+// How far the surface between the rings first..=last moves if only the outer two are kept
+fn get_skip_error(rings: &Vec<Ring>, first: usize, last: usize, radius: f32) -> f32 {
+    let (a, b) = (rings[first], rings[last]);
+    let bend = angle_between(rings[first + 1].center - a.center,
+			     b.center - rings[last - 1].center);
+    let max_scale = rings[first..=last].iter().fold(0.0f32, |m, r| m.max(r.scale));
+
+    let mut error = radius * max_scale * (1.0 - (bend / 2.0).cos());
+
+    for m in (first + 1)..last {
+	let t = (m - first) as f32 / (last - first) as f32;
+	let scale = a.scale * (1.0 - t) + b.scale * t;
+
+	error = error.max(distance_to_segment(rings[m].center, a.center, b.center) +
+			  radius * (rings[m].scale - scale).abs());
+    }
+
+    error
+}
+
+// Greedily drops rings that are not needed to stay within the tolerance
+fn decimate(rings: &Vec<Ring>, radius: f32, closed: bool, tolerance: f32) -> Vec<Ring> {
+    // Closed splines are handled as open ones that end where they started
+    let mut rings = rings.clone();
+    if closed {
+	rings.push(rings[0]);
+    }
+
+    let mut kept = vec![rings[0]];
+    let mut first = 0;
+
+    for j in 2..rings.len() {
+	if get_skip_error(&rings, first, j, radius) > tolerance {
+	    first = j - 1;
+	    kept.push(rings[first]);
+	}
+    }
+
+    if !closed {
+	kept.push(rings[rings.len() - 1]);
+    }
+
+    kept
+}
+
+// Rings along the spline, given its points and their radius scales
+pub fn get_rings(points: &Vec<glm::Vec3>,
+		 scales: &Vec<f32>,
+		 radius: f32,
+		 closed: bool,
+		 tolerance: f32) -> Vec<Ring> {
+    let samples : Vec<Ring> = points.iter().zip(scales.iter())
+	.map(|(p, s)| Ring { center: *p, scale: *s })
+	.collect();
+
+    if samples.len() < 3 {
+	return samples;
+    }
+
+    let rings = decimate(&refine(&samples, radius, closed, tolerance), radius, closed, tolerance);
+
+    // The sweep needs two rings to find its direction, and closed loops three to wrap around
+    if rings.len() < (if closed { 3 } else { 2 }) {
+	samples
+    } else {
+	rings
+    }
+}
+
+// Splits the pieces between rings that are longer than the spacing, so that surface
+// detail of about twice that size can be shown
+pub fn subdivide_rings(rings: &Vec<Ring>, spacing: f32, closed: bool) -> Vec<Ring> {
+    let n = rings.len();
+    let num_segments = if closed { n } else { n - 1 };
+
+    let lengths : Vec<f32> = (0..num_segments)
+	.map(|i| length(rings[(i + 1) % n].center - rings[i].center))
+	.collect();
+
+    // Coarser than asked for when the ring count would grow too large
+    let total_length : f32 = lengths.iter().sum();
+    let spacing = spacing.max(total_length / MAX_DETAIL_RINGS as f32).max(1e-6);
+
+    let mut subdivided = Vec::with_capacity(n);
+    for i in 0..num_segments {
+	let (a, b) = (rings[i], rings[(i + 1) % n]);
+	let k = ((lengths[i] / spacing).ceil() as usize).max(1);
+
+	for j in 0..k {
+	    let t = j as f32 / k as f32;
+	    subdivided.push(Ring { center: a.center * (1.0 - t) + b.center * t,
+				   scale: a.scale * (1.0 - t) + b.scale * t });
+	}
+    }
+
+    if !closed {
+	subdivided.push(rings[n - 1]);
+    }
+
+    subdivided
+}
@@ -33,15 +33,42 @@ pub fn print_matrix<T: na::Dim,
 	
     }
 
+// Where the point ends up on screen, in normalized coordinates
+pub fn project_point(point: glm::Vec3,
+		     projection_matrix: &glm::Mat4) -> glm::Vec2 {
+    let l = *projection_matrix * glm::vec4(point.x, point.y, point.z, 1.0);
+    glm::vec2(l.x / l.w, l.y / l.w)
+}
+
 pub fn length_mouse_pos_to_point(mouse_pos: glm::Vec2,
 				 point: glm::Vec3,
 				 projection_matrix: &glm::Mat4) -> f32 {
-    let l = *projection_matrix * glm::vec4(point.x, point.y, point.z, 1.0);
-    let p = glm::vec2(l.x / l.w, l.y / l.w);
+    let p = project_point(point, projection_matrix);
     let nm = normalize_point(mouse_pos);
     glm::builtin::length(p - nm)
 }
 
+// Even-odd rule, so self-intersecting lassos select what they enclose an odd number of times
+pub fn point_in_polygon(p: glm::Vec2, polygon: &Vec<glm::Vec2>) -> bool {
+    let n = polygon.len();
+    if n < 3 {
+	return false;
+    }
+
+    let mut inside = false;
+    let mut j = n - 1;
+    for i in 0..n {
+	let (a, b) = (polygon[i], polygon[j]);
+	if (a.y > p.y) != (b.y > p.y) &&
+	    p.x < a.x + (p.y - a.y) / (b.y - a.y) * (b.x - a.x) {
+		inside = !inside;
+	    }
+	j = i;
+    }
+
+    inside
+}
+
 pub fn select_point(mouse_pos : glm::Vec2, points : &Vec<glm::Vec3>,
 		    projection_matrix: &glm::Mat4, sensitivity : f32) -> i32 {
 